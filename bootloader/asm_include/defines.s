@@ -2,6 +2,8 @@ FIRST_STACK equ 0x7bff
 VBR_BOOTLOADER equ 0x500            ; Position of the second bootloader in memory
 
 E820_MAP_BASE equ 0x7e00            ; Position of the e820 mapping for upper memory
+E820_MAX_ENTRIES equ 21             ; Entries that fit below the AP trampoline at 0x8000 (smp.rs)
 
 KERNEL_LOAD_BASE equ 0x100000       ; Base where the kernel is being loaded to
+KERNEL_VIRT_BASE equ 0xFFFFFFFF80000000 ; Virtual address of physical 0 for the kernel (linkscript.ld)
 SOURCE_PTR equ 0x70000              ; Source ptr where one head is read and moved from
\ No newline at end of file
@@ -1,35 +1,41 @@
 [bits 32]
 set_paging:
     ; ===================================================================================
-    ; Performs identity mapping on the first 6MiB of RAM ad enables paging
+    ; Maps the first 6MiB of RAM three times and enables paging:
+    ;   0x0000000000000000  identity, only used until the kernel runs in the higher half
+    ;   0xFFFF800000000000  start of the direct map of physical memory (PHYS_MAP_BASE)
+    ;   0xFFFFFFFF80000000  where the kernel is linked (KERNEL_VIRT_BASE)
+    ; All of them share the same page directory. The kernel replaces these tables with
+    ; its own in `memory::init`
     ; ===================================================================================
    
     mov     eax, cr4                 ; Enable PAE for 64bit tables
     or      eax, 1 << 5
     mov     cr4, eax   
 
+    mov edi, 0x70000                ; The tables lie where the kernel tracks were read to,
+    mov ecx, 0x1C00                 ; zero all 7 pages
+    xor eax, eax
+    rep stosd
+
                                     ; Page Map Table (1) (Total 6MiB)
     mov DWORD [0x70000], 0x71003    ; Set address to the Page table and set R/W+P flags
-    mov DWORD [0x70004], 0x0        ; Fill upper 32 bits with 0x0
+    mov DWORD [0x70800], 0x71003    ; Entry 256 (direct map), same PDPT
+    mov DWORD [0x70FF8], 0x76003    ; Entry 511 (kernel), own PDPT
 
 
-                                    ; Page Directory Pointer Table (1) 
+                                    ; Page Directory Pointer Table (2) 
     mov DWORD [0x71000], 0x72003    ; Set address to the Page table and set R/W+P flags
-    mov DWORD [0x71004], 0x0        ; Fill upper 32 bits with 0x0
+    mov DWORD [0x76FF0], 0x72003    ; Entry 510 of the kernel PDPT, same page directory
 
 
                                     ; Page Directory Table(s) (3) (2MiB each)
     mov DWORD [0x72000], 0x73003    ; Set address to the Page table and set R/W+P flags
-    mov DWORD [0x72004], 0x0        ; Fill upper 32 bits with 0x0
-
-    mov DWORD [0x72008], 0x74003    ; Set address to the Page table and set R/W+P flags
-    mov DWORD [0x7200C], 0x0        ; Fill upper 32 bits with 0x0
-
+    mov DWORD [0x72008], 0x74003
     mov DWORD [0x72010], 0x75003
-    mov DWORD [0x72004], 0x0
 
 
-    ; Perform identical mapping on the first 4MiB
+    ; Map the first 6MiB
     mov ecx, 0x0                    
     fill_page_tables:
         mov eax, ecx
@@ -44,7 +50,6 @@ set_paging:
         pop edx
 
         mov [0x73000+edx+0], eax    ; Identical mapping, flags same as above
-        mov DWORD [0x73000+edx+4], 0x00
 
         inc ecx
         cmp ecx, 0x0600             ; Until all 3 pages are filled
@@ -121,6 +121,8 @@ get_e820_memory_map:
     
         inc bp                      ; Increment the entry counter
         add di, 24                  ; Increment the entry destination offset
+        cmp bp, E820_MAX_ENTRIES    ; Drop the rest rather than run into 0x8000
+        jae end_e820
 
     entry_skip_e820:
         test ebx, ebx
@@ -27,13 +27,14 @@ main64:
     mov ax, '2@'
     mov [0xb800C], ax 
 
-    mov rsp, 0x400000                 ; Set stack at 0x9000  
+    mov rsp, KERNEL_VIRT_BASE+0x400000  ; Set stack at 0x400000 (through the kernel mapping)
     mov rbp, rsp
 
     call clear_screen
 
 
-    jmp KERNEL_LOAD_BASE+0x600 
+    mov rax, KERNEL_VIRT_BASE+KERNEL_LOAD_BASE+0x600
+    jmp rax                         ; Absolute jump into the higher half
 
 clear_screen:
     ; ===================================================================================
@@ -15,4 +15,7 @@ parted build/os.img -s mklabel msdos mkpart primary fat32 1s 100%
 mkfs.fat -b 0 -F 32 -M 0xf8 --mbr=n -R $((KERNEL_SECTORS+2)) --offset=1 build/os.img
 dd if=build/bootloader/mbr.bin of=build/os.img bs=440 count=1 conv=notrunc
 dd if=build/bootloader/vbr.bin of=build/os.img bs=1 count=420 conv=notrunc seek=602
-dd if=build/kernel.bin of=build/os.img bs=512 count=$KERNEL_SECTORS seek=3 conv=notrunc
\ No newline at end of file
+dd if=build/kernel.bin of=build/os.img bs=512 count=$KERNEL_SECTORS seek=3 conv=notrunc
+
+# The ELF test program `kernel_main` runs, into the partition that starts at sector 1
+MTOOLS_SKIP_CHECK=1 mcopy -o -i build/os.img@@512 src/test_files/elf/hello.elf ::HELLO.ELF
@@ -19,6 +19,11 @@ if ! command_exists mkfs.fat; then
     exit 1
 fi
 
+if ! command_exists mcopy; then
+    echo 'INSTALL MCOPY IN MTOOLS (MacOS)'
+    exit 1
+fi
+
 
 objcopy -I elf64-x86-64 -O binary --binary-architecture=i386:x86-64 $KERNEL_FILE_ELF $KERNEL_FILE_BIN
 
@@ -47,3 +52,6 @@ mkfs.fat -b 0 -h 1 -F 32 -M 0xf8 --mbr=n -R $((KERNEL_SECTORS+2)) --offset=1 bui
 dd if=build/bootloader/mbr.bin of=build/os.img bs=512 count=1 conv=notrunc
 dd if=build/bootloader/vbr.bin of=build/os.img bs=1 count=420 conv=notrunc seek=602
 dd if=build/kernel.bin of=build/os.img bs=512 count=$KERNEL_SECTORS seek=3 conv=notrunc
+
+# The ELF test program `kernel_main` runs, into the partition that starts at sector 1
+MTOOLS_SKIP_CHECK=1 mcopy -o -i build/os.img@@512 src/test_files/elf/hello.elf ::HELLO.ELF
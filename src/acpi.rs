@@ -1,5 +1,6 @@
 use core::{arch::asm, mem::size_of};
 
+use crate::mem::memory::phys_to_virt;
 use crate::tooling::qemu_io::{qemu_print_hex, qemu_println, SerialWriter};
 use core::fmt::Write;
 
@@ -69,14 +70,22 @@ pub trait Signature {
     fn get_signature() -> [u8; 4];
 }
 
+impl SDTHeader {
+    // the checksum covers the whole table, not just the header
+    pub fn is_valid(&self) -> bool {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) };
+        bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+    }
+}
+
 impl RSDT {
-    fn from_rsdp(rsdp: &RSDP) -> Option<&RSDT> {
+    fn from_rsdp(rsdp: &RSDP) -> Option<&'static RSDT> {
         qemu_print_hex(rsdp.rsdt_address);
-        let res = rsdp.rsdt_address as *const RSDT;
+        // it is a physical addr
+        let res = phys_to_virt(rsdp.rsdt_address as u64) as *const RSDT;
         let res = unsafe { &*res };
 
-        // panic since it is a physical addr
-
         if !res.is_valid() {
             return None;
         }
@@ -84,27 +93,42 @@ impl RSDT {
         Some(res)
     }
 
-    pub fn find_table<A>(&self) -> Option<A>
+    // the header is followed by the physical addresses of the other tables
+    fn entries(&self) -> impl Iterator<Item = u64> + '_ {
+        let count = (self.h.length as usize - size_of::<RSDT>()) / 4;
+        let first = unsafe { (self as *const RSDT).add(1) as *const u32 };
+        (0..count).map(move |i| unsafe { first.add(i).read_unaligned() as u64 })
+    }
+
+    pub fn find_table<A>(&self) -> Option<&'static A>
     where
         A: Signature,
     {
-        let entries = (RSDTX.h.length - size_of::<RSDT>() as u32) / 4;
-
-        for x in 0..entries {}
+        for addr in self.entries() {
+            let header = unsafe { &*(phys_to_virt(addr) as *const SDTHeader) };
+            if header.signature == A::get_signature() && header.is_valid() {
+                return Some(unsafe { &*(header as *const SDTHeader as *const A) });
+            }
+        }
         None
     }
 
     pub fn is_valid(&self) -> bool {
-        self.h.signature.eq(b"RSDT") && sum_struct!(self) == 0
+        self.h.signature.eq(b"RSDT") && self.h.is_valid()
     }
 }
 
+/// The RSDT, if the firmware has ACPI tables. Unlike RSDTX this doesn't panic without them
+pub fn rsdt() -> Option<&'static RSDT> {
+    RSDT::from_rsdp(find_rsdp().ok()?)
+}
+
 fn find_rsdp() -> Result<&'static RSDP, &'static str> {
-    let bios_start = 0x000E_0000;
-    let bios_end = 0x0010_0000;
+    let bios_start: u64 = 0x000E_0000;
+    let bios_end: u64 = 0x0010_0000;
 
     for addr in (bios_start..bios_end).step_by(16) {
-        let ptr = addr as *const RSDP;
+        let ptr = phys_to_virt(addr) as *const RSDP;
         let rsdp = unsafe { &*ptr };
 
         if !rsdp.validate() {
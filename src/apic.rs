@@ -1,10 +1,28 @@
 use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::null_mut;
 
-use crate::acpi::*;
+use crate::acpi::{self, SDTHeader, Signature};
+use crate::handlers::InterruptStackFrame;
+use crate::irq::{self, Controller, IRQ_BASE, IRQ_LINES};
+use crate::mem::memory::{map_mmio, rdmsr, wrmsr};
+use crate::pic;
+use crate::qemu_println;
+use crate::sync::{IrqSpinlock, Once};
 
-lazy_static! {
-    pub static ref MADTX: MADT = RSDTX.find_table::<MADT>().unwrap();
-}
+/* Local APIC and IO APIC. The MADT lists the local APIC of every CPU, the IO APICs, how
+ * the ISA IRQs are wired to the IO APIC inputs (interrupt source overrides) and which
+ * pins carry NMIs. `init` enables the local APIC of the boot CPU, routes the 16 ISA IRQs
+ * through the IO APIC to the same vectors the PIC used and disables the PIC. Without a
+ * MADT or IO APIC the kernel keeps using the PIC (see pic.rs and irq.rs). */
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+pub const ERROR_VECTOR: u8 = 0xFE;
+pub const TIMER_VECTOR: u8 = 0xEF;
+
+pub const MAX_CPUS: usize = 64;
+const MAX_IO_APICS: usize = 8;
+const MAX_NMIS: usize = 8;
 
 impl Signature for MADT {
     fn get_signature() -> [u8; 4] {
@@ -13,49 +31,510 @@ impl Signature for MADT {
 }
 
 // https://wiki.osdev.org/MADT
-#[repr(C)]
+#[repr(C, packed)]
 pub struct MADT {
-    pub signature: u32,
-    pub length: u32,
-    pub revision: u8,
-    pub checksum: u8,
-    pub oemid: [u8; 6],
-    pub oem_table_id: u64,
-    pub oem_revision: u32,
-    pub creator_id: u32,
-    pub creator_revision: u32,
-}
-
-pub fn init() {
-    disable_pic();
-}
-// https://wiki.osdev.org/PIC#Disabling
-fn disable_pic() {
+    pub h: SDTHeader,
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+impl MADT {
+    /// The machine also has the two legacy PICs
+    const PCAT_COMPAT: u32 = 1 << 0;
+
+    pub fn entries(&self) -> MadtEntries {
+        let start = self as *const MADT as *const u8;
+        unsafe {
+            MadtEntries {
+                ptr: start.add(size_of::<MADT>()),
+                end: start.add(self.h.length as usize),
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    SourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    NmiSource {
+        flags: u16,
+        gsi: u32,
+    },
+    LocalApicNmi {
+        /// 0xFF for all processors
+        processor_id: u8,
+        flags: u16,
+        lint: u8,
+    },
+    LocalApicOverride {
+        address: u64,
+    },
+    Unknown(u8),
+}
+
+pub struct MadtEntries {
+    ptr: *const u8,
+    end: *const u8,
+}
+
+unsafe fn read<T>(ptr: *const u8, offset: usize) -> T {
+    (ptr.add(offset) as *const T).read_unaligned()
+}
+
+// every entry starts with its type and length
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        unsafe {
+            if self.ptr.add(2) > self.end {
+                return None;
+            }
+            let (entry_type, length) = (*self.ptr, *self.ptr.add(1) as usize);
+            if length < 2 || self.ptr.add(length) > self.end {
+                return None;
+            }
+
+            let p = self.ptr;
+            self.ptr = self.ptr.add(length);
+            Some(match entry_type {
+                0 => MadtEntry::LocalApic {
+                    processor_id: read(p, 2),
+                    apic_id: read(p, 3),
+                    flags: read(p, 4),
+                },
+                1 => MadtEntry::IoApic {
+                    id: read(p, 2),
+                    address: read(p, 4),
+                    gsi_base: read(p, 8),
+                },
+                2 => MadtEntry::SourceOverride {
+                    bus: read(p, 2),
+                    source: read(p, 3),
+                    gsi: read(p, 4),
+                    flags: read(p, 8),
+                },
+                3 => MadtEntry::NmiSource {
+                    flags: read(p, 2),
+                    gsi: read(p, 4),
+                },
+                4 => MadtEntry::LocalApicNmi {
+                    processor_id: read(p, 2),
+                    flags: read(p, 3),
+                    lint: read(p, 5),
+                },
+                5 => MadtEntry::LocalApicOverride {
+                    address: read(p, 4),
+                },
+                other => MadtEntry::Unknown(other),
+            })
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Cpu {
+    pub processor_id: u8,
+    pub apic_id: u8,
+}
+
+#[derive(Copy, Clone)]
+struct IoApic {
+    id: u8,
+    regs: *mut u32,
+    gsi_base: u32,
+    pins: u32,
+}
+
+#[derive(Copy, Clone)]
+struct LocalNmi {
+    processor_id: u8,
+    flags: u16,
+    lint: u8,
+}
+
+/* Polarity and trigger mode flags of overrides and NMI entries, 0 is "bus default" */
+const MPS_ACTIVE_LOW: u16 = 0b11;
+const MPS_LEVEL: u16 = 0b11 << 2;
+
+struct Apic {
+    lapic: *mut u32,
+    cpus: [Option<Cpu>; MAX_CPUS],
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    /* GSI and flags of every ISA IRQ, identity mapped unless overridden */
+    isa_irqs: [(u32, u16); IRQ_LINES],
+    nmi_sources: [Option<(u32, u16)>; MAX_NMIS],
+    local_nmis: [Option<LocalNmi>; MAX_NMIS],
+}
+
+/* The registers are MMIO mapped for every CPU */
+unsafe impl Send for Apic {}
+unsafe impl Sync for Apic {}
+
+/* Set by `init` and only read afterwards */
+static APIC: Once<Apic> = Once::new();
+
+/* IO APIC registers are accessed through a select and a window register */
+static IO_APIC_REGS: IrqSpinlock<()> = IrqSpinlock::new(());
+
+fn apic() -> &'static Apic {
+    APIC.get().expect("the APICs aren't initialized")
+}
+
+// local APIC registers, https://wiki.osdev.org/APIC#Local_APIC_registers
+pub const LAPIC_ID: usize = 0x20;
+pub const LAPIC_VERSION: usize = 0x30;
+pub const LAPIC_TPR: usize = 0x80;
+pub const LAPIC_EOI: usize = 0xB0;
+pub const LAPIC_SVR: usize = 0xF0;
+pub const LAPIC_ESR: usize = 0x280;
+pub const LAPIC_ICR_LOW: usize = 0x300;
+pub const LAPIC_ICR_HIGH: usize = 0x310;
+pub const LAPIC_LVT_TIMER: usize = 0x320;
+pub const LAPIC_LVT_LINT0: usize = 0x350;
+pub const LAPIC_LVT_LINT1: usize = 0x360;
+pub const LAPIC_LVT_ERROR: usize = 0x370;
+pub const LAPIC_TIMER_INITIAL: usize = 0x380;
+pub const LAPIC_TIMER_CURRENT: usize = 0x390;
+pub const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const SVR_ENABLE: u32 = 1 << 8;
+
+// bits shared by the LVT and the IO APIC redirection entries
+pub const LVT_MASKED: u32 = 1 << 16;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const ACTIVE_LOW: u32 = 1 << 13;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+
+/* Timer input is the bus clock divided by 16 */
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10 / 4;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+pub fn lapic_read(reg: usize) -> u32 {
+    unsafe { apic().lapic.add(reg / 4).read_volatile() }
+}
+
+pub fn lapic_write(reg: usize, value: u32) {
+    unsafe { apic().lapic.add(reg / 4).write_volatile(value) }
+}
+
+/// APIC id of the CPU this runs on
+pub fn lapic_id() -> u8 {
+    (lapic_read(LAPIC_ID) >> 24) as u8
+}
+
+pub fn send_eoi() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+/// Whether `init` found and enabled the APICs
+pub fn enabled() -> bool {
+    APIC.is_completed() && irq::controller() == Controller::IoApic
+}
+
+/// Raises TIMER_VECTOR once after `count` timer ticks, replacing a pending one
+pub fn timer_oneshot(count: u32) {
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, TIMER_VECTOR as u32);
+    lapic_write(LAPIC_TIMER_INITIAL, count);
+}
+
+/// Starts the timer counting down from `u32::MAX` with its interrupt masked, for
+/// measuring its frequency
+pub fn timer_free_run() {
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+}
+
+pub fn timer_current() -> u32 {
+    lapic_read(LAPIC_TIMER_CURRENT)
+}
+
+pub fn timer_stop() {
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+}
+
+/// The CPUs the firmware reported as usable
+pub fn cpus() -> impl Iterator<Item = Cpu> {
+    APIC.get().into_iter().flat_map(|apic| apic.cpus.into_iter().flatten())
+}
+
+pub fn cpu_count() -> usize {
+    cpus().count().max(1)
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        let _regs = IO_APIC_REGS.lock();
+        unsafe {
+            self.regs.add(IOAPIC_REGSEL).write_volatile(reg);
+            self.regs.add(IOAPIC_WINDOW).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        let _regs = IO_APIC_REGS.lock();
+        unsafe {
+            self.regs.add(IOAPIC_REGSEL).write_volatile(reg);
+            self.regs.add(IOAPIC_WINDOW).write_volatile(value);
+        }
+    }
+
+    fn set_redirection(&self, pin: u32, low: u32, dest_apic_id: u8) {
+        /* Masked while the entry is inconsistent */
+        self.write(IOAPIC_REDIRECTION + pin * 2, LVT_MASKED);
+        self.write(IOAPIC_REDIRECTION + pin * 2 + 1, (dest_apic_id as u32) << 24);
+        self.write(IOAPIC_REDIRECTION + pin * 2, low);
+    }
+}
+
+// IO APIC and pin that receive global system interrupt `gsi`
+fn io_apic_pin(gsi: u32) -> Option<(IoApic, u32)> {
+    apic()
+        .io_apics
+        .iter()
+        .flatten()
+        .find(|io| io.gsi_base <= gsi && gsi < io.gsi_base + io.pins)
+        .map(|io| (*io, gsi - io.gsi_base))
+}
+
+fn mps_flags(flags: u16) -> u32 {
+    let mut bits = 0;
+    if flags & MPS_ACTIVE_LOW == MPS_ACTIVE_LOW {
+        bits |= ACTIVE_LOW;
+    }
+    if flags & MPS_LEVEL == MPS_LEVEL {
+        bits |= LEVEL_TRIGGERED;
+    }
+    bits
+}
+
+fn set_irq_masked(line: u8, masked: bool) {
+    let (gsi, _) = apic().isa_irqs[line as usize];
+    if let Some((io, pin)) = io_apic_pin(gsi) {
+        let reg = IOAPIC_REDIRECTION + pin * 2;
+        let low = io.read(reg);
+        io.write(reg, if masked { low | LVT_MASKED } else { low & !LVT_MASKED });
+    }
+}
+
+pub fn mask_irq(line: u8) {
+    set_irq_masked(line, true);
+}
+
+pub fn unmask_irq(line: u8) {
+    set_irq_masked(line, false);
+}
+
+fn parse(madt: &MADT) -> Result<Apic, &'static str> {
+    let mut lapic_address = madt.local_apic_address as u64;
+    let mut apic = Apic {
+        lapic: null_mut(),
+        cpus: [None; MAX_CPUS],
+        io_apics: [None; MAX_IO_APICS],
+        isa_irqs: [(0, 0); IRQ_LINES],
+        nmi_sources: [None; MAX_NMIS],
+        local_nmis: [None; MAX_NMIS],
+    };
+    for line in 0..IRQ_LINES {
+        apic.isa_irqs[line] = (line as u32, 0);
+    }
+
+    let (mut cpus, mut io_apics, mut nmi_sources, mut local_nmis) = (0, 0, 0, 0);
+    for entry in madt.entries() {
+        match entry {
+            /* Bit 0: enabled, bit 1: can be enabled */
+            MadtEntry::LocalApic {
+                processor_id,
+                apic_id,
+                flags,
+            } if flags & 0b11 != 0 && cpus < MAX_CPUS => {
+                apic.cpus[cpus] = Some(Cpu {
+                    processor_id,
+                    apic_id,
+                });
+                cpus += 1;
+            }
+            MadtEntry::IoApic {
+                id,
+                address,
+                gsi_base,
+            } if io_apics < MAX_IO_APICS => {
+                let regs = map_mmio(address as u64, 0x20)? as *mut u32;
+                let mut io = IoApic {
+                    id,
+                    regs,
+                    gsi_base,
+                    pins: 0,
+                };
+                io.pins = ((io.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+                apic.io_apics[io_apics] = Some(io);
+                io_apics += 1;
+            }
+            /* Only ISA overrides exist in practice */
+            MadtEntry::SourceOverride {
+                bus: 0,
+                source,
+                gsi,
+                flags,
+            } if (source as usize) < IRQ_LINES => {
+                apic.isa_irqs[source as usize] = (gsi, flags);
+            }
+            MadtEntry::NmiSource { flags, gsi } if nmi_sources < MAX_NMIS => {
+                apic.nmi_sources[nmi_sources] = Some((gsi, flags));
+                nmi_sources += 1;
+            }
+            MadtEntry::LocalApicNmi {
+                processor_id,
+                flags,
+                lint,
+            } if local_nmis < MAX_NMIS => {
+                apic.local_nmis[local_nmis] = Some(LocalNmi {
+                    processor_id,
+                    flags,
+                    lint,
+                });
+                local_nmis += 1;
+            }
+            MadtEntry::LocalApicOverride { address } => lapic_address = address,
+            _ => {}
+        }
+    }
+
+    if io_apics == 0 {
+        return Err("no IO APIC in the MADT");
+    }
+    apic.lapic = map_mmio(lapic_address, 0x400)? as *mut u32;
+    Ok(apic)
+}
+
+/// Enables the local APIC of the CPU this runs on. The boot CPU does it in `init`, the
+/// others when they come up
+pub fn init_local() {
+    unsafe {
+        wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE);
+    }
+
+    lapic_write(LAPIC_TPR, 0);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    lapic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+    lapic_write(LAPIC_LVT_LINT1, LVT_MASKED);
+    lapic_write(LAPIC_LVT_ERROR, ERROR_VECTOR as u32);
+
+    /* LINT pins the firmware wired to NMI */
+    let id = lapic_id();
+    let processor_id = cpus().find(|cpu| cpu.apic_id == id).map(|cpu| cpu.processor_id);
+    for nmi in apic().local_nmis.iter().flatten() {
+        if nmi.processor_id != 0xFF && Some(nmi.processor_id) != processor_id {
+            continue;
+        }
+        let reg = if nmi.lint == 0 {
+            LAPIC_LVT_LINT0
+        } else {
+            LAPIC_LVT_LINT1
+        };
+        lapic_write(reg, DELIVERY_NMI | mps_flags(nmi.flags));
+    }
+
+    /* The error status register is cleared by writing it */
+    lapic_write(LAPIC_ESR, 0);
+    lapic_write(LAPIC_ESR, 0);
+    send_eoi();
+    lapic_write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+fn init_io_apics() {
+    let bsp = lapic_id();
+    for io in apic().io_apics.iter().flatten() {
+        for pin in 0..io.pins {
+            io.set_redirection(pin, LVT_MASKED, bsp);
+        }
+    }
+
+    /* ISA IRQs are edge triggered and active high unless overridden. They stay
+     * masked until irq.rs unmasks the ones that have handlers */
+    for line in 0..IRQ_LINES {
+        let (gsi, flags) = apic().isa_irqs[line];
+        if let Some((io, pin)) = io_apic_pin(gsi) {
+            let low = (IRQ_BASE as u32 + line as u32) | mps_flags(flags) | LVT_MASKED;
+            io.set_redirection(pin, low, bsp);
+        }
+    }
+
+    for (gsi, flags) in apic().nmi_sources.iter().flatten() {
+        if let Some((io, pin)) = io_apic_pin(*gsi) {
+            io.set_redirection(pin, DELIVERY_NMI | mps_flags(*flags), bsp);
+        }
+    }
+}
+
+/// Switches interrupt handling from the PIC to the APICs. Fails without changing
+/// anything if there is no MADT or it doesn't list an IO APIC
+pub fn init() -> Result<(), &'static str> {
+    let madt = acpi::rsdt()
+        .ok_or("no ACPI tables")?
+        .find_table::<MADT>()
+        .ok_or("no MADT")?;
+    let parsed = parse(madt)?;
+    APIC.call_once(|| parsed);
+
+    unsafe {
+        asm!("cli");
+    }
+    if madt.flags & MADT::PCAT_COMPAT != 0 {
+        pic::disable();
+    }
+    init_local();
+    init_io_apics();
+    irq::set_controller(Controller::IoApic);
     unsafe {
-        asm!(
-            // // init the pics
-            "mov al, 0x11",
-            "out 0x20, al",
-            "out 0xa0, al",
-            // move the offsets to 32 and 40
-            "mov al, 0x20",
-            "out 0x21, al",
-            "mov al, 0x28",
-            "out 0xa1, al",
-            // chain stuff
-            "mov al, 0x04",
-            "out 0x21, al",
-            "mov al, 0x02",
-            "out 0xa1, al",
-            // mode x801239384
-            "mov al, 0x01",
-            "out 0x21, al",
-            "out 0xa1, al",
-            // mask
-            "mov al, 0xff",
-            "out 0xa1, al",
-            "out 0x21, al",
-            options(nostack, preserves_flags, nomem)
+        asm!("sti");
+    }
+
+    for io in apic().io_apics.iter().flatten() {
+        qemu_println!(
+            "apic: IO APIC {} with {} pins from GSI {}",
+            io.id,
+            io.pins,
+            io.gsi_base
         );
     }
+    qemu_println!(
+        "apic: local APIC {} (version {:#x}), {} CPUs",
+        lapic_id(),
+        lapic_read(LAPIC_VERSION) & 0xFF,
+        cpu_count()
+    );
+    Ok(())
+}
+
+// no EOI for spurious interrupts
+pub extern "x86-interrupt" fn spurious_handler(_isf: InterruptStackFrame) {}
+
+pub extern "x86-interrupt" fn error_handler(_isf: InterruptStackFrame) {
+    lapic_write(LAPIC_ESR, 0);
+    qemu_println!("apic: error, status {:#x}", lapic_read(LAPIC_ESR));
+    send_eoi();
 }
@@ -1,3 +1,4 @@
+use crate::mem::stats::{self, Subsystem};
 use crate::qemu_println;
 use crate::time;
 use crate::tooling::serial::*;
@@ -92,7 +93,8 @@ pub fn stop() {
 // beep() and sweep() should not be used since they just waste cpu time
 pub fn beep(freq: u32, duration: u64) {
     play(freq);
-    let timer = crate::time::Timer::new(duration, &stop);
+    let _mem = stats::scope(Subsystem::Drivers);
+    time::after(duration, stop);
 }
 
 pub fn sweep(start: i32, end: i32, delay: u64) {
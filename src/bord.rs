@@ -23,12 +23,58 @@ pub struct SegmentDescriptor {
     pub base2: u8,
 }
 
+// https://wiki.osdev.org/Global_Descriptor_Table#Segment_Descriptor
 impl SegmentDescriptor {
-    fn new(func: &dyn FnMut() -> ()) -> Self {
-        let mut x = Self::default();
+    // access byte
+    pub const PRESENT: u8 = 1 << 7;
+    pub const CODE_DATA: u8 = 1 << 4; // S flag, clear for system segments (TSS)
+    pub const EXECUTABLE: u8 = 1 << 3;
+    pub const READ_WRITE: u8 = 1 << 1; // readable for code, writable for data
+    pub const TSS_AVAILABLE: u8 = 0x9; // 64-bit TSS, system segment type
 
-        x.misc |= 1 << 7; // P flag
-        x
+    // flags (upper nibble of misc)
+    pub const GRANULARITY: u8 = 1 << 3; // limit is in 4 KiB units
+    pub const SIZE_32: u8 = 1 << 2;
+    pub const LONG_MODE: u8 = 1 << 1;
+
+    pub const fn new(base: u32, limit: u32, access: u8, flags: u8, dpl: Ring) -> Self {
+        let access = access | Self::PRESENT | ((dpl as u8) << 5);
+        Self {
+            segment_limit: limit as u16,
+            base_addr: base as u16,
+            base: (base >> 16) as u8,
+            misc: access as u16 | (((limit >> 16) & 0xF) as u16) << 8 | ((flags & 0xF) as u16) << 12,
+            base2: (base >> 24) as u8,
+        }
+    }
+
+    // 64-bit code segment, base and limit are ignored in long mode
+    pub const fn code(dpl: Ring) -> Self {
+        Self::new(
+            0,
+            0xFFFFF,
+            Self::CODE_DATA | Self::EXECUTABLE | Self::READ_WRITE,
+            Self::GRANULARITY | Self::LONG_MODE,
+            dpl,
+        )
+    }
+
+    pub const fn data(dpl: Ring) -> Self {
+        Self::new(
+            0,
+            0xFFFFF,
+            Self::CODE_DATA | Self::READ_WRITE,
+            Self::GRANULARITY | Self::SIZE_32,
+            dpl,
+        )
+    }
+
+    pub const fn as_u64(&self) -> u64 {
+        self.segment_limit as u64
+            | (self.base_addr as u64) << 16
+            | (self.base as u64) << 32
+            | (self.misc as u64) << 40
+            | (self.base2 as u64) << 56
     }
 }
 
@@ -55,7 +101,6 @@ struct IDTDescriptor<'a> {
 }
 
 #[repr(C)]
-#[derive(Default)]
 pub struct IDT {
     pub divide_error: IDTEntry,
     pub debug: IDTEntry,
@@ -78,8 +123,12 @@ pub struct IDT {
     pub machine_check: IDTEntry,
     pub simd_floating_point: IDTEntry,
     pub virtualization: IDTEntry,
+    pub control_protection: IDTEntry,
+    pub more_reserved: [IDTEntry; 6],
+    pub hypervisor_injection: IDTEntry,
+    pub vmm_communication: IDTEntry,
     pub security_exception: IDTEntry,
-    pub more_reserved: [IDTEntry; 10],
+    pub reserved_31: IDTEntry,
     pub interrupt1: IDTEntry,
     pub interrupt2: IDTEntry,
     pub interrupt3: IDTEntry,
@@ -96,9 +145,27 @@ pub struct IDT {
     pub interrupt14: IDTEntry,
     pub interrupt15: IDTEntry,
     pub interrupt16: IDTEntry,
+    // vectors 48-255, see `IDT::set`
+    pub vectors: [IDTEntry; 208],
+}
+
+impl IDT {
+    pub fn set(&mut self, vector: u8, entry: IDTEntry) {
+        let entries = self as *mut IDT as *mut IDTEntry;
+        unsafe {
+            *entries.add(vector as usize) = entry;
+        }
+    }
 }
 
-type HandlerFunc = extern "x86-interrupt" fn(isf: InterruptStackFrame);
+impl Default for IDT {
+    fn default() -> Self {
+        let entries = [IDTEntry::default(); 256];
+        unsafe { core::mem::transmute(entries) }
+    }
+}
+
+pub type HandlerFunc = extern "x86-interrupt" fn(isf: InterruptStackFrame);
 
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
@@ -114,7 +181,17 @@ pub struct IDTEntry {
 
 impl IDTEntry {
     pub fn new(f: HandlerFunc, dpl: Ring) -> Self {
-        let handler_addr: u64 = f as u64;
+        Self::from_addr(f as u64, dpl)
+    }
+
+    // run the handler on the given Interrupt Stack Table stack (1-7, see gdt.rs)
+    pub fn with_ist(mut self, ist: u8) -> Self {
+        self.ist = ist & 0b111;
+        self
+    }
+
+    // for handlers written in assembly (see exceptions.rs)
+    pub fn from_addr(handler_addr: u64, dpl: Ring) -> Self {
         let mut attribs = 1 << 7; // P flag
         attribs |= (dpl as u8) << 5;
         attribs |= 0b1110; // type = interrupt gate
@@ -191,6 +268,16 @@ pub fn store_gdt() -> GDTR {
     p
 }
 
+pub fn load_gdt(gdtr: &GDTR) {
+    unsafe {
+        asm!(
+            "lgdt [{x}]",
+            x = in(reg) gdtr,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
 fn get_cs() -> SegmentSelector {
     let mut x: u16;
     unsafe {
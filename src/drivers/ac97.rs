@@ -5,6 +5,7 @@ use super::pci::{pci_device_search_by_class_subclass, pci_get_header_type, pci_g
 use crate::misc::rand;
 use crate::format;
 use crate::time;
+use crate::mem::memory::virt_to_phys;
 /*  
 * Shameless theft from https://wiki.osdev.org/AC97
 */
@@ -127,7 +128,7 @@ impl AC97 {
         /* This code doesn't belong in an init() function but for testing purposes it will stay */
         let buf_desc: BufferDescriptor = 
             BufferDescriptor { 
-                ptr: &noises as *const [u16; 1024] as u32, 
+                ptr: virt_to_phys(&noises as *const [u16; 1024] as u64).unwrap() as u32, 
                 size: noise.len() as u16 / 2, // 16 bit audio = 2 bytes
                 flags: 0b00 << 14 // don't do this -> // generate interrupt after every sample; stop playing sound after this buffer is done
             };
@@ -201,7 +202,7 @@ impl BufferDescriptor {
         poll_ctr = 0;
 
         /* Write physical position of buffer descriptor list to bar1 + 0x10 */
-        let addr = as_physical_address(self as *const Self) as u32;
+        let addr = virt_to_phys(self as *const Self as u64).ok_or("Buffer descriptor is not mapped")? as u32;
         if addr > 0x40000000 { // maximum u30 value
             return Err("Address too high (> 0x40000000)")
         }
@@ -243,12 +244,6 @@ fn generate_noise(rng: &mut rand::Rng) -> [u16; 1024] {
     arr
 }
 
-// wtb implementation
-#[inline]
-fn as_physical_address<T>(ptr: *const T) -> *const T {
-    ptr
-}
-
 // test func
 #[inline]
 fn waste_time(t: u64) {
@@ -7,8 +7,12 @@ use super::pci::{
     pci_device_search_by_class_subclass, pci_get_bar_address, pci_get_header_0x00,
     pci_get_header_type, pci_get_progif, PCIDeviceHeader0x00,
 };
+use crate::irq;
+use crate::time;
 use crate::tooling::qemu_io::{qemu_fmt_println, qemu_print, qemu_print_hex, qemu_println};
 use crate::tooling::serial::{inb, ind, outb};
+use crate::wait::WaitQueue;
+use core::sync::atomic::{AtomicU16, Ordering};
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug)]
@@ -235,14 +239,26 @@ pub struct IDE {
     ide_irq_invoked: u8,
 }
 
-impl IDE {
-    /* Should be replaced later */
-    fn tmp_sleep(&self, channel: ATAChannel, ms: usize) {
-        for i in 0..ms * 10 {
-            IDE::read_chreg(self, channel, ATARegister::ControlORAltStatus);
-        }
+/* Woken by the IRQs of both channels, waiters check the status of their drive */
+static DISK_IRQ: WaitQueue = WaitQueue::new();
+/* Status port of each channel, 0 if the channel has no legacy IRQ */
+static STATUS_PORTS: [AtomicU16; 2] = [AtomicU16::new(0), AtomicU16::new(0)];
+
+const PRIMARY_IRQ: u8 = 14;
+const SECONDARY_IRQ: u8 = 15;
+
+fn ide_irq(line: u8) -> bool {
+    let port = STATUS_PORTS[(line == SECONDARY_IRQ) as usize].load(Ordering::Relaxed);
+    if port == 0 {
+        return false;
     }
+    /* Reading the status register acknowledges the interrupt */
+    inb(port);
+    DISK_IRQ.wake_all();
+    true
+}
 
+impl IDE {
     pub fn new() -> Self {
         Self {
             channels: unsafe { core::mem::zeroed() },
@@ -301,6 +317,16 @@ impl IDE {
             self.channels[ATAChannel::Secondary].control_base = 0x376;
             self.channels[ATAChannel::Secondary].base_io = 0x170;
             self.channels[ATAChannel::Secondary].bus_master_ide = 0x08;
+
+            /* Native mode would need the PCI interrupt line instead, it keeps polling */
+            for (channel, line) in [(0, PRIMARY_IRQ), (1, SECONDARY_IRQ)] {
+                STATUS_PORTS[channel].store(
+                    self.channels[channel].base_io + ATARegister::CommandORStatus as u16,
+                    Ordering::Relaxed,
+                );
+                /* Fails if an earlier `init` registered it already */
+                let _ = irq::register(line, "ide", ide_irq);
+            }
         }
 
         /* Disable IRQ */
@@ -330,8 +356,8 @@ impl IDE {
                 /* Select drive */
                 IDE::write_chreg(&self, channel, ATARegister::HDDEvsel, 0x0A | (j << 4));
 
-                /* Sleep 1ms by reading alt status port 10 times */
-                IDE::tmp_sleep(&self, channel, 1);
+                /* Sleep 1ms */
+                time::sleep(1);
 
                 /* Send ATA identify command */
                 IDE::write_chreg(
@@ -342,7 +368,7 @@ impl IDE {
                 );
 
                 /* Sleep for 1ms */
-                IDE::tmp_sleep(&self, channel, 1);
+                time::sleep(1);
 
                 /* If status == 0: no device */
                 if IDE::read_chreg(&self, channel, ATARegister::CommandORStatus) == 0x00 {
@@ -629,18 +655,32 @@ impl IDE {
         }
     }
 
+    /// Waits until the drive on `channel` isn't busy. Blocks until the drive raises its
+    /// IRQ if the channel has one, spins otherwise
+    fn wait_not_busy(&self, channel: ATAChannel) {
+        let not_busy = || {
+            !ATAStatus::Busy.presence(IDE::read_chreg(
+                self,
+                channel,
+                ATARegister::CommandORStatus,
+            ))
+        };
+        if STATUS_PORTS[channel as usize].load(Ordering::Relaxed) != 0 {
+            DISK_IRQ.wait_until(not_busy);
+        } else {
+            while !not_busy() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
     pub fn polling(&self, channel: ATAChannel) -> Result<u8, &'static str> {
         /* Delay 400ns by reading alt status port 4 times, which takes in total 400ns */
         for i in 0..4 {
             IDE::read_chreg(self, channel, ATARegister::ControlORAltStatus);
         }
 
-        /* Wait until not busy */
-        while ATAStatus::Busy.presence(IDE::read_chreg(
-            &self,
-            channel,
-            ATARegister::CommandORStatus,
-        )) {}
+        self.wait_not_busy(channel);
 
         let state: u8 = IDE::read_chreg(&self, channel, ATARegister::CommandORStatus);
         if ATAStatus::Error.presence(state) {
@@ -726,7 +766,8 @@ impl IDE {
         let mut head = 0x00;
         let mut address_sliced: [u8; 6] = [0u8; 6];
 
-        IDE::write_chreg(&self, channel, ATARegister::ControlORAltStatus, 0x02);
+        /* Interrupts on, `polling` sleeps until the drive is done */
+        IDE::write_chreg(&self, channel, ATARegister::ControlORAltStatus, 0x00);
         self.ide_irq_invoked = 0x00;
         self.channels[channel].no_interrupt = false;
 
@@ -771,12 +812,7 @@ impl IDE {
             head = ((address + 1 - sector as u64) % (16 * 63) / 63) as u8;
         }
 
-        /* Wait until not busy */
-        while ATAStatus::Busy.presence(IDE::read_chreg(
-            &self,
-            channel,
-            ATARegister::CommandORStatus,
-        )) {}
+        self.wait_not_busy(channel);
 
         if addressing_mode == 0x00 {
             /* We indicate CHS mode */
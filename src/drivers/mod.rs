@@ -1,3 +1,4 @@
 pub mod ide;
 pub mod pci;
+pub mod rtc;
 // pub mod ac97;
@@ -0,0 +1,363 @@
+use crate::irq;
+use crate::sync::IrqSpinlock;
+use crate::tooling::serial::{inb, outb};
+
+/* CMOS real-time clock. The RTC keeps the date and time while the machine is off, in BCD
+ * or binary and with a 12 or 24 hour clock depending on status register B. A read can
+ * race with the once-a-second update, so `read` waits for the update-in-progress flag
+ * to clear and reads until two reads in a row agree.
+ *
+ * Optionally the RTC raises IRQ 8 periodically (2 Hz - 8 kHz) or when the alarm time is
+ * reached. */
+
+// https://wiki.osdev.org/CMOS
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_UPDATE_IRQ: u8 = 1 << 4;
+const STATUS_B_ALARM_IRQ: u8 = 1 << 5;
+const STATUS_B_PERIODIC_IRQ: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+pub const IRQ_LINE: u8 = 8;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// days since 1970-01-01 of a date in the proleptic Gregorian calendar,
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        (days * 86400) as u64
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64;
+        let seconds = timestamp % 86400;
+
+        // the inverse of days_from_civil
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+/* The register is selected through the address port before every access */
+static CMOS: IrqSpinlock<()> = IrqSpinlock::new(());
+
+fn read_register(reg: u8) -> u8 {
+    let _cmos = CMOS.lock();
+    outb(CMOS_ADDRESS, reg);
+    inb(CMOS_DATA)
+}
+
+fn write_register(reg: u8, value: u8) {
+    let _cmos = CMOS.lock();
+    outb(CMOS_ADDRESS, reg);
+    outb(CMOS_DATA, value);
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/* 12 AM is midnight, 12 PM noon */
+fn hour_from_12(hour: u8, pm: bool) -> u8 {
+    hour % 12 + if pm { 12 } else { 0 }
+}
+
+// 1-12 and whether it is PM
+fn hour_to_12(hour: u8) -> (u8, bool) {
+    let twelve = if hour % 12 == 0 { 12 } else { hour % 12 };
+    (twelve, hour >= 12)
+}
+
+fn read_raw() -> [u8; 6] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+    ]
+}
+
+/// Reads the current date and time from the RTC. The RTC is assumed to run in UTC
+pub fn read() -> DateTime {
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year] = raw;
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour = hour_from_12(hour, pm);
+    }
+
+    /* No century register without the FADT, a two digit year is good until 2069 */
+    let year = if year < 70 { 2000 } else { 1900 } + year as u16;
+    DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+type RtcHandler = fn();
+
+struct Handlers {
+    periodic: Option<RtcHandler>,
+    alarm: Option<RtcHandler>,
+}
+
+/* Also held while status register B is changed, so that enabling one interrupt doesn't
+ * undo another */
+static HANDLERS: IrqSpinlock<Handlers> = IrqSpinlock::new(Handlers {
+    periodic: None,
+    alarm: None,
+});
+
+fn rtc_irq(_line: u8) -> bool {
+    /* Reading C acknowledges the interrupt, without it the RTC never raises another one.
+     * Its flags are at the same bits as the enable bits in B */
+    let flags = read_register(REG_STATUS_C);
+    let (periodic, alarm) = {
+        let handlers = HANDLERS.lock();
+        (handlers.periodic, handlers.alarm)
+    };
+    if flags & STATUS_B_PERIODIC_IRQ != 0 {
+        if let Some(handler) = periodic {
+            handler();
+        }
+    }
+    if flags & STATUS_B_ALARM_IRQ != 0 {
+        if let Some(handler) = alarm {
+            handler();
+        }
+    }
+    flags & (STATUS_B_PERIODIC_IRQ | STATUS_B_ALARM_IRQ | STATUS_B_UPDATE_IRQ) != 0
+}
+
+fn enable_irq(bit: u8) -> Result<(), &'static str> {
+    let _handlers = HANDLERS.lock();
+    let status_b = read_register(REG_STATUS_B);
+    if status_b & (STATUS_B_PERIODIC_IRQ | STATUS_B_ALARM_IRQ) == 0 {
+        irq::register(IRQ_LINE, "rtc", rtc_irq)?;
+    }
+    write_register(REG_STATUS_B, status_b | bit);
+    read_register(REG_STATUS_C);
+    Ok(())
+}
+
+fn disable_irq(bit: u8) {
+    let _handlers = HANDLERS.lock();
+    let status_b = read_register(REG_STATUS_B) & !bit;
+    write_register(REG_STATUS_B, status_b);
+    if status_b & (STATUS_B_PERIODIC_IRQ | STATUS_B_ALARM_IRQ) == 0 {
+        let _ = irq::unregister(IRQ_LINE, rtc_irq);
+    }
+}
+
+/// Calls `handler` at 32768 >> (rate - 1) Hz, `rate` 3 (8 kHz) to 15 (2 Hz)
+pub fn enable_periodic(rate: u8, handler: RtcHandler) -> Result<(), &'static str> {
+    if !(3..=15).contains(&rate) {
+        return Err("RTC rate must be 3-15");
+    }
+    /* Nothing changes unless the interrupt line could be registered */
+    enable_irq(STATUS_B_PERIODIC_IRQ)?;
+    let status_a = read_register(REG_STATUS_A);
+    write_register(REG_STATUS_A, (status_a & 0xF0) | rate);
+    HANDLERS.lock().periodic = Some(handler);
+    Ok(())
+}
+
+pub fn disable_periodic() {
+    disable_irq(STATUS_B_PERIODIC_IRQ);
+    HANDLERS.lock().periodic = None;
+}
+
+/// Calls `handler` every day when the RTC reaches `hour`:`minute`:`second`
+pub fn set_alarm(hour: u8, minute: u8, second: u8, handler: RtcHandler) -> Result<(), &'static str> {
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err("invalid alarm time");
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let encode = |value: u8| {
+        if status_b & STATUS_B_BINARY == 0 {
+            binary_to_bcd(value)
+        } else {
+            value
+        }
+    };
+    let hour = if status_b & STATUS_B_24_HOUR == 0 {
+        /* 12 hour clock: 1-12 with the PM bit */
+        let (twelve, pm) = hour_to_12(hour);
+        encode(twelve) | if pm { HOUR_PM } else { 0 }
+    } else {
+        encode(hour)
+    };
+
+    enable_irq(STATUS_B_ALARM_IRQ)?;
+    write_register(REG_SECONDS_ALARM, encode(second));
+    write_register(REG_MINUTES_ALARM, encode(minute));
+    write_register(REG_HOURS_ALARM, hour);
+    HANDLERS.lock().alarm = Some(handler);
+    Ok(())
+}
+
+pub fn clear_alarm() {
+    disable_irq(STATUS_B_ALARM_IRQ);
+    HANDLERS.lock().alarm = None;
+}
+
+#[cfg(test)]
+mod rtc_tests {
+    use super::*;
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn test_unix_timestamps_round_trip() {
+        let cases = [
+            (date(1970, 1, 1, 0, 0, 0), 0),
+            (date(1999, 12, 31, 23, 59, 59), 946684799),
+            (date(2000, 2, 29, 12, 34, 56), 951827696),
+            (date(2000, 3, 1, 0, 0, 0), 951868800),
+            (date(2038, 1, 19, 3, 14, 7), 2147483647),
+        ];
+        for (date, timestamp) in cases {
+            assert!(date.to_unix() == timestamp);
+            assert!(DateTime::from_unix(timestamp) == date);
+        }
+    }
+
+    #[test]
+    fn test_days_from_civil() {
+        assert!(days_from_civil(1970, 1, 1) == 0);
+        assert!(days_from_civil(1969, 12, 31) == -1);
+        assert!(days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28) == 2);
+        assert!(days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28) == 1);
+    }
+
+    #[test]
+    fn test_bcd() {
+        assert!(bcd_to_binary(0x00) == 0);
+        assert!(bcd_to_binary(0x09) == 9);
+        assert!(bcd_to_binary(0x10) == 10);
+        assert!(bcd_to_binary(0x59) == 59);
+        for value in 0..100 {
+            assert!(bcd_to_binary(binary_to_bcd(value)) == value);
+        }
+    }
+
+    #[test]
+    fn test_12_hour_clock() {
+        // 12 AM is midnight, 12 PM noon
+        assert!(hour_from_12(12, false) == 0);
+        assert!(hour_from_12(1, false) == 1);
+        assert!(hour_from_12(12, true) == 12);
+        assert!(hour_from_12(11, true) == 23);
+
+        assert!(hour_to_12(0) == (12, false));
+        assert!(hour_to_12(11) == (11, false));
+        assert!(hour_to_12(12) == (12, true));
+        assert!(hour_to_12(23) == (11, true));
+        for hour in 0..24 {
+            let (twelve, pm) = hour_to_12(hour);
+            assert!(hour_from_12(twelve, pm) == hour);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use alloc::vec::Vec;
+
+/* ELF64 executables. `parse` checks the headers of a statically linked little endian
+ * x86_64 executable and returns its entry point and loadable segments. Every offset and
+ * size in the file is checked against its length, a broken binary is rejected with an
+ * error instead of being read past its end. Mapping the segments is up to loader.rs.
+ *
+ * https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html */
+
+const EI_MAG: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 0x3E;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+// segments have to be in the lower half, where user mappings go, and keep off its last
+// page (see process::USER_END)
+const USER_END: u64 = 0x0000_8000_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+/// A PT_LOAD segment: `file_size` bytes from `offset` in the file go to `vaddr`, the rest
+/// up to `mem_size` is zeroed (.bss)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    /// PF_* bits
+    pub flags: u32,
+}
+
+impl Segment {
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    fn end(&self) -> u64 {
+        self.vaddr + self.mem_size
+    }
+}
+
+#[derive(Debug)]
+pub struct Elf {
+    pub entry: u64,
+    /// Sorted by address, they don't overlap
+    pub segments: Vec<Segment>,
+    /// Where the program headers end up in memory, if a segment loads them (AT_PHDR)
+    pub program_headers: Option<u64>,
+    pub program_header_count: u16,
+}
+
+impl Elf {
+    /// First address after the highest segment
+    pub fn end(&self) -> u64 {
+        self.segments.last().map_or(0, |segment| segment.end())
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Checks the ELF file in `data` and returns what the loader needs
+pub fn parse(data: &[u8]) -> Result<Elf, &'static str> {
+    if data.len() < EHDR_SIZE {
+        return Err("file is too small for an ELF header");
+    }
+    if data[0..4] != EI_MAG {
+        return Err("not an ELF file");
+    }
+    if data[4] != ELFCLASS64 {
+        return Err("not a 64 bit ELF file");
+    }
+    if data[5] != ELFDATA2LSB {
+        return Err("not a little endian ELF file");
+    }
+    if data[6] != EV_CURRENT || u32_at(data, 20) != EV_CURRENT as u32 {
+        return Err("unknown ELF version");
+    }
+    match u16_at(data, 16) {
+        ET_EXEC => (),
+        ET_DYN => return Err("position independent executables aren't supported"),
+        _ => return Err("not an executable"),
+    }
+    if u16_at(data, 18) != EM_X86_64 {
+        return Err("not an x86_64 executable");
+    }
+
+    let entry = u64_at(data, 24);
+    let phoff = u64_at(data, 32);
+    let phentsize = u16_at(data, 54) as usize;
+    let phnum = u16_at(data, 56);
+    if phentsize != PHDR_SIZE {
+        return Err("unexpected program header size");
+    }
+    if phnum == 0 {
+        return Err("no program headers");
+    }
+    let phdrs_end = (phnum as u64)
+        .checked_mul(PHDR_SIZE as u64)
+        .and_then(|size| phoff.checked_add(size))
+        .ok_or("program headers are out of bounds")?;
+    if phdrs_end > data.len() as u64 {
+        return Err("program headers are out of bounds");
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum as usize {
+        let phdr = &data[phoff as usize + i * PHDR_SIZE..][..PHDR_SIZE];
+        match u32_at(phdr, 0) {
+            PT_LOAD => (),
+            PT_INTERP => return Err("dynamically linked executables aren't supported"),
+            _ => continue,
+        }
+        let segment = Segment {
+            flags: u32_at(phdr, 4),
+            offset: u64_at(phdr, 8),
+            vaddr: u64_at(phdr, 16),
+            file_size: u64_at(phdr, 32),
+            mem_size: u64_at(phdr, 40),
+        };
+        let align = u64_at(phdr, 48);
+
+        if segment.file_size > segment.mem_size {
+            return Err("segment is larger in the file than in memory");
+        }
+        match segment.offset.checked_add(segment.file_size) {
+            Some(end) if end <= data.len() as u64 => (),
+            _ => return Err("segment data is out of bounds"),
+        }
+        match segment.vaddr.checked_add(segment.mem_size) {
+            Some(end) if end <= USER_END - PAGE_SIZE => (),
+            _ => return Err("segment is outside of user memory"),
+        }
+        if align > 1 && (!align.is_power_of_two() || segment.vaddr % align != segment.offset % align) {
+            return Err("segment is misaligned");
+        }
+        if segment.mem_size > 0 {
+            segments.push(segment);
+        }
+    }
+    if segments.is_empty() {
+        return Err("nothing to load");
+    }
+
+    segments.sort_by_key(|segment| segment.vaddr);
+    if segments.windows(2).any(|pair| pair[0].end() > pair[1].vaddr) {
+        return Err("segments overlap");
+    }
+    if !segments
+        .iter()
+        .any(|segment| segment.executable() && (segment.vaddr..segment.end()).contains(&entry))
+    {
+        return Err("entry point is not in an executable segment");
+    }
+
+    let program_headers = segments
+        .iter()
+        .find(|segment| segment.offset <= phoff && phdrs_end <= segment.offset + segment.file_size)
+        .map(|segment| segment.vaddr + (phoff - segment.offset));
+
+    Ok(Elf {
+        entry,
+        segments,
+        program_headers,
+        program_header_count: phnum,
+    })
+}
+
+#[cfg(test)]
+mod elf_tests {
+    use super::*;
+    use std::fs;
+
+    /* Made by src/test_files/elf/make_fixtures.py */
+    fn fixture(name: &str) -> Vec<u8> {
+        fs::read(format!("src/test_files/elf/{}", name)).unwrap()
+    }
+
+    // offset of field `offset` in program header `index` of hello.elf
+    fn phdr_field(index: usize, offset: usize) -> usize {
+        EHDR_SIZE + index * PHDR_SIZE + offset
+    }
+
+    fn patch_u64(data: &mut [u8], offset: usize, value: u64) {
+        data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_parses_hello() {
+        let elf = parse(&fixture("hello.elf")).unwrap();
+        assert!(elf.entry == 0x4000B0);
+        assert!(elf.segments.len() == 2);
+
+        let text = &elf.segments[0];
+        assert!(text.vaddr == 0x400000 && text.offset == 0);
+        assert!(text.executable() && !text.writable());
+
+        let data = &elf.segments[1];
+        assert!(data.writable() && !data.executable());
+        /* .bss follows the data */
+        assert!(data.mem_size > data.file_size);
+
+        assert!(elf.program_headers == Some(0x400000 + EHDR_SIZE as u64));
+        assert!(elf.program_header_count == 2);
+        assert!(elf.end() == data.vaddr + data.mem_size);
+    }
+
+    #[test]
+    fn test_rejects_bad_magic() {
+        assert!(parse(&fixture("bad_magic.elf")).unwrap_err() == "not an ELF file");
+    }
+
+    #[test]
+    fn test_rejects_truncated_file() {
+        let data = fixture("truncated.elf");
+        assert!(parse(&data).unwrap_err() == "program headers are out of bounds");
+        assert!(parse(&data[..EHDR_SIZE - 1]).unwrap_err() == "file is too small for an ELF header");
+        assert!(parse(&[]).is_err());
+    }
+
+    #[test]
+    fn test_rejects_kernel_segment() {
+        assert!(parse(&fixture("kernel_segment.elf")).unwrap_err() == "segment is outside of user memory");
+    }
+
+    #[test]
+    fn test_rejects_other_formats() {
+        let hello = fixture("hello.elf");
+
+        let mut data = hello.clone();
+        data[4] = 1;
+        assert!(parse(&data).unwrap_err() == "not a 64 bit ELF file");
+
+        let mut data = hello.clone();
+        data[5] = 2;
+        assert!(parse(&data).unwrap_err() == "not a little endian ELF file");
+
+        let mut data = hello.clone();
+        data[16] = ET_DYN as u8;
+        assert!(parse(&data).unwrap_err() == "position independent executables aren't supported");
+
+        let mut data = hello.clone();
+        data[18] = 0x03; // EM_386
+        assert!(parse(&data).unwrap_err() == "not an x86_64 executable");
+    }
+
+    #[test]
+    fn test_rejects_bad_segments() {
+        let hello = fixture("hello.elf");
+
+        let mut data = hello.clone();
+        patch_u64(&mut data, phdr_field(1, 32), 0x10000);
+        assert!(parse(&data).unwrap_err() == "segment is larger in the file than in memory");
+
+        let mut data = hello.clone();
+        patch_u64(&mut data, phdr_field(1, 8), u64::MAX);
+        assert!(parse(&data).unwrap_err() == "segment data is out of bounds");
+
+        let mut data = hello.clone();
+        patch_u64(&mut data, phdr_field(1, 16), u64::MAX - 8);
+        assert!(parse(&data).unwrap_err() == "segment is outside of user memory");
+
+        /* Ending right at the top of the lower half, in the last page */
+        let mut data = hello.clone();
+        let mem_size = u64::from_le_bytes(data[phdr_field(1, 40)..phdr_field(1, 48)].try_into().unwrap());
+        patch_u64(&mut data, phdr_field(1, 16), USER_END - mem_size);
+        assert!(parse(&data).unwrap_err() == "segment is outside of user memory");
+
+        let mut data = hello.clone();
+        patch_u64(&mut data, phdr_field(1, 16), 0x400008);
+        assert!(parse(&data).unwrap_err() == "segment is misaligned");
+
+        /* The code segment grown over the data */
+        let mut data = hello.clone();
+        patch_u64(&mut data, phdr_field(0, 40), 0x2000);
+        assert!(parse(&data).unwrap_err() == "segments overlap");
+
+        let mut data = hello.clone();
+        data[phdr_field(0, 0)] = PT_INTERP as u8;
+        assert!(parse(&data).unwrap_err() == "dynamically linked executables aren't supported");
+    }
+
+    #[test]
+    fn test_rejects_entry_outside_code() {
+        let mut data = fixture("hello.elf");
+        let data_vaddr = u64::from_le_bytes(data[phdr_field(1, 16)..][..8].try_into().unwrap());
+        patch_u64(&mut data, 24, data_vaddr);
+        assert!(parse(&data).unwrap_err() == "entry point is not in an executable segment");
+    }
+}
@@ -0,0 +1,485 @@
+use core::arch::global_asm;
+use core::fmt;
+use core::ptr;
+
+use crate::bord::{HandlerFunc, IDTEntry, Ring};
+use crate::gdt;
+use crate::mem::fault::{self, PageFault};
+use crate::mem::memory::{get_cr0, get_cr2, get_cr3, get_cr4};
+use crate::mem::stack;
+use crate::process;
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+use crate::task;
+use crate::tooling::vga::write_str_at;
+
+/* CPU exceptions (vectors 0-31). Every vector has a small assembly stub that pushes a
+ * dummy error code if the CPU doesn't push one, the vector number and all general purpose
+ * registers, so `exception_dispatch` always sees the same `ExceptionFrame`. Page faults
+ * are offered to the registered fault handlers first (see mem/fault.rs), everything else
+ * is handled according to the policy of its vector: a crash report followed by a panic,
+ * a report after which the interrupted code continues, or nothing at all. A fault that
+ * would halt the kernel kills the process instead when it comes from user code. */
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NMI: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+
+pub const EXCEPTION_COUNT: usize = 32;
+
+struct ExceptionInfo {
+    mnemonic: &'static str,
+    name: &'static str,
+    /* The error code is a segment selector index */
+    selector_error: bool,
+}
+
+const fn info(mnemonic: &'static str, name: &'static str, selector_error: bool) -> ExceptionInfo {
+    ExceptionInfo {
+        mnemonic,
+        name,
+        selector_error,
+    }
+}
+
+// https://wiki.osdev.org/Exceptions
+const EXCEPTIONS: [ExceptionInfo; EXCEPTION_COUNT] = [
+    info("#DE", "divide error", false),
+    info("#DB", "debug", false),
+    info("NMI", "non-maskable interrupt", false),
+    info("#BP", "breakpoint", false),
+    info("#OF", "overflow", false),
+    info("#BR", "bound range exceeded", false),
+    info("#UD", "invalid opcode", false),
+    info("#NM", "device not available", false),
+    info("#DF", "double fault", false),
+    info("-", "coprocessor segment overrun", false),
+    info("#TS", "invalid TSS", true),
+    info("#NP", "segment not present", true),
+    info("#SS", "stack segment fault", true),
+    info("#GP", "general protection fault", true),
+    info("#PF", "page fault", false),
+    info("-", "reserved", false),
+    info("#MF", "x87 floating point exception", false),
+    info("#AC", "alignment check", false),
+    info("#MC", "machine check", false),
+    info("#XM", "SIMD floating point exception", false),
+    info("#VE", "virtualization exception", false),
+    info("#CP", "control protection exception", false),
+    info("-", "reserved", false),
+    info("-", "reserved", false),
+    info("-", "reserved", false),
+    info("-", "reserved", false),
+    info("-", "reserved", false),
+    info("-", "reserved", false),
+    info("#HV", "hypervisor injection exception", false),
+    info("#VC", "VMM communication exception", false),
+    info("#SX", "security exception", false),
+    info("-", "reserved", false),
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Policy {
+    /// Print the crash report and panic
+    Halt,
+    /// Print the crash report and return to the interrupted code
+    Report,
+    /// Return to the interrupted code without a word
+    Ignore,
+}
+
+static POLICIES: IrqSpinlock<[Policy; EXCEPTION_COUNT]> = IrqSpinlock::new(default_policies());
+
+const fn default_policies() -> [Policy; EXCEPTION_COUNT] {
+    let mut policies = [Policy::Halt; EXCEPTION_COUNT];
+    policies[DEBUG as usize] = Policy::Report;
+    policies[NMI as usize] = Policy::Report;
+    policies[BREAKPOINT as usize] = Policy::Report;
+    policies[OVERFLOW as usize] = Policy::Report;
+    policies
+}
+
+/// Changes what happens on `vector`. Only traps and NMIs can continue, returning from a
+/// fault would run into the same fault again
+pub fn set_policy(vector: u8, policy: Policy) -> Result<(), &'static str> {
+    if vector as usize >= EXCEPTION_COUNT {
+        return Err("not an exception vector");
+    }
+    if policy != Policy::Halt && !matches!(vector, DEBUG | NMI | BREAKPOINT | OVERFLOW) {
+        return Err("only traps and NMIs can continue");
+    }
+    POLICIES.lock()[vector as usize] = policy;
+    Ok(())
+}
+
+pub fn policy(vector: u8) -> Policy {
+    POLICIES.lock()[vector as usize]
+}
+
+/// Everything the stubs push, lowest address first
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// 0 for exceptions without an error code
+    pub error_code: u64,
+    /* Pushed by the CPU */
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+// https://wiki.osdev.org/Exceptions#Selector_Error_Code
+struct SelectorError(u64);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = match (self.0 >> 1) & 0b11 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} index {:#x}", table, (self.0 >> 3) & 0x1FFF)?;
+        if self.0 & 1 != 0 {
+            write!(f, ", external")?;
+        }
+        Ok(())
+    }
+}
+
+// Vectors 8, 10-14, 17, 21, 29 and 30 come with an error code from the CPU
+global_asm!(
+    r#"
+.macro exception_stub vector, error_code
+exception_stub_\vector:
+.if \error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp exception_common
+.endm
+
+exception_stub 0, 0
+exception_stub 1, 0
+exception_stub 2, 0
+exception_stub 3, 0
+exception_stub 4, 0
+exception_stub 5, 0
+exception_stub 6, 0
+exception_stub 7, 0
+exception_stub 8, 1
+exception_stub 9, 0
+exception_stub 10, 1
+exception_stub 11, 1
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 15, 0
+exception_stub 16, 0
+exception_stub 17, 1
+exception_stub 18, 0
+exception_stub 19, 0
+exception_stub 20, 0
+exception_stub 21, 1
+exception_stub 22, 0
+exception_stub 23, 0
+exception_stub 24, 0
+exception_stub 25, 0
+exception_stub 26, 0
+exception_stub 27, 0
+exception_stub 28, 0
+exception_stub 29, 1
+exception_stub 30, 1
+exception_stub 31, 0
+
+exception_common:
+    /* From user mode the GS base is the user's, get the per-CPU one back */
+    test byte ptr [rsp + 24], 3
+    jz 1f
+    swapgs
+1:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    /* The CPU aligned the stack before pushing its frame, 22 pushes keep it aligned */
+    cld
+    mov rdi, rsp
+    call exception_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    /* vector and error code */
+    add rsp, 16
+    test byte ptr [rsp + 8], 3
+    jz 1f
+    swapgs
+1:
+    iretq
+
+.section .rodata
+.balign 8
+.global EXCEPTION_STUBS
+EXCEPTION_STUBS:
+.irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    .quad exception_stub_\vector
+.endr
+.text
+
+/* Interrupt vectors 32-255, one 64 byte stub each. The handlers are x86-interrupt
+ * functions that expect the per-CPU GS base and return with iretq. From kernel mode the
+ * stub just jumps to the handler. From user mode it swaps GS and calls the handler with
+ * a frame that returns to kernel mode, right after the call, then swaps back and returns
+ * to user mode. `sub rsp, 8` keeps the copied frame aligned like the CPU's */
+.balign 64
+.global INTERRUPT_STUBS
+INTERRUPT_STUBS:
+.set interrupt_vector, 32
+.rept 224
+.balign 64
+    test byte ptr [rsp + 8], 3
+    jnz 1f
+    jmp qword ptr [rip + INTERRUPT_HANDLERS + interrupt_vector * 8]
+1:
+    swapgs
+    sub rsp, 8
+    push {kernel_data}
+    push rsp
+    pushfq
+    push {kernel_code}
+    call qword ptr [rip + INTERRUPT_HANDLERS + interrupt_vector * 8]
+    /* iretq of the handler left rsp at the pushed SS */
+    add rsp, 16
+    swapgs
+    iretq
+.set interrupt_vector, interrupt_vector + 1
+.endr
+"#,
+    kernel_data = const gdt::KERNEL_DATA_SELECTOR as u64,
+    kernel_code = const gdt::KERNEL_CODE_SELECTOR as u64,
+);
+
+const INTERRUPT_STUB_SIZE: u64 = 64;
+
+/* Handler of every vector from 32 on, the stubs call through it */
+#[no_mangle]
+static mut INTERRUPT_HANDLERS: [u64; 256] = [0; 256];
+
+extern "C" {
+    static EXCEPTION_STUBS: [u64; EXCEPTION_COUNT];
+    static INTERRUPT_STUBS: u8;
+}
+
+/// Address of the entry stub of `vector`, for the IDT
+pub fn stub(vector: u8) -> u64 {
+    unsafe { EXCEPTION_STUBS[vector as usize] }
+}
+
+/// IDT entry for an interrupt vector (32-255) that runs `handler`, also when the
+/// interrupt comes in while user code runs
+pub fn interrupt_entry(vector: u8, handler: HandlerFunc) -> IDTEntry {
+    assert!(vector as usize >= EXCEPTION_COUNT, "exceptions have their own stubs");
+    unsafe {
+        INTERRUPT_HANDLERS[vector as usize] = handler as u64;
+        let stubs = ptr::addr_of!(INTERRUPT_STUBS) as u64;
+        let stub = stubs + (vector as u64 - EXCEPTION_COUNT as u64) * INTERRUPT_STUB_SIZE;
+        IDTEntry::from_addr(stub, Ring::Zero)
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    let vector = frame.vector as u8;
+    match vector {
+        PAGE_FAULT => page_fault(frame),
+        DOUBLE_FAULT => double_fault(frame),
+        _ => match policy(vector) {
+            Policy::Ignore => {}
+            Policy::Report => report(frame),
+            Policy::Halt if frame.from_user() => kill(frame),
+            Policy::Halt => halt(frame),
+        },
+    }
+}
+
+fn page_fault(frame: &ExceptionFrame) {
+    let fault = PageFault {
+        addr: unsafe { get_cr2() },
+        error_code: frame.error_code,
+        ip: frame.rip,
+        sp: frame.rsp,
+    };
+    if fault::handle(&fault) {
+        return;
+    }
+    if frame.from_user() {
+        fault::report(&fault);
+        kill(frame);
+    }
+    if let Some(task) = stack::guard_owner(fault.addr) {
+        report(frame);
+        panic!("kernel stack overflow in task {} ({})", task, fault);
+    }
+
+    fault::report(&fault);
+    halt(frame);
+}
+
+// runs on its own IST stack (gdt::DOUBLE_FAULT_IST), so it also works when the double
+// fault comes from a stack overflow. The error code is always 0
+fn double_fault(frame: &ExceptionFrame) {
+    // cr2 still holds the address of the page fault that couldn't be delivered
+    let addr = unsafe { get_cr2() };
+    if let Some(task) = stack::guard_owner(addr).or(stack::guard_owner(frame.rsp)) {
+        report(frame);
+        panic!(
+            "kernel stack overflow in task {} (rsp {:#x}, ip {:#x})",
+            task, frame.rsp, frame.rip
+        );
+    }
+    halt(frame);
+}
+
+// a fault in user code only takes down the process that caused it
+fn kill(frame: &ExceptionFrame) -> ! {
+    let info = &EXCEPTIONS[frame.vector as usize];
+    let pid = task::current_id();
+    qemu_println!(
+        "process {} ({}) killed: {} {} at ip {:#x}",
+        pid,
+        process::name(pid).unwrap_or("?"),
+        info.mnemonic,
+        info.name,
+        frame.rip
+    );
+    process::exit(process::KILLED_EXIT_CODE + frame.vector as i64);
+}
+
+fn halt(frame: &ExceptionFrame) -> ! {
+    let info = &EXCEPTIONS[frame.vector as usize];
+    report(frame);
+    write_str_at("err: ", 5, 0, 0xde);
+    write_str_at(info.name, 5, 5, 0xde);
+    panic!(
+        "unhandled {} {} at ip {:#x}",
+        info.mnemonic, info.name, frame.rip
+    );
+}
+
+/// Prints the exception, the error code and all registers over serial
+pub fn report(frame: &ExceptionFrame) {
+    let info = &EXCEPTIONS[frame.vector as usize];
+    let (cr0, cr2, cr3, cr4) = unsafe { (get_cr0(), get_cr2(), get_cr3(), get_cr4()) };
+
+    qemu_println!("==================== exception ====================");
+    qemu_println!(
+        "{} {} (vector {}) in {} mode",
+        info.mnemonic,
+        info.name,
+        frame.vector,
+        if frame.from_user() { "user" } else { "kernel" }
+    );
+    if info.selector_error && frame.error_code != 0 {
+        qemu_println!(
+            "error code {:#x}: {}",
+            frame.error_code,
+            SelectorError(frame.error_code)
+        );
+    } else {
+        qemu_println!("error code {:#x}", frame.error_code);
+    }
+
+    qemu_println!(
+        "rip {:#018x} cs  {:#06x} rflags {:#010x}",
+        frame.rip,
+        frame.cs,
+        frame.rflags
+    );
+    qemu_println!("rsp {:#018x} ss  {:#06x}", frame.rsp, frame.ss);
+    qemu_println!(
+        "rax {:#018x} rbx {:#018x} rcx {:#018x}",
+        frame.rax,
+        frame.rbx,
+        frame.rcx
+    );
+    qemu_println!(
+        "rdx {:#018x} rsi {:#018x} rdi {:#018x}",
+        frame.rdx,
+        frame.rsi,
+        frame.rdi
+    );
+    qemu_println!(
+        "rbp {:#018x} r8  {:#018x} r9  {:#018x}",
+        frame.rbp,
+        frame.r8,
+        frame.r9
+    );
+    qemu_println!(
+        "r10 {:#018x} r11 {:#018x} r12 {:#018x}",
+        frame.r10,
+        frame.r11,
+        frame.r12
+    );
+    qemu_println!(
+        "r13 {:#018x} r14 {:#018x} r15 {:#018x}",
+        frame.r13,
+        frame.r14,
+        frame.r15
+    );
+    qemu_println!("cr0 {:#018x} cr2 {:#018x}", cr0, cr2);
+    qemu_println!("cr3 {:#018x} cr4 {:#018x}", cr3, cr4);
+    qemu_println!("===================================================");
+}
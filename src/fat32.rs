@@ -1,11 +1,66 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
 use heapless::String;
 
 use crate::drivers::ide::{self, ATADirection, IDE};
+use crate::drivers::rtc::DateTime;
 use crate::mem::memory::{kmemcpy, kmemset};
+use crate::mem::stats::{self, Subsystem};
+use crate::time;
+use crate::wait::Mutex;
 use crate::tooling::qemu_io::{qemu_print, qemu_print_hex, qemu_println};
 
-/* Temporary until proper memory allocation is fixed */
-const LOAD_ADDR: u64 = 0x43000000;
+/// Why a filesystem operation failed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsError {
+    /// Nothing at the path
+    NotFound,
+    /// Expected a file, found a directory
+    IsDirectory,
+    /// Expected a directory, found a file
+    NotDirectory,
+    /// The directory already has an object with that name
+    Exists,
+    /// The directory has no free entry left
+    DirectoryFull,
+    /// The name doesn't fit 8.3
+    InvalidName,
+    /// The partition doesn't hold a FAT32 filesystem
+    NotFat32,
+    NotMounted,
+    AlreadyMounted,
+}
+
+impl FsError {
+    pub fn message(self) -> &'static str {
+        match self {
+            FsError::NotFound => "File was not found!",
+            FsError::IsDirectory => "File is a directory!",
+            FsError::NotDirectory => "Given directory is a file!",
+            FsError::Exists => "A file object under this name already exists",
+            FsError::DirectoryFull => "Couldn't found an available entry in given directory",
+            FsError::InvalidName => "Given filename is not valid!",
+            FsError::NotFat32 => "Partition is not a valid FAT32 filesystem!",
+            FsError::NotMounted => "no filesystem mounted",
+            FsError::AlreadyMounted => "a filesystem is already mounted",
+        }
+    }
+}
+
+/* Callers that report errors as strings can keep using `?` */
+impl From<FsError> for &'static str {
+    fn from(err: FsError) -> Self {
+        err.message()
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -90,7 +145,7 @@ impl DirectoryEntry {
     }
 
     /// Filename given with extension in point form
-    pub fn compare_filename(&self, filename: &str) -> Result<bool, &'static str> {
+    pub fn compare_filename(&self, filename: &str) -> Result<bool, FsError> {
         let mut part_counter: u8 = 0x00;
         for part in filename.split('.') {
             match part_counter {
@@ -116,7 +171,7 @@ impl DirectoryEntry {
                         return Ok(false);
                     }
                 }
-                _ => return Err("Given filename is not valid!"),
+                _ => return Err(FsError::InvalidName),
             }
 
             part_counter += 1;
@@ -140,7 +195,7 @@ impl DirectoryEntry {
     }
 
     // TODO: check for illegal characters
-    pub fn parse_filename(filename: &str) -> Result<([u8; 8], [u8; 3]), &'static str> {
+    pub fn parse_filename(filename: &str) -> Result<([u8; 8], [u8; 3]), FsError> {
         let mut name: [u8; 8] = [0x20u8; 8];
         let mut ext: [u8; 3] = [0x20u8; 3];
 
@@ -149,7 +204,7 @@ impl DirectoryEntry {
             match part_counter {
                 0x00 => {
                     if part.len() > 8 {
-                        return Err("Name longer than 8 bytes");
+                        return Err(FsError::InvalidName);
                     }
                     for (i, c) in part.bytes().enumerate() {
                         name[i] = c;
@@ -158,7 +213,7 @@ impl DirectoryEntry {
                 }
                 0x01 => {
                     if part.len() > 3 {
-                        return Err("Extension longer than 3 bytes");
+                        return Err(FsError::InvalidName);
                     }
                     for (i, c) in part.bytes().enumerate() {
                         ext[i] = c;
@@ -166,7 +221,7 @@ impl DirectoryEntry {
                     part_counter += 1;
                 }
                 _ => {
-                    return Err("Wrong format of given filename");
+                    return Err(FsError::InvalidName);
                 }
             }
         }
@@ -195,6 +250,34 @@ impl DirectoryEntry {
         let lo: u16 = (chain & 0xFFFF) as u16;
         return (hi, lo);
     }
+
+    /// Returns in format (date, time, 10ms units). FAT times only have a 2 second
+    /// resolution, the 10ms field of the creation time holds the odd second
+    pub fn fat_timestamp(at: &DateTime) -> (u16, u16, u8) {
+        /* Dates are counted from 1980 */
+        let date: u16 =
+            ((at.year.saturating_sub(1980) as u16) << 9) | ((at.month as u16) << 5) | at.day as u16;
+        let time: u16 =
+            ((at.hour as u16) << 11) | ((at.minute as u16) << 5) | (at.second as u16 / 2);
+        return (date, time, (at.second % 2) * 100);
+    }
+
+    /// Sets all timestamps to `at`
+    pub fn stamp_created(&mut self, at: &DateTime) {
+        let (date, time, time_10ms) = DirectoryEntry::fat_timestamp(at);
+        self.creation_time_100ms = time_10ms;
+        self.creation_time = time;
+        self.creation_date = date;
+        self.stamp_modified(at);
+    }
+
+    /// Sets the modification and access timestamps to `at`
+    pub fn stamp_modified(&mut self, at: &DateTime) {
+        let (date, time, _) = DirectoryEntry::fat_timestamp(at);
+        self.last_accessed_date = date;
+        self.last_modification_time = time;
+        self.last_modification_date = date;
+    }
 }
 
 pub enum FATEntry {}
@@ -208,15 +291,18 @@ impl FATEntry {
 }
 
 pub struct FAT {
+    /* All FATs as loaded from the disk, `fat_address` points into it */
+    buffer: Vec<u8>,
     fat_address: u64,
     sectors_per_fat: u32,
     fat_num: u8,
 }
 
 impl FAT {
-    pub fn new(fat_address: u64, sectors_per_fat: u32, fat_num: u8) -> Self {
+    pub fn new(buffer: Vec<u8>, sectors_per_fat: u32, fat_num: u8) -> Self {
         Self {
-            fat_address: fat_address,
+            fat_address: buffer.as_ptr() as u64,
+            buffer: buffer,
             sectors_per_fat: sectors_per_fat,
             fat_num: fat_num,
         }
@@ -250,12 +336,13 @@ impl<'b, 'a> Iterator for FATChainFollower<'b, 'a> {
             return None;
         }
 
+        let load_addr: u64 = self.fs_processor.load_addr();
         self.fs_processor.ide_processor.ata_access_pio(
             ATADirection::Read,
             0,
             self.fs_processor.cluster_lba(current_chain),
             self.fs_processor.sectors_per_cluster,
-            LOAD_ADDR,
+            load_addr,
         );
 
         self.current_chain = unsafe { *fat.offset(current_chain as isize) } & 0x0FFFFFFF;
@@ -297,6 +384,8 @@ impl<'a> Iterator for FATChainSearcher<'a> {
 pub struct FAT32<'a> {
     ide_processor: &'a mut IDE,
     fat_processor: FAT,
+    /* Holds the cluster (or sector) that is currently being worked on */
+    cluster_buffer: Vec<u8>,
 
     partition_start_lba: u32,
     reserved_sectors: u16,
@@ -308,36 +397,37 @@ pub struct FAT32<'a> {
 }
 
 impl<'a> FAT32<'a> {
-    pub fn new(ide_processor: &'a mut IDE) -> Result<Self, &'static str> {
-        ide_processor.ata_access_pio(ATADirection::Read, 0, 0x01, 1, 0x41000000);
-
-        let bootsector: BootSector = unsafe { *(0x41000000 as *const _) };
-        let extended_boot_record: &ExtendedBootRecord = unsafe { &*(0x41000024 as *const _) };
+    pub fn new(ide_processor: &'a mut IDE) -> Result<Self, FsError> {
+        let _mem = stats::scope(Subsystem::Fs);
+        let mut sector: Vec<u8> = vec![0x00u8; 512];
+        ide_processor.ata_access_pio(ATADirection::Read, 0, 0x01, 1, sector.as_mut_ptr() as u64);
+
+        let bootsector: BootSector = unsafe { *(sector.as_ptr() as *const _) };
+        let extended_boot_record: ExtendedBootRecord =
+            unsafe { *(sector.as_ptr().offset(0x24) as *const _) };
         if extended_boot_record.signature != 0x28 && extended_boot_record.signature != 0x29 {
-            return Err("Signature in extended boot record is not valid!");
+            return Err(FsError::NotFat32);
         }
 
         /* Check byte by byte, since the u64 is misaligned :( */
         let system_identifier_match: [u8; 8] = [0x46, 0x41, 0x54, 0x33, 0x32, 0x20, 0x20, 0x20];
         for i in 0usize..8usize {
             if system_identifier_match[i] != extended_boot_record.system_identifier[i] {
-                return Err("System identifier string in extended boot record is not valid!");
+                return Err(FsError::NotFat32);
             }
         }
 
-        let fsinfo_address: u64 =
-            0x41000000 + 0x200 * extended_boot_record.fsinfo_sector_num as u64;
         ide_processor.ata_access_pio(
             ATADirection::Read,
             0,
             bootsector.num_hidden_sectors as u64 + extended_boot_record.fsinfo_sector_num as u64,
             1,
-            fsinfo_address,
+            sector.as_mut_ptr() as u64,
         );
 
-        let fsinfo: &FSInfoMain = unsafe { &*(fsinfo_address as *const _) };
+        let fsinfo: &FSInfoMain = unsafe { &*(sector.as_ptr() as *const _) };
         if fsinfo.signature_1 != 0x41615252 || fsinfo.signature_2 != 0x61417272 {
-            return Err("Signatures in FSInfo struct are not valid!");
+            return Err(FsError::NotFat32);
         }
 
         let mut total_sectors: u32 = bootsector.num_sectors as u32;
@@ -346,21 +436,28 @@ impl<'a> FAT32<'a> {
         }
 
         /* Load FAT */
+        let fat_sectors: u32 = extended_boot_record.sectors_per_fat * bootsector.num_fats as u32;
+        let mut fat_buffer: Vec<u8> =
+            vec![0x00u8; fat_sectors as usize * bootsector.bytes_per_sector as usize];
         ide_processor.ata_access_pio(
             ATADirection::Read,
             0,
             bootsector.num_hidden_sectors as u64 + bootsector.reserved_sectors as u64,
-            (extended_boot_record.sectors_per_fat * bootsector.num_fats as u32) as u8,
-            0x42000000,
+            fat_sectors as u8,
+            fat_buffer.as_mut_ptr() as u64,
         );
 
+        let cluster_size: usize =
+            bootsector.sectors_per_cluster as usize * bootsector.bytes_per_sector as usize;
+
         Ok(Self {
             ide_processor: ide_processor,
             fat_processor: FAT::new(
-                0x42000000,
+                fat_buffer,
                 extended_boot_record.sectors_per_fat,
                 bootsector.num_fats,
             ),
+            cluster_buffer: vec![0x00u8; cluster_size],
             partition_start_lba: bootsector.num_hidden_sectors,
             reserved_sectors: bootsector.reserved_sectors,
             sectors_per_cluster: bootsector.sectors_per_cluster,
@@ -373,16 +470,16 @@ impl<'a> FAT32<'a> {
 
     /// Returns the file size of the file at the given path. Using this function on
     /// directories will result in an error!!!
-    pub fn get_file_size(&mut self, path: &str) -> Result<u32, &'static str> {
+    pub fn get_file_size(&mut self, path: &str) -> Result<u32, FsError> {
         let packed: Option<(DirectoryEntry, u32, u64)> = self.traverse(path)?;
         if packed.is_none() {
-            return Err("File was not found!");
+            return Err(FsError::NotFound);
         }
 
         let unpacked: (DirectoryEntry, u32, u64) = packed.unwrap();
         let entry: DirectoryEntry = unpacked.0;
         if entry.file_attribute == 0x10 {
-            return Err("File is a directory!");
+            return Err(FsError::IsDirectory);
         }
 
         Ok(entry.file_size)
@@ -392,44 +489,42 @@ impl<'a> FAT32<'a> {
     /// `path` path to file to read
     /// `to` buffer to place read data
     /// `n` number of characters to read
-    pub fn read_file(&mut self, path: &str, to: &mut [u8], n: usize) -> Result<(), &'static str> {
+    pub fn read_file(&mut self, path: &str, to: &mut [u8], n: usize) -> Result<(), FsError> {
         let packed: Option<(DirectoryEntry, u32, u64)> = self.traverse(path)?;
         if packed.is_none() {
-            return Err("File was not found!");
+            return Err(FsError::NotFound);
         }
 
         let unpacked: (DirectoryEntry, u32, u64) = packed.unwrap();
         let entry: DirectoryEntry = unpacked.0;
         if entry.file_attribute == 0x10 {
-            return Err("File is a directory!");
+            return Err(FsError::IsDirectory);
         }
 
         /* main FAT where we are searching clusters */
         let fat: *const u32 = self.fat_processor.fat_address as *const u32;
 
-        let mut remaining: usize = core::cmp::min(entry.file_size as usize, n);
+        /* Never read past the end of the file or the given buffer */
+        let total: usize = core::cmp::min(core::cmp::min(entry.file_size as usize, n), to.len());
+        let mut remaining: usize = total;
         /* Cluster in bytes */
         let clb: usize = self.bytes_per_sector as usize * self.sectors_per_cluster as usize;
+        let load_addr: u64 = self.load_addr();
 
         for ncluster in FATChainFollower::new(entry.get_chain(), self) {
-            let offset_in_mem: u64 = entry.file_size as u64 - remaining as u64;
-            /* Copy to buffer */
-            if remaining < clb {
-                kmemcpy(
-                    unsafe { (LOAD_ADDR + offset_in_mem) as *const u8 },
-                    unsafe { to.as_mut_ptr() },
-                    remaining,
-                );
+            if remaining == 0 {
                 break;
             }
 
-            /* Copy one cluster */
+            /* Copy (at most) one cluster to the buffer */
+            let copied: usize = total - remaining;
+            let chunk: usize = core::cmp::min(remaining, clb);
             kmemcpy(
-                unsafe { (LOAD_ADDR + offset_in_mem) as *const u8 },
-                unsafe { to.as_mut_ptr() },
-                clb,
+                load_addr as *const u8,
+                unsafe { to.as_mut_ptr().offset(copied as isize) },
+                chunk,
             );
-            remaining -= clb;
+            remaining -= chunk;
         }
         Ok(())
     }
@@ -438,19 +533,20 @@ impl<'a> FAT32<'a> {
     /// `path` the path to the file to write to
     /// `from` the buffer to write from
     /// `n` number of characters to write to file
-    pub fn write_file(&mut self, path: &str, from: &[u8], n: usize) -> Result<(), &'static str> {
+    pub fn write_file(&mut self, path: &str, from: &[u8], n: usize) -> Result<(), FsError> {
         let packed: Option<(DirectoryEntry, u32, u64)> = self.traverse(path)?;
         if packed.is_none() {
-            return Err("File was not found!");
+            return Err(FsError::NotFound);
         }
 
         let unpacked: (DirectoryEntry, u32, u64) = packed.unwrap();
         let entry: DirectoryEntry = unpacked.0;
         if entry.file_attribute == 0x10 {
-            return Err("File is a directory!");
+            return Err(FsError::IsDirectory);
         }
 
         let fat: *mut u32 = self.fat_processor.fat_address as *mut u32;
+        let load_addr: u64 = self.load_addr();
         let mut last_chain: u32 = unpacked.0.get_chain();
 
         /* Get last cluster chain number */
@@ -480,14 +576,14 @@ impl<'a> FAT32<'a> {
             0x00,
             self.cluster_lba(last_chain),
             self.sectors_per_cluster as u8,
-            LOAD_ADDR,
+            load_addr,
         );
 
         let first_cluster_offset: usize = unpacked.0.file_size as usize % clb;
         let first_write_num: usize = core::cmp::min(clb - first_cluster_offset, n);
         kmemcpy(
             from.as_ptr(),
-            (LOAD_ADDR + first_cluster_offset as u64) as *mut u8,
+            (load_addr + first_cluster_offset as u64) as *mut u8,
             first_write_num,
         );
 
@@ -497,7 +593,7 @@ impl<'a> FAT32<'a> {
             0x00,
             self.cluster_lba(last_chain),
             self.sectors_per_cluster as u8,
-            LOAD_ADDR,
+            load_addr,
         );
 
         /* Don't forget to overwrite while writing to clusters */
@@ -506,26 +602,34 @@ impl<'a> FAT32<'a> {
         while remaining > 0 {
             if remaining < clb {
                 /* Write the remaining part to disk */
-                kmemcpy(from.as_ptr(), LOAD_ADDR as *mut u8, remaining);
+                kmemcpy(
+                    unsafe { from.as_ptr().offset((n - remaining) as isize) },
+                    load_addr as *mut u8,
+                    remaining,
+                );
                 self.ide_processor.ata_access_pio(
                     ATADirection::Write,
                     0x00,
                     self.cluster_lba(last_chain),
                     ((remaining + self.bytes_per_sector as usize - 1)
                         / (self.bytes_per_sector as usize)) as u8,
-                    LOAD_ADDR,
+                    load_addr,
                 );
                 break;
             }
 
             /* Write a whole cluster to disk */
-            kmemcpy(from.as_ptr(), LOAD_ADDR as *mut u8, clb);
+            kmemcpy(
+                unsafe { from.as_ptr().offset((n - remaining) as isize) },
+                load_addr as *mut u8,
+                clb,
+            );
             self.ide_processor.ata_access_pio(
                 ATADirection::Write,
                 0x00,
                 self.cluster_lba(last_chain),
                 self.sectors_per_cluster,
-                LOAD_ADDR,
+                load_addr,
             );
 
             last_chain = unsafe { *fat.offset(last_chain as isize) };
@@ -538,12 +642,13 @@ impl<'a> FAT32<'a> {
             0x00,
             self.cluster_lba(unpacked.1) + unpacked.2 / self.bytes_per_sector as u64,
             1,
-            LOAD_ADDR,
+            load_addr,
         );
         /* Append the size */
         let offset: u64 = unpacked.2 % self.bytes_per_sector as u64;
-        let entry: &mut DirectoryEntry = unsafe { &mut *((LOAD_ADDR + offset) as *mut _) };
+        let entry: &mut DirectoryEntry = unsafe { &mut *((load_addr + offset) as *mut _) };
         entry.file_size += n as u32;
+        entry.stamp_modified(&time::wall_clock());
 
         /* Write the entry back to disk */
         self.ide_processor.ata_access_pio(
@@ -551,7 +656,7 @@ impl<'a> FAT32<'a> {
             0x00,
             self.cluster_lba(unpacked.1) + unpacked.2 / self.bytes_per_sector as u64,
             1,
-            LOAD_ADDR,
+            load_addr,
         );
 
         Ok(())
@@ -565,7 +670,7 @@ impl<'a> FAT32<'a> {
         &mut self,
         directory_path: &str,
         filename: &str,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), FsError> {
         /* Create directory entry and write it to disk */
         self.create_object(directory_path, filename, 0x03)?;
 
@@ -576,27 +681,28 @@ impl<'a> FAT32<'a> {
         &mut self,
         directory_path: &str,
         dirname: &str,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), FsError> {
         /* Create directory entry and write it to disk */
         let (parent, current) = self.create_object(directory_path, dirname, 0x10)?;
+        let load_addr: u64 = self.load_addr();
 
         self.ide_processor.ata_access_pio(
             ATADirection::Read,
             0x00,
             self.cluster_lba(current),
             self.sectors_per_cluster as u8,
-            LOAD_ADDR,
+            load_addr,
         );
         /* Set cluster to 0x00 */
         kmemset(
-            unsafe { LOAD_ADDR as *mut u8 },
+            unsafe { load_addr as *mut u8 },
             0x00,
             self.sectors_per_cluster as usize * self.bytes_per_sector as usize,
         );
         /* Add '.' and '..' which are required entries in a directory */
         unsafe {
-            let dot: &mut DirectoryEntry = &mut *(LOAD_ADDR as *mut _);
-            let dotdot: &mut DirectoryEntry = &mut *((LOAD_ADDR + 32) as *mut _);
+            let dot: &mut DirectoryEntry = &mut *(load_addr as *mut _);
+            let dotdot: &mut DirectoryEntry = &mut *((load_addr + 32) as *mut _);
 
             let dot_cluster = DirectoryEntry::divide_chain(current);
             dot.file_name = [
@@ -631,6 +737,10 @@ impl<'a> FAT32<'a> {
             dotdot.last_modification_date = 0x00;
             dotdot.low_first_entry_cluster = dotdot_cluster.1;
             dotdot.file_size = 0x00;
+
+            let now = time::wall_clock();
+            dot.stamp_created(&now);
+            dotdot.stamp_created(&now);
         }
 
         /* Write back cluster to disk */
@@ -639,39 +749,39 @@ impl<'a> FAT32<'a> {
             0x00,
             self.cluster_lba(current),
             self.sectors_per_cluster as u8,
-            LOAD_ADDR,
+            load_addr,
         );
 
         Ok(())
     }
 
     /// Deletes a file on given path. Returns error if operation was not performed
-    pub fn delete_file(&mut self, path: &str) -> Result<(), &'static str> {
+    pub fn delete_file(&mut self, path: &str) -> Result<(), FsError> {
         let packed: Option<(DirectoryEntry, u32, u64)> = self.traverse(path)?;
         if packed.is_none() {
-            return Err("File was not found!");
+            return Err(FsError::NotFound);
         }
 
         let unpacked: (DirectoryEntry, u32, u64) = packed.unwrap();
         let entry: DirectoryEntry = unpacked.0;
         if entry.file_attribute == 0x10 {
-            return Err("File is a directory!");
+            return Err(FsError::IsDirectory);
         }
 
         Ok(self.delete_object(&unpacked.0, unpacked.1, unpacked.2)?)
     }
 
     /// Deletes a directory on given path. Returns error if operation was not performed
-    pub fn delete_directory(&mut self, path: &str) -> Result<(), &'static str> {
+    pub fn delete_directory(&mut self, path: &str) -> Result<(), FsError> {
         let packed: Option<(DirectoryEntry, u32, u64)> = self.traverse(path)?;
         if packed.is_none() {
-            return Err("Directory was not found!");
+            return Err(FsError::NotFound);
         }
 
         let unpacked: (DirectoryEntry, u32, u64) = packed.unwrap();
         let entry: DirectoryEntry = unpacked.0;
         if entry.file_attribute != 0x10 {
-            return Err("File is a file!");
+            return Err(FsError::NotDirectory);
         }
 
         Ok(self.delete_object(&unpacked.0, unpacked.1, unpacked.2)?)
@@ -688,28 +798,29 @@ impl<'a> FAT32<'a> {
         directory_path: &str,
         filename: &str,
         file_attibute: u8,
-    ) -> Result<(u32, u32), &'static str> {
+    ) -> Result<(u32, u32), FsError> {
         /* Creates a directory entry in the given directory cluster `cluster` by searching
          * for available clusters. Then places `file_attribute` and allocates 1 FAT entry.
          * Finally it writes the directory entry to disk and dumps the FAT to disk as well.*/
         /* Cluster size in bytes */
+        let load_addr: u64 = self.load_addr();
         let mut cluster: u32 = 0x02;
         /* If not root directory */
         if directory_path.len() != 0x00 {
             let packed: Option<(DirectoryEntry, u32, u64)> = self.traverse(directory_path)?;
             if packed.is_none() {
-                return Err("Directory was not found");
+                return Err(FsError::NotFound);
             }
 
             let unpacked: (DirectoryEntry, u32, u64) = packed.unwrap();
             if unpacked.0.file_attribute != 0x10 {
-                return Err("Given directory is a file!");
+                return Err(FsError::NotDirectory);
             }
             cluster = unpacked.0.get_chain();
         }
 
         if self.internal_object_exists(cluster, filename) {
-            return Err("A file object under this name already exists");
+            return Err(FsError::Exists);
         }
 
         /* Bytes per cluster */
@@ -722,7 +833,7 @@ impl<'a> FAT32<'a> {
          * available entries */
         'cluster_loop: for ncluster in FATChainFollower::new(cluster, self) {
             for offset in (0..clb).step_by(32) {
-                let first_byte: u8 = unsafe { *((LOAD_ADDR + offset) as *const u8) };
+                let first_byte: u8 = unsafe { *((load_addr + offset) as *const u8) };
                 /* If found available entry */
                 if first_byte == 0x00 || first_byte == 0xE5 {
                     /* Since we cannot mut borrow multiple times */
@@ -734,7 +845,7 @@ impl<'a> FAT32<'a> {
             }
         }
         if !ok {
-            return Err("Couldn't found an available entry in given directory");
+            return Err(FsError::DirectoryFull);
         }
 
         let allocated_chain: u32 = self.allocate_chain(1);
@@ -743,7 +854,7 @@ impl<'a> FAT32<'a> {
         let (hichain, lochain) = DirectoryEntry::divide_chain(allocated_chain);
 
         /* Change the found and available directory entry */
-        let entry: &mut DirectoryEntry = unsafe { &mut *((LOAD_ADDR + dir_offset) as *mut _) };
+        let entry: &mut DirectoryEntry = unsafe { &mut *((load_addr + dir_offset) as *mut _) };
         entry.file_name = name;
         entry.file_ext = ext;
         entry.file_attribute = file_attibute;
@@ -757,6 +868,7 @@ impl<'a> FAT32<'a> {
         entry.last_modification_date = 0x00;
         entry.low_first_entry_cluster = lochain;
         entry.file_size = 0x00;
+        entry.stamp_created(&time::wall_clock());
 
         /* Write new directory entry to disk */
         self.ide_processor.ata_access_pio(
@@ -764,7 +876,7 @@ impl<'a> FAT32<'a> {
             0x00,
             self.cluster_lba(cluster_target) + dir_offset / self.bytes_per_sector as u64,
             1,
-            LOAD_ADDR + (dir_offset / self.bytes_per_sector as u64) * self.bytes_per_sector as u64,
+            load_addr + (dir_offset / self.bytes_per_sector as u64) * self.bytes_per_sector as u64,
         );
 
         /* Write FAT tables from memory to disk */
@@ -780,24 +892,25 @@ impl<'a> FAT32<'a> {
         entry: &DirectoryEntry,
         cluster: u32,
         offset: u64,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), FsError> {
+        let load_addr: u64 = self.load_addr();
         /* The LBA address of the sector where the given directory entry is found */
         let offset_in_sectors: u64 = offset / self.bytes_per_sector as u64;
         let lba: u64 = self.cluster_lba(cluster) + offset_in_sectors;
 
         /* Read that sector, modify the entry and write back to disk... */
         self.ide_processor
-            .ata_access_pio(ATADirection::Read, 0x00, lba, 1, LOAD_ADDR);
+            .ata_access_pio(ATADirection::Read, 0x00, lba, 1, load_addr);
 
         unsafe {
             /* Mark directory entry as unused by setting the first byte to 0xE5 */
-            let addr: *mut u8 = LOAD_ADDR as *mut u8;
+            let addr: *mut u8 = load_addr as *mut u8;
             *addr.offset((offset % self.bytes_per_sector as u64) as isize) = 0xE5;
         }
 
         /* Write the modified directory entry back to disk */
         self.ide_processor
-            .ata_access_pio(ATADirection::Write, 0x00, lba, 1, LOAD_ADDR);
+            .ata_access_pio(ATADirection::Write, 0x00, lba, 1, load_addr);
 
         /* Clean the cluster chain in FAT, that is associated with the given object */
         self.deallocate_chain(entry.get_chain());
@@ -813,9 +926,10 @@ impl<'a> FAT32<'a> {
         &mut self,
         chain: u32,
         name: &str,
-    ) -> Result<Option<(DirectoryEntry, u32, u64)>, &'static str> {
+    ) -> Result<Option<(DirectoryEntry, u32, u64)>, FsError> {
+        let load_addr: u64 = self.load_addr();
         'cluster_loop: for ncluster in FATChainFollower::new(chain, self) {
-            let mut dir_offset: u64 = LOAD_ADDR;
+            let mut dir_offset: u64 = load_addr;
             loop {
                 let fetch: Option<(*mut DirectoryEntry, bool)> = DirectoryEntry::fetch(dir_offset);
                 /* None is marking the end of directory */
@@ -830,7 +944,7 @@ impl<'a> FAT32<'a> {
                 }
 
                 if (*entry).compare_filename(name)? {
-                    return Ok(Some(((*entry), ncluster, dir_offset - LOAD_ADDR)));
+                    return Ok(Some(((*entry), ncluster, dir_offset - load_addr)));
                 }
                 dir_offset += 32;
             }
@@ -847,7 +961,7 @@ impl<'a> FAT32<'a> {
     pub fn traverse(
         &mut self,
         path: &str,
-    ) -> Result<Option<(DirectoryEntry, u32, u64)>, &'static str> {
+    ) -> Result<Option<(DirectoryEntry, u32, u64)>, FsError> {
         let mut found_entry: Option<(DirectoryEntry, u32, u64)> = None;
 
         let mut current_chain: u32 = 0x02;
@@ -855,7 +969,7 @@ impl<'a> FAT32<'a> {
             /* If a directory entry was found, unpack it and search through it */
             if let Some(entry) = found_entry {
                 if entry.0.file_attribute != 0x10 {
-                    return Err("Trying to search through a file!");
+                    return Err(FsError::NotDirectory);
                 }
 
                 /* Assemble the cluster number where the directory is placed */
@@ -874,10 +988,11 @@ impl<'a> FAT32<'a> {
     /// Only for use inside filesystem!!
     fn internal_object_exists(&mut self, cluster: u32, filename: &str) -> bool {
         let end: usize = self.sectors_per_cluster as usize * self.bytes_per_sector as usize;
+        let load_addr: u64 = self.load_addr();
         'cluster_loop: for _ in FATChainFollower::new(cluster, self) {
             let mut offset = 0x00;
             loop {
-                let fetch = unsafe { DirectoryEntry::fetch(LOAD_ADDR + offset as u64) };
+                let fetch = unsafe { DirectoryEntry::fetch(load_addr + offset as u64) };
                 if fetch.is_none() {
                     break 'cluster_loop;
                 }
@@ -898,6 +1013,13 @@ impl<'a> FAT32<'a> {
         return false;
     }
 
+    /// Address of the buffer clusters are read to and written from. Mutably borrowed,
+    /// since the disk and the callers write through it
+    #[inline]
+    fn load_addr(&mut self) -> u64 {
+        self.cluster_buffer.as_mut_ptr() as u64
+    }
+
     /// Converts cluster number to a valid LBA address
     fn cluster_lba(&self, cluster: u32) -> u64 {
         self.partition_start_lba as u64
@@ -966,38 +1088,75 @@ impl<'a> FAT32<'a> {
     }
 }
 
-pub fn test_filesystem(fs_processor: &mut FAT32) {
+/* The filesystem system calls and the program loader work on, see `mount`. Held across
+ * the IDE transfers, so a task that wants it meanwhile sleeps instead of spinning */
+static FILESYSTEM: Mutex<Option<FAT32<'static>>> = Mutex::new(None);
+
+/// Mounts the FAT32 partition of the first IDE disk for `with_filesystem`
+pub fn mount() -> Result<(), FsError> {
+    let mut filesystem = FILESYSTEM.lock();
+    if filesystem.is_some() {
+        return Err(FsError::AlreadyMounted);
+    }
+    /* Lives as long as the mount, which is forever */
+    let ide_processor: &'static mut IDE = {
+        let _mem = stats::scope(Subsystem::Drivers);
+        Box::leak(Box::new(IDE::new()))
+    };
+    ide_processor.init();
+    *filesystem = Some(FAT32::new(ide_processor)?);
+    Ok(())
+}
+
+/// Runs `f` on the mounted filesystem
+pub fn with_filesystem<T, E: From<FsError>>(
+    f: impl FnOnce(&mut FAT32<'static>) -> Result<T, E>,
+) -> Result<T, E> {
+    let mut filesystem = FILESYSTEM.lock();
+    f(filesystem.as_mut().ok_or(FsError::NotMounted)?)
+}
+
+pub fn test_filesystem(fs_processor: &mut FAT32) -> Result<(), &'static str> {
     let buf: [u8; 10] = [0x10u8; 10];
 
     let mut buf: [u8; 64] = [0x00u8; 64];
-    fs_processor.read_file("KEK/ABA/LOL3.TXT", &mut buf, 420);
-    fs_processor.delete_directory("KEK/ABA").unwrap();
-    fs_processor.create_file("KEK", "A.TXT").unwrap();
-    fs_processor.create_directory("", "UUU").unwrap();
-    fs_processor.create_directory("UUU", "OOO").unwrap();
-    fs_processor.create_file("UUU", "B.TXT").unwrap();
-    fs_processor.create_file("UUU", "AAA.TXT").unwrap();
-    fs_processor.create_file("UUU/OOO", "CD.TXT").unwrap();
-    fs_processor.create_file("KEK", "B0.TXT").unwrap();
+    let _ = fs_processor.read_file("KEK/ABA/LOL3.TXT", &mut buf, 420);
+    fs_processor.delete_directory("KEK/ABA")?;
+    fs_processor.create_file("KEK", "A.TXT")?;
+    fs_processor.create_directory("", "UUU")?;
+    fs_processor.create_directory("UUU", "OOO")?;
+    fs_processor.create_file("UUU", "B.TXT")?;
+    fs_processor.create_file("UUU", "AAA.TXT")?;
+    fs_processor.create_file("UUU/OOO", "CD.TXT")?;
+    fs_processor.create_file("KEK", "B0.TXT")?;
     let str1: &str = "append from fs wow!";
     fs_processor
         .write_file("KEK/A.TXT", str1.as_bytes(), str1.len())
-        .unwrap();
+        ?;
     let str2: &str = " [please hope this appends]";
     fs_processor
         .write_file("LOL.TXT", str2.as_bytes(), str2.len())
-        .unwrap();
-    fs_processor.create_file("UUU/OOO", "LOL.TXT").unwrap();
+        ?;
+    fs_processor.create_file("UUU/OOO", "LOL.TXT")?;
 
     fs_processor
         .write_file("UUU/OOO/LOL.TXT", str2.as_bytes(), str2.len())
-        .unwrap();
+        ?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod fat32_tests {
     use super::*;
     use std::fs::File;
+    use std::io::Read;
+
+    /* Reads the boot sector the way `FAT32::new` does */
+    fn read_bootsector(f: &mut File) -> BootSector {
+        let mut sector = [0u8; core::mem::size_of::<BootSector>()];
+        f.read_exact(&mut sector).unwrap();
+        unsafe { *(sector.as_ptr() as *const BootSector) }
+    }
 
     #[test]
     fn test_read_bootsector_data_is_ordered() {
@@ -1022,4 +1181,23 @@ mod fat32_tests {
         assert!(boot_sector.num_hidden_sectors == 0x15);
         assert!(boot_sector.large_sector_count == 0x16);
     }
+
+    #[test]
+    fn test_fat_timestamp_packs_date_and_time() {
+        let at = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        let (date, time, time_10ms) = DirectoryEntry::fat_timestamp(&at);
+
+        /* Bits 15-9 year since 1980, 8-5 month, 4-0 day */
+        assert!(date == (44 << 9) | (2 << 5) | 29);
+        /* Bits 15-11 hours, 10-5 minutes, 4-0 seconds / 2 */
+        assert!(time == (23 << 11) | (59 << 5) | 29);
+        assert!(time_10ms == 100);
+    }
 }
@@ -0,0 +1,147 @@
+use core::arch::asm;
+use core::mem::size_of;
+
+use crate::bord::{load_gdt, Ring, SegmentDescriptor, GDTR};
+use crate::mem::stack;
+use crate::percpu;
+use crate::qemu_println;
+
+/* The kernel's own GDT and TSS. The GDT left behind by the VBR only has kernel segments
+ * and no TSS. The TSS holds the stack the CPU switches to when an interrupt arrives in
+ * ring 3 (rsp0) and the Interrupt Stack Table: exceptions that can happen while the stack
+ * is broken (double faults on a stack overflow, NMIs, machine checks) switch to a known
+ * good stack through it.
+ *
+ * The user segments are in the order SYSRET expects: user data right before user code.
+ * Every CPU has its own GDT and TSS, the TSS can't be shared because the CPU marks the
+ * descriptor busy on `ltr`, and every CPU needs its own IST stacks. */
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+/* RPL 3 */
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+pub const TSS_SELECTOR: u16 = 0x28;
+
+/// IST entries (1-based, as used in the IDT) of the exceptions that get their own stack
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+pub const MACHINE_CHECK_IST: u8 = 3;
+const IST_STACK_PAGES: u64 = 4;
+
+// https://wiki.osdev.org/Task_State_Segment#Long_Mode
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    reserved1: u32,
+    pub rsp: [u64; 3],
+    reserved2: u64,
+    pub ist: [u64; 7],
+    reserved3: u64,
+    reserved4: u16,
+    pub iomap_base: u16,
+}
+
+pub(crate) const TSS_INIT: TaskStateSegment = TaskStateSegment {
+    reserved1: 0,
+    rsp: [0; 3],
+    reserved2: 0,
+    ist: [0; 7],
+    reserved3: 0,
+    reserved4: 0,
+    /* No I/O permission bitmap */
+    iomap_base: size_of::<TaskStateSegment>() as u16,
+};
+
+pub(crate) const GDT_ENTRIES: usize = 7;
+
+// null, kernel code, kernel data, user data, user code and the TSS (which takes two entries)
+pub(crate) const GDT_INIT: [u64; GDT_ENTRIES] = [
+    0,
+    SegmentDescriptor::code(Ring::Zero).as_u64(),
+    SegmentDescriptor::data(Ring::Zero).as_u64(),
+    SegmentDescriptor::data(Ring::Three).as_u64(),
+    SegmentDescriptor::code(Ring::Three).as_u64(),
+    0,
+    0,
+];
+
+// https://wiki.osdev.org/Global_Descriptor_Table#Long_Mode_System_Segment_Descriptor
+fn tss_descriptor(base: u64, limit: u32) -> (u64, u64) {
+    let low = SegmentDescriptor::new(
+        base as u32,
+        limit,
+        SegmentDescriptor::TSS_AVAILABLE,
+        0,
+        Ring::Zero,
+    );
+    (low.as_u64(), base >> 32)
+}
+
+/// Loads the GDT and TSS of the calling CPU, which live in its `PerCpu` (call
+/// `percpu::init` first)
+pub fn init() {
+    let cpu = percpu::current();
+    for (ist, name) in [
+        (DOUBLE_FAULT_IST, "double fault"),
+        (NMI_IST, "nmi"),
+        (MACHINE_CHECK_IST, "machine check"),
+    ] {
+        let stack = stack::alloc(name, IST_STACK_PAGES).unwrap();
+        cpu.tss.ist[ist as usize - 1] = stack.top();
+    }
+
+    let (low, high) = tss_descriptor(
+        core::ptr::addr_of!(cpu.tss) as u64,
+        size_of::<TaskStateSegment>() as u32 - 1,
+    );
+    cpu.gdt[TSS_SELECTOR as usize / 8] = low;
+    cpu.gdt[TSS_SELECTOR as usize / 8 + 1] = high;
+
+    unsafe {
+        load_gdt(&GDTR {
+            limit: size_of::<[u64; GDT_ENTRIES]>() as u16 - 1,
+            base: cpu.gdt.as_ptr() as u64,
+        });
+        reload_segments();
+
+        asm!("ltr {0:x}", in(reg) TSS_SELECTOR, options(nostack, preserves_flags));
+    }
+    qemu_println!("gdt: GDT and TSS of cpu {} loaded", cpu.index);
+}
+
+/// Stack the CPU switches to when an interrupt, exception or system call arrives in
+/// ring 3
+pub fn set_kernel_stack(top: u64) {
+    let cpu = percpu::current();
+    cpu.tss.rsp[0] = top;
+    /* SYSCALL doesn't look at the TSS, the entry takes the stack from here */
+    cpu.kernel_stack = top;
+}
+
+pub fn kernel_stack() -> u64 {
+    percpu::current().tss.rsp[0]
+}
+
+/// Points IST entry `ist` (1-7) of the calling CPU at the stack ending at `top`
+pub fn set_ist(ist: u8, top: u64) {
+    assert!((1..=7).contains(&ist), "gdt: IST entries are 1-7");
+    percpu::current().tss.ist[ist as usize - 1] = top;
+}
+
+// cs can only be changed with a far jump/return
+unsafe fn reload_segments() {
+    asm!(
+        "push {code}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "mov ss, {data:x}",
+        code = in(reg) KERNEL_CODE_SELECTOR as u64,
+        data = in(reg) KERNEL_DATA_SELECTOR,
+        tmp = out(reg) _,
+        options(preserves_flags)
+    );
+}
@@ -4,12 +4,15 @@ use crate::math::utils::float_ceil;
 use crate::math::utils::float_floor;
 use crate::math::vec2::*;
 use crate::mem::alloc::kalloc;
+use crate::mem::memory::phys_to_virt;
 use crate::tooling::qemu_io::*;
 use crate::tooling::serial::inb;
 use crate::tooling::serial::outb;
 use crate::tooling::serial::outw;
 use crate::tooling::vga::VGAWriter;
 
+use alloc::boxed::Box;
+use alloc::vec;
 use core::cell;
 use core::cmp::max;
 use core::cmp::min;
@@ -30,8 +33,8 @@ https://qbmikehawk.neocities.org/articles/palette/
 
 pub struct VgaPlanarWriter {
     video_buffer: &'static mut [u8],
-    plane_buffer: [u8; VgaPlanarWriter::PLANE_BUFF_SZ],
-    //plane_buffer: &'static mut [u8],
+    //Too large for a task stack, lives on the heap
+    plane_buffer: Box<[u8]>,
     //plane_buffer: *mut ColorCode,
     pub palette: ColorPalette,
 }
@@ -46,10 +49,7 @@ impl VgaPlanarWriter {
     pub const COL_CNT: usize = 640;
 
     //Video memory adress
-    const VIDEO_MEM_BASE: *mut u8 = 0xA0000 as *mut u8;
-
-    //Alternative plane buffer memory adress
-    const PLANE_BUFF_BASE: *mut u8 = 0x1e8480 as *mut u8;
+    const VIDEO_MEM_BASE: *mut u8 = phys_to_virt(0xA0000) as *mut u8;
 
     //The size of one bitplane(plane)
     const PLANE_SZ: usize = VgaPlanarWriter::SCAN_LN_CNT * VgaPlanarWriter::SCAN_LN_SZ;
@@ -67,13 +67,7 @@ impl VgaPlanarWriter {
                     VgaPlanarWriter::VIDEO_MEM_BASE,
                     VgaPlanarWriter::VIDEO_MEM_SZ,
                 ),
-                /*
-                plane_buffer: core::slice::from_raw_parts_mut(
-                    VgaPlanarWriter::PLANE_BUFF_BASE,
-                    VgaPlanarWriter::PLANE_BUFF_SZ,
-                ),
-                */
-                plane_buffer: [0; VgaPlanarWriter::PLANE_BUFF_SZ],
+                plane_buffer: vec![0; VgaPlanarWriter::PLANE_BUFF_SZ].into_boxed_slice(),
                 /*
                 plane_buffer: core::slice::from_raw_parts_mut(
                     p as *mut u8,
@@ -5,6 +5,7 @@ use super::utils::ColorCode;
 use crate::graph::utils;
 use crate::math::vec2::{self, Vec2};
 use crate::mem::alloc;
+use crate::mem::stats::{self, Subsystem};
 use crate::tooling::qemu_io::*;
 
 /*
@@ -43,6 +44,7 @@ impl Surface {
         color: ColorCode,
         _background_color: Option<ColorCode>,
     ) -> Self {
+        let _mem = stats::scope(Subsystem::Graphics);
         let mut new_surface = Surface {
             width: font.font_width as usize,
             height: font.font_height as usize,
@@ -96,6 +98,7 @@ impl Surface {
 
     //Create a blank
     pub fn from_blank(_width: usize, _height: usize) -> Surface {
+        let _mem = stats::scope(Subsystem::Graphics);
         let new_surface = Surface {
             width: _width,
             height: _height,
@@ -3,11 +3,15 @@ use crate::input::key_codes::{
 };
 
 use super::key_codes;
+use crate::irq;
+use crate::sync::{Counter, IrqSpinlock};
+use crate::wait::WaitQueue;
+use core::sync::atomic::{AtomicI32, Ordering};
+use crate::tooling::serial::inb;
 
 type Callback = fn(key_code: i32);
 
-// NOT THREAD SAFE - needs to be fixed if more threads are added
-pub static mut KEYBOARD: Keyboard = Keyboard {
+pub static KEYBOARD: IrqSpinlock<Keyboard> = IrqSpinlock::new(Keyboard {
     shift: false,
     caps: false,
     alt: false,
@@ -17,10 +21,34 @@ pub static mut KEYBOARD: Keyboard = Keyboard {
     callback2: NOOP,
     callback3: NOOP,
     callback4: NOOP,
-};
+});
 
 pub const NOOP: fn(i32) = |_| {};
 
+const DATA_PORT: u16 = 0x60;
+
+static INPUT: WaitQueue = WaitQueue::new();
+static KEY_EVENTS: Counter = Counter::new(0);
+static LAST_KEY: AtomicI32 = AtomicI32::new(0);
+
+pub fn init() {
+    irq::register(1, "keyboard", keyboard_irq).unwrap();
+}
+
+fn keyboard_irq(_line: u8) -> bool {
+    let code = inb(DATA_PORT) as i32;
+    LAST_KEY.store(code, Ordering::Relaxed);
+    KEY_EVENTS.inc();
+    INPUT.wake_all();
+
+    /* The callbacks run without the lock, they may want to look at or change KEYBOARD */
+    let callbacks = KEYBOARD.lock().handle_key(code);
+    for callback in callbacks {
+        callback(code);
+    }
+    true
+}
+
 pub struct Keyboard {
     callback0: Callback,
     callback1: Callback,
@@ -49,13 +77,8 @@ impl Keyboard {
     pub fn set_callback4(&mut self, callback: Callback) {
         self.callback4 = callback;
     }
-    pub fn handle_key(&mut self, code: i32) {
-        (self.callback0)(code);
-        (self.callback1)(code);
-        (self.callback2)(code);
-        (self.callback3)(code);
-        (self.callback4)(code);
-
+    /// Updates the modifier state, returns the callbacks that should get `code`
+    pub fn handle_key(&mut self, code: i32) -> [Callback; 5] {
         match get_from_code_pressed(code) {
             KeyPressedCodes::CapsLock => self.caps = !self.caps,
             KeyPressedCodes::LeftShift => self.shift = true,
@@ -76,6 +99,14 @@ impl Keyboard {
             KeyReleasedCodes::RightAlt => self.alt = false,
             _ => (),
         }
+
+        [
+            self.callback0,
+            self.callback1,
+            self.callback2,
+            self.callback3,
+            self.callback4,
+        ]
     }
 }
 
@@ -86,3 +117,33 @@ pub fn get_key_pressed(key_code: i32) -> KeyPressedCodes {
 pub fn get_key_released(key_code: i32) -> KeyReleasedCodes {
     return get_from_code_released(key_code);
 }
+
+/// The character a key press types, without shift (letters are lower case). None for
+/// releases and keys that don't type anything
+pub fn to_ascii(key_code: i32) -> Option<u8> {
+    use KeyPressedCodes::*;
+    let letters = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    let digits = [Zero, One, Two, Three, Four, Five, Six, Seven, Eight, Nine];
+
+    if let Some(i) = letters.iter().position(|&key| key as i32 == key_code) {
+        return Some(b'a' + i as u8);
+    }
+    if let Some(i) = digits.iter().position(|&key| key as i32 == key_code) {
+        return Some(b'0' + i as u8);
+    }
+    match get_key_pressed(key_code) {
+        Enter => Some(b'\n'),
+        Space => Some(b' '),
+        Backspace => Some(0x08),
+        _ => None,
+    }
+}
+
+/// Blocks (halted) until the next key is pressed or released, returns its key code
+pub fn sleep_until_input() -> i32 {
+    let seen = KEY_EVENTS.get();
+    INPUT.wait_until(|| KEY_EVENTS.get() != seen);
+    LAST_KEY.load(Ordering::Relaxed)
+}
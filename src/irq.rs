@@ -0,0 +1,237 @@
+use crate::apic;
+use crate::bord::HandlerFunc;
+use crate::handlers::InterruptStackFrame;
+use crate::pic;
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+use crate::task;
+
+/* Hardware interrupts. The 16 PIC lines are remapped to vectors IRQ_BASE..IRQ_BASE + 16,
+ * every vector has an entry that calls `dispatch` with its line. Drivers claim lines
+ * with `register`, a line can be shared by up to MAX_SHARED handlers which are all called
+ * in registration order. The line is unmasked when it gets its first handler and masked
+ * again when the last one is unregistered. EOIs are sent here, not by the handlers.
+ *
+ * The lines come from the legacy PICs until `apic::init` switches to the IO APIC, the
+ * handlers don't notice the difference. */
+
+pub const IRQ_BASE: u8 = 32;
+pub const IRQ_LINES: usize = 16;
+const MAX_SHARED: usize = 4;
+
+/// Called with the line that fired. Returns true if the interrupt came from the handler's
+/// device, shared lines ask every handler
+pub type IrqHandler = fn(line: u8) -> bool;
+
+#[derive(Copy, Clone)]
+struct IrqAction {
+    name: &'static str,
+    handler: IrqHandler,
+}
+
+#[derive(Copy, Clone)]
+struct IrqLine {
+    actions: [Option<IrqAction>; MAX_SHARED],
+    count: u64,
+    /* Interrupts no handler claimed */
+    unhandled: u64,
+    spurious: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Controller {
+    Pic,
+    IoApic,
+}
+
+static CONTROLLER: IrqSpinlock<Controller> = IrqSpinlock::new(Controller::Pic);
+
+/* Not held while the handlers run, they may register and unregister handlers themselves */
+static IRQS: IrqSpinlock<[IrqLine; IRQ_LINES]> = IrqSpinlock::new(
+    [IrqLine {
+        actions: [None; MAX_SHARED],
+        count: 0,
+        unhandled: 0,
+        spurious: 0,
+    }; IRQ_LINES],
+);
+
+pub fn controller() -> Controller {
+    *CONTROLLER.lock()
+}
+
+/// Moves the IRQ lines to another interrupt controller, the lines that have handlers are
+/// unmasked there. Call with interrupts disabled and the old controller masked
+pub fn set_controller(controller: Controller) {
+    *CONTROLLER.lock() = controller;
+    let irqs = IRQS.lock();
+    for line in 0..IRQ_LINES as u8 {
+        if irqs[line as usize].actions[0].is_some() {
+            unmask(line);
+        }
+    }
+}
+
+fn mask(line: u8) {
+    match controller() {
+        Controller::Pic => pic::mask(line),
+        Controller::IoApic => apic::mask_irq(line),
+    }
+}
+
+fn unmask(line: u8) {
+    match controller() {
+        Controller::Pic => pic::unmask(line),
+        Controller::IoApic => apic::unmask_irq(line),
+    }
+}
+
+fn send_eoi(line: u8) {
+    match controller() {
+        Controller::Pic => pic::send_eoi(line),
+        Controller::IoApic => apic::send_eoi(),
+    }
+}
+
+/// Adds `handler` to the handlers of `line` and unmasks it
+pub fn register(line: u8, name: &'static str, handler: IrqHandler) -> Result<(), &'static str> {
+    if line as usize >= IRQ_LINES {
+        return Err("invalid IRQ line");
+    }
+
+    let mut irqs = IRQS.lock();
+    let irq = &mut irqs[line as usize];
+    if irq
+        .actions
+        .iter()
+        .flatten()
+        .any(|action| action.handler as usize == handler as usize)
+    {
+        return Err("handler is already registered for this IRQ");
+    }
+
+    let slot = irq
+        .actions
+        .iter_mut()
+        .find(|action| action.is_none())
+        .ok_or("too many handlers share this IRQ")?;
+    *slot = Some(IrqAction { name, handler });
+    unmask(line);
+    Ok(())
+}
+
+/// Removes `handler` from `line`, the line is masked when no handler is left
+pub fn unregister(line: u8, handler: IrqHandler) -> Result<(), &'static str> {
+    if line as usize >= IRQ_LINES {
+        return Err("invalid IRQ line");
+    }
+
+    let mut irqs = IRQS.lock();
+    let irq = &mut irqs[line as usize];
+    let slot = irq
+        .actions
+        .iter_mut()
+        .find(|action| matches!(action, Some(a) if a.handler as usize == handler as usize))
+        .ok_or("handler isn't registered for this IRQ")?;
+    *slot = None;
+
+    /* Keep the chain in registration order without holes */
+    let mut actions = [None; MAX_SHARED];
+    for (i, action) in irq.actions.iter().flatten().enumerate() {
+        actions[i] = Some(*action);
+    }
+    irq.actions = actions;
+
+    if irq.actions[0].is_none() {
+        mask(line);
+    }
+    Ok(())
+}
+
+fn dispatch(line: u8) {
+    /* The local APIC has its own vector for spurious interrupts */
+    if controller() == Controller::Pic && pic::is_spurious(line) {
+        IRQS.lock()[line as usize].spurious += 1;
+        /* A spurious IRQ 15 still went through the master's cascade line */
+        if line == 15 {
+            pic::send_eoi(0);
+        }
+        return;
+    }
+
+    let actions = {
+        let mut irqs = IRQS.lock();
+        irqs[line as usize].count += 1;
+        irqs[line as usize].actions
+    };
+    let mut handled = false;
+    for action in actions.iter().flatten() {
+        handled |= (action.handler)(line);
+    }
+    if !handled {
+        IRQS.lock()[line as usize].unhandled += 1;
+    }
+    send_eoi(line);
+    /* After the EOI, the task switched to might run for a while */
+    task::preempt();
+}
+
+/// Interrupts seen on `line`
+pub fn count(line: u8) -> u64 {
+    IRQS.lock()[line as usize].count
+}
+
+pub fn dump() {
+    /* Copied out, the lock isn't held while printing */
+    let irqs = *IRQS.lock();
+    for (line, irq) in irqs.iter().enumerate() {
+        if irq.count == 0 && irq.spurious == 0 && irq.actions[0].is_none() {
+            continue;
+        }
+        qemu_println!(
+            "irq {:2}: {:>10} interrupts, {} unhandled, {} spurious",
+            line,
+            irq.count,
+            irq.unhandled,
+            irq.spurious
+        );
+        for action in irq.actions.iter().flatten() {
+            qemu_println!("        {}", action.name);
+        }
+    }
+}
+
+macro_rules! irq_entry {
+    ($name:ident, $line:expr) => {
+        extern "x86-interrupt" fn $name(_isf: InterruptStackFrame) {
+            dispatch($line);
+        }
+    };
+}
+
+irq_entry!(irq0, 0);
+irq_entry!(irq1, 1);
+irq_entry!(irq2, 2);
+irq_entry!(irq3, 3);
+irq_entry!(irq4, 4);
+irq_entry!(irq5, 5);
+irq_entry!(irq6, 6);
+irq_entry!(irq7, 7);
+irq_entry!(irq8, 8);
+irq_entry!(irq9, 9);
+irq_entry!(irq10, 10);
+irq_entry!(irq11, 11);
+irq_entry!(irq12, 12);
+irq_entry!(irq13, 13);
+irq_entry!(irq14, 14);
+irq_entry!(irq15, 15);
+
+const ENTRIES: [HandlerFunc; IRQ_LINES] = [
+    irq0, irq1, irq2, irq3, irq4, irq5, irq6, irq7, irq8, irq9, irq10, irq11, irq12, irq13,
+    irq14, irq15,
+];
+
+/// IDT entry point of `line`
+pub fn entry(line: u8) -> HandlerFunc {
+    ENTRIES[line as usize]
+}
@@ -0,0 +1,204 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::elf::{self, Elf};
+use crate::fat32;
+use crate::mem::memory::{PageFlags, PAGE_SIZE};
+use crate::misc::rand::RNG;
+use crate::process::{self, UserSpace, USER_STACK_PAGES};
+use crate::task::JoinHandle;
+
+/* Runs ELF executables from the FAT32 filesystem. The file is read whole, checked by
+ * elf.rs and copied into a new address space. Each page gets the permissions of the
+ * segments on it: writable only if a segment on it is, executable only if a segment on
+ * it is. Pages are mapped zeroed, so whatever a segment has past its file data (.bss)
+ * is zero. The heap starts on the page after the highest segment.
+ *
+ * The process starts with the stack the System V ABI describes, from rsp upwards:
+ *
+ *   argc
+ *   argv[0] .. argv[argc - 1], 0
+ *   envp[0] .. envp[n - 1], 0
+ *   auxv pairs (type, value), ending with AT_NULL
+ *
+ * with the strings and the AT_RANDOM bytes above it, at the top of the stack. rsp is
+ * 16 byte aligned. */
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_RANDOM: u64 = 25;
+
+const PHDR_SIZE: u64 = 56;
+// the arguments may take at most this much of the stack
+const MAX_ARGS_SIZE: u64 = USER_STACK_PAGES * PAGE_SIZE / 4;
+
+fn page_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_up(addr: u64) -> u64 {
+    page_down(addr + PAGE_SIZE - 1)
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, &'static str> {
+    fat32::with_filesystem(|fs| {
+        let size = fs.get_file_size(path)? as usize;
+        let mut data = vec![0; size];
+        fs.read_file(path, &mut data, size)?;
+        Ok(data)
+    })
+}
+
+// maps the segments of `elf` into `space` and copies their data from `file`
+fn map_segments(space: &mut UserSpace, elf: &Elf, file: &[u8]) -> Result<(), &'static str> {
+    /* (writable, executable) of every page, segments can share pages */
+    let mut pages: BTreeMap<u64, (bool, bool)> = BTreeMap::new();
+    for segment in &elf.segments {
+        let end = segment.vaddr + segment.mem_size;
+        for page in (page_down(segment.vaddr)..page_up(end)).step_by(PAGE_SIZE as usize) {
+            let flags = pages.entry(page).or_insert((false, false));
+            flags.0 |= segment.writable();
+            flags.1 |= segment.executable();
+        }
+    }
+
+    for (&page, &(writable, executable)) in &pages {
+        let mut flags = PageFlags::empty();
+        if writable {
+            flags |= PageFlags::WRITABLE;
+        }
+        if !executable {
+            flags |= PageFlags::NO_EXECUTE;
+        }
+        space.map(page, 1, flags)?;
+    }
+
+    for segment in &elf.segments {
+        let start = segment.offset as usize;
+        space.write(segment.vaddr, &file[start..start + segment.file_size as usize])?;
+    }
+    space.set_heap_start(page_up(elf.end()))
+}
+
+// lays out argc, argv, envp and the auxiliary vector below `top`, returns the stack pointer
+fn setup_stack(
+    space: &mut UserSpace,
+    top: u64,
+    elf: &Elf,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<u64, &'static str> {
+    /* Everything is put together in `image`, which ends at `top`, and written at once.
+     * Strings are pushed to its front while it grows down */
+    let mut image: Vec<u8> = Vec::new();
+    let mut push = |data: &[u8]| -> u64 {
+        image.splice(0..0, data.iter().copied());
+        top - image.len() as u64
+    };
+
+    let mut random = [0u8; 16];
+    {
+        let mut rng = RNG.lock();
+        random[..8].copy_from_slice(&rng.u64().to_le_bytes());
+        random[8..].copy_from_slice(&rng.u64().to_le_bytes());
+    }
+    let random = push(&random);
+
+    let mut push_strings = |strings: &[&str]| -> Vec<u64> {
+        strings
+            .iter()
+            .rev()
+            .map(|string| {
+                push(&[0]);
+                push(string.as_bytes())
+            })
+            .collect::<Vec<u64>>()
+            .into_iter()
+            .rev()
+            .collect()
+    };
+    let envp = push_strings(envp);
+    let argv = push_strings(argv);
+
+    let mut words = vec![argv.len() as u64];
+    words.extend(&argv);
+    words.push(0);
+    words.extend(&envp);
+    words.push(0);
+    if let Some(program_headers) = elf.program_headers {
+        words.extend([AT_PHDR, program_headers]);
+    }
+    words.extend([
+        AT_PHENT,
+        PHDR_SIZE,
+        AT_PHNUM,
+        elf.program_header_count as u64,
+        AT_PAGESZ,
+        PAGE_SIZE,
+        AT_ENTRY,
+        elf.entry,
+        AT_UID,
+        0,
+        AT_EUID,
+        0,
+        AT_GID,
+        0,
+        AT_EGID,
+        0,
+        AT_RANDOM,
+        random,
+        AT_NULL,
+        0,
+    ]);
+
+    let strings_start = top - image.len() as u64;
+    let sp = (strings_start - words.len() as u64 * 8) & !0xF;
+    if top - sp > MAX_ARGS_SIZE {
+        return Err("arguments don't fit on the stack");
+    }
+
+    let mut stack = vec![0u8; (strings_start - sp) as usize];
+    for (i, word) in words.iter().enumerate() {
+        stack[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    stack.extend(image);
+    space.write(sp, &stack)?;
+    Ok(sp)
+}
+
+/// Loads the executable at `path` into a new address space, with `argv` and `envp` on its
+/// stack. Returns the space, the entry point and the stack pointer to start it with
+pub fn load(
+    path: &str,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(UserSpace, u64, u64), &'static str> {
+    let file = read_file(path)?;
+    let elf = elf::parse(&file)?;
+
+    let mut space = UserSpace::new()?;
+    map_segments(&mut space, &elf, &file)?;
+    let top = space.map_stack(USER_STACK_PAGES)?;
+    let sp = setup_stack(&mut space, top, &elf, argv, envp)?;
+    Ok((space, elf.entry, sp))
+}
+
+/// Starts the executable at `path` as a process. Join the handle for its exit code
+pub fn spawn(
+    name: &'static str,
+    path: &str,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<JoinHandle, &'static str> {
+    let (space, entry, sp) = load(path, argv, envp)?;
+    process::spawn(name, space, entry, sp)
+}
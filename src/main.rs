@@ -1,28 +1,43 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(panic_info_message)]
 #![feature(strict_provenance)]
 #![feature(ptr_from_ref)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![allow(unused, unconditional_panic)]
 
 #[macro_use]
 extern crate lazy_static;
+extern crate alloc;
 
+mod acpi;
+mod apic;
 mod audio_system;
 mod bord;
 mod drivers;
+mod elf;
+mod exceptions;
 mod format;
+mod gdt;
 mod graph;
 mod handlers;
-mod heap;
+mod irq;
+mod loader;
 mod math;
 pub mod mem;
 mod misc;
+mod percpu;
 mod pic;
+mod process;
+mod smp;
+mod sync;
+mod syscall;
+mod task;
 mod time;
 mod tooling;
 mod utils;
+mod wait;
 
 use core::arch::asm;
 use core::fmt::Write;
@@ -30,10 +45,8 @@ use core::fmt::Write;
 pub mod input;
 use fat32::test_filesystem;
 use input::key_codes::KeyPressedCodes;
-use input::keyboard::KEYBOARD;
 
 use bord::*;
-use drivers::ide::IDE;
 // use drivers::ac97::AC97;
 use graph::font_writer::FontWriter;
 use graph::surface::Surface;
@@ -59,14 +72,16 @@ use audio_system::audio::{self, note, Notes};
 
 pub const FORMAT_STRING_SIZE: usize = 256;
 
-// "options"
-const use_fs: bool = false;
-const do_graphics_test: bool = true;
+const KERNEL_MAIN_STACK_PAGES: u64 = 16;
 
+#[cfg(not(test))]
 #[no_mangle]
 #[link_section = ".start"]
 pub extern "C" fn _start() -> ! {
+    zero_bss();
     load_idt(&IDTX);
+    /* The timer tick reads the per-CPU data through GS as soon as interrupts are on */
+    percpu::init(0);
     time::init();
     pic::init();
     //let mut audio_processor: AC97 = AC97::new();
@@ -76,32 +91,101 @@ pub extern "C" fn _start() -> ! {
     qemu_fmt_println("{}", format_args!("{}", my_root));
 
     memory::init();
-    pic::init();
+    gdt::init();
+    syscall::init();
 
-    let z = time::Timer::new(1000, &say_hi);
-    z.init();
+    // leave the unprotected boot stack for one with a guard page
+    let main_stack = mem::stack::alloc("kernel main", KERNEL_MAIN_STACK_PAGES).unwrap();
+    unsafe {
+        switch_stack(main_stack.top(), kernel_main);
+    }
+}
 
-    let mut rng = misc::rand::Rng::new();
+extern "C" fn kernel_main() -> ! {
+    #[cfg(feature = "self-test")]
+    sync::self_test();
 
-    unsafe {
-        // callback 0-4
-        KEYBOARD.set_callback0(key_event as fn(i32));
+    match apic::init() {
+        Ok(()) => {
+            time::calibrate();
+            smp::init();
+            #[cfg(feature = "self-test")]
+            smp::self_test().unwrap();
+        }
+        Err(err) => {
+            qemu_println!("apic: {}, staying with the PIC", err);
+            time::calibrate();
+        }
     }
+    time::init_wall_clock();
 
-    let buf: [u8; 10] = [0x10u8; 10];
+    time::after(1000, say_hi);
 
-    let mut buf: [u8; 64] = [0x00u8; 64];
-    if use_fs {
-        let mut ide_processor: IDE = IDE::new();
-        ide_processor.init();
-        let mut fs_processor = fat32::FAT32::new(&mut ide_processor).unwrap();
-        test_filesystem(&mut fs_processor);
+    let mut rng = misc::rand::Rng::new();
+
+    input::keyboard::init();
+    task::init().unwrap();
+    time::start_timer_task().unwrap();
+    #[cfg(feature = "self-test")]
+    {
+        process::self_test().unwrap();
+        syscall::self_test().unwrap();
     }
-    if do_graphics_test {
-        test_graphics_lib();
+
+    task::spawn("keyboard", || loop {
+        key_event(input::keyboard::sleep_until_input());
+    })
+    .unwrap();
+    task::spawn("graphics", test_graphics_lib).unwrap();
+    let fs_test = task::spawn("fs test", || {
+        if let Err(err) = fs_test() {
+            qemu_println!("fs test: {}", err);
+            task::exit(1);
+        }
+    })
+    .unwrap();
+
+    qemu_println!("fs test exited with {}", fs_test.join());
+
+    /* makeimg_*.sh copy the test program from src/test_files/elf onto the image */
+    match loader::spawn("hello", "HELLO.ELF", &["hello"], &[]) {
+        Ok(hello) => qemu_println!("hello exited with {}", hello.join()),
+        Err(err) => qemu_println!("hello: {}", err),
     }
+    task::exit(0);
+}
 
-    loop {}
+fn fs_test() -> Result<(), &'static str> {
+    fat32::mount()?;
+    fat32::with_filesystem(|fs_processor| test_filesystem(fs_processor))
+}
+
+// switches to the stack at `top` and calls `f`, the old stack is abandoned
+unsafe fn switch_stack(top: u64, f: extern "C" fn() -> !) -> ! {
+    asm!(
+        "mov rsp, {top}",
+        "xor rbp, rbp", // ends the stack trace in the panic handler
+        "call {f}",
+        top = in(reg) top,
+        f = in(reg) f,
+        options(noreturn)
+    );
+}
+
+extern "C" {
+    static mut __bss_start: u8;
+    static mut __bss_end: u8;
+}
+
+// the bootloader copies whole tracks from the disk, so .bss is filled with whatever
+// comes after the kernel image instead of zeroes
+#[inline(always)]
+fn zero_bss() {
+    unsafe {
+        let start = core::ptr::addr_of_mut!(__bss_start);
+        let end = core::ptr::addr_of_mut!(__bss_end);
+        core::ptr::write_bytes(start, 0x00, end as usize - start as usize);
+    }
 }
 
 pub fn key_event(key: i32) {
@@ -115,16 +199,40 @@ pub fn key_event(key: i32) {
         qemu_println("C");
     }
     if key == KeyPressedCodes::T as i32 {
+        let now = time::wall_clock();
         qemu_println!(
-            "time since system start: {}.{:03}s",
+            "time since system start: {}.{:03}s, {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
             time::get_millis() / 1000,
-            time::get_millis() % 1000
+            time::get_millis() % 1000,
+            now.year,
+            now.month,
+            now.day,
+            now.hour,
+            now.minute,
+            now.second
         )
     }
     if key == KeyPressedCodes::R as i32 {
         let mut rng = crate::misc::rand::Rng::new();
         qemu_println!("random u32: {:010}", rng.u32())
     }
+    if key == KeyPressedCodes::M as i32 {
+        mem::stats::dump();
+    }
+    if key == KeyPressedCodes::L as i32 {
+        mem::stats::dump_allocations(None);
+    }
+    if key == KeyPressedCodes::K as i32 {
+        let enabled = !mem::stats::call_site_tracking();
+        mem::stats::set_call_site_tracking(enabled);
+        qemu_println!("call site tracking {}", if enabled { "on" } else { "off" });
+    }
+    if key == KeyPressedCodes::P as i32 {
+        task::dump();
+    }
+    if key == KeyPressedCodes::I as i32 {
+        irq::dump();
+    }
 }
 
 pub fn get_rsp() -> *mut u64 {
@@ -144,19 +252,6 @@ pub fn output_rsp() {
     qemu_print("\n");
 }
 
-pub fn wait(t: u64) {
-    let mut i = 0;
-    let mut counter = 0;
-    while (i < t) {
-        counter += 1;
-        if (i % 2 == 0) {
-            i += 1;
-        } else {
-            i += 2;
-        }
-    }
-}
-
 pub fn test_graphics_lib() {
     qemu_println("Test!");
     let mut writer = planar_writer::VgaPlanarWriter::new();
@@ -192,7 +287,7 @@ pub fn test_graphics_lib() {
 
         writer.present(counter);
         counter += 1;
-        wait(100000000);
+        time::sleep(100);
     }
 
     //writer.color_test();
@@ -228,7 +323,7 @@ pub fn test_graphics_mode_12() {
             ",
         );
 
-        let mem_pointer: *mut u8 = 0xA0000 as *mut u8;
+        let mem_pointer: *mut u8 = phys_to_virt(0xA0000) as *mut u8;
         let buffer = core::slice::from_raw_parts_mut(mem_pointer, 38400); // (rows * cols) * (chars + color)
         let scan_line_sz = 80;
         let scan_line_cnt = 480;
@@ -291,7 +386,7 @@ pub fn test_graphics_mode_105_vesa() {
         let page_size = 65536; //64kb
         let scan_line_cnt = 768;
         let scan_line_sz = 1024;
-        let mem_pointer: *mut u8 = 0xA0000 as *mut u8;
+        let mem_pointer: *mut u8 = phys_to_virt(0xA0000) as *mut u8;
         let buffer = core::slice::from_raw_parts_mut(mem_pointer, 786432); // (rows * cols) * (chars + color)
                                                                            /*
                                                                            for i in 0..12 {
@@ -319,45 +414,79 @@ pub fn test_graphics_mode_105_vesa() {
     }
 }
 
+// vectors 0-31 all go through the stubs in exceptions.rs
+fn exception_entry(vector: u8) -> IDTEntry {
+    IDTEntry::from_addr(exceptions::stub(vector), Ring::Zero)
+}
+
+// and the rest through the interrupt stubs, which also handle interrupted user code
+fn irq_entry(line: u8) -> IDTEntry {
+    exceptions::interrupt_entry(irq::IRQ_BASE + line, irq::entry(line))
+}
+
 lazy_static! {
-    static ref IDTX: IDT = IDT {
-        page_fault: IDTEntry::new(handlers::page_fault, Ring::Zero),
-        divide_error: IDTEntry::new(handlers::zero_div, Ring::Zero),
-        debug: IDTEntry::new(handlers::debug, Ring::Zero),
-        non_maskable_interrupt: IDTEntry::new(handlers::non_maskable_interrupt, Ring::Zero),
-        breakpoint: IDTEntry::new(handlers::breakpoint, Ring::Zero),
-        overflow: IDTEntry::new(handlers::overflow, Ring::Zero),
-        bound_range_exceeded: IDTEntry::new(handlers::bound_range_exceeded, Ring::Zero),
-        invalid_opcode: IDTEntry::new(handlers::invalid_opcode, Ring::Zero),
-        device_not_available: IDTEntry::new(handlers::device_not_available, Ring::Zero),
-        double_fault: IDTEntry::new(handlers::double_fault, Ring::Zero),
-        invalid_tss: IDTEntry::new(handlers::invalid_tss, Ring::Zero),
-        segment_not_present: IDTEntry::new(handlers::segment_not_present, Ring::Zero),
-        stack_segment_fault: IDTEntry::new(handlers::stack_segment_fault, Ring::Zero),
-        general_protection_fault: IDTEntry::new(handlers::general_protection_fault, Ring::Zero),
-        x87_floating_point: IDTEntry::new(handlers::x87_floating_point, Ring::Zero),
-        alignment_check: IDTEntry::new(handlers::alignment_check, Ring::Zero),
-        machine_check: IDTEntry::new(handlers::machine_check, Ring::Zero),
-        simd_floating_point: IDTEntry::new(handlers::simd_floating_point, Ring::Zero),
-        virtualization: IDTEntry::new(handlers::virtualization, Ring::Zero),
-        security_exception: IDTEntry::new(handlers::security_exception, Ring::Zero),
-        interrupt1: IDTEntry::new(handler1_wtf, Ring::Zero),
-        interrupt2: IDTEntry::new(keyboard_handler, Ring::Zero),
-        interrupt3: IDTEntry::new(mh3, Ring::Zero),
-        interrupt4: IDTEntry::new(mh4, Ring::Zero),
-        interrupt5: IDTEntry::new(mh5, Ring::Zero),
-        interrupt6: IDTEntry::new(mh6, Ring::Zero),
-        interrupt7: IDTEntry::new(mh7, Ring::Zero),
-        interrupt8: IDTEntry::new(mh8, Ring::Zero),
-        interrupt9: IDTEntry::new(mh9, Ring::Zero),
-        interrupt10: IDTEntry::new(mh10, Ring::Zero),
-        interrupt11: IDTEntry::new(mh11, Ring::Zero),
-        interrupt12: IDTEntry::new(mh12, Ring::Zero),
-        interrupt13: IDTEntry::new(mh13, Ring::Zero),
-        interrupt14: IDTEntry::new(mh14, Ring::Zero),
-        interrupt15: IDTEntry::new(mh15, Ring::Zero),
-        interrupt16: IDTEntry::new(mh16, Ring::Zero),
-        ..Default::default()
+    pub static ref IDTX: IDT = {
+        let mut idt = IDT {
+            divide_error: exception_entry(0),
+            debug: exception_entry(1),
+            non_maskable_interrupt: exception_entry(2).with_ist(gdt::NMI_IST),
+            breakpoint: exception_entry(3),
+            overflow: exception_entry(4),
+            bound_range_exceeded: exception_entry(5),
+            invalid_opcode: exception_entry(6),
+            device_not_available: exception_entry(7),
+            double_fault: exception_entry(8).with_ist(gdt::DOUBLE_FAULT_IST),
+            reserved_9: exception_entry(9),
+            invalid_tss: exception_entry(10),
+            segment_not_present: exception_entry(11),
+            stack_segment_fault: exception_entry(12),
+            general_protection_fault: exception_entry(13),
+            page_fault: exception_entry(14),
+            reserved_15: exception_entry(15),
+            x87_floating_point: exception_entry(16),
+            alignment_check: exception_entry(17),
+            machine_check: exception_entry(18).with_ist(gdt::MACHINE_CHECK_IST),
+            simd_floating_point: exception_entry(19),
+            virtualization: exception_entry(20),
+            control_protection: exception_entry(21),
+            more_reserved: core::array::from_fn(|i| exception_entry(22 + i as u8)),
+            hypervisor_injection: exception_entry(28),
+            vmm_communication: exception_entry(29),
+            security_exception: exception_entry(30),
+            reserved_31: exception_entry(31),
+            interrupt1: irq_entry(0),
+            interrupt2: irq_entry(1),
+            interrupt3: irq_entry(2),
+            interrupt4: irq_entry(3),
+            interrupt5: irq_entry(4),
+            interrupt6: irq_entry(5),
+            interrupt7: irq_entry(6),
+            interrupt8: irq_entry(7),
+            interrupt9: irq_entry(8),
+            interrupt10: irq_entry(9),
+            interrupt11: irq_entry(10),
+            interrupt12: irq_entry(11),
+            interrupt13: irq_entry(12),
+            interrupt14: irq_entry(13),
+            interrupt15: irq_entry(14),
+            interrupt16: irq_entry(15),
+            ..Default::default()
+        };
+        let handlers: [(u8, HandlerFunc); 4] = [
+            (apic::SPURIOUS_VECTOR, apic::spurious_handler),
+            (apic::ERROR_VECTOR, apic::error_handler),
+            (apic::TIMER_VECTOR, time::deadline_interrupt),
+            (smp::WAKEUP_VECTOR, smp::wakeup_handler),
+        ];
+        for (vector, handler) in handlers {
+            idt.set(vector, exceptions::interrupt_entry(vector, handler));
+        }
+        // user code may call `int 0x80`
+        idt.set(
+            syscall::INT_VECTOR,
+            IDTEntry::from_addr(syscall::int_entry(), Ring::Three),
+        );
+        idt
     };
 }
 
@@ -1,53 +1,187 @@
-use crate::mem::memory;
+use crate::mem::buddy::{self, MAX_ORDER};
+use crate::mem::frame::FRAME_SIZE;
+use crate::mem::slab::{self, SlabCache};
+use crate::mem::stats;
 use crate::tooling::qemu_io::{qemu_fmt_println, qemu_print_hex, qemu_println};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
 use core::mem;
 use core::mem::size_of;
 use core::ptr;
 use core::ptr::{addr_of_mut, *};
 // do not allocate over regions referenced by e820
 
-#[repr(C)]
-pub struct Allocator {
-    pub head: *mut u8,
-}
+/// Lets the `alloc` crate (`Vec`, `Box`, `String`, `BTreeMap`...) use the kernel allocator
+pub struct KernelAllocator;
 
-// how to singleton ?????? (with custom bootloader)
-impl Allocator {
-    pub unsafe fn new() -> &'static mut Self {
-        let mut adata = ((1 << 30) + 0x1000000) as *mut Allocator;
-        (*adata).head = (adata as u64 + (size_of::<Self>() as u64)) as *mut u8;
-        &mut *adata
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // blocks are aligned to their own size, so asking for at least `align` bytes
+        // satisfies any alignment
+        kalloc(layout.size().max(layout.align()))
     }
-    pub unsafe fn get() -> &'static mut Self {
-        let mut adata = ((1 << 30) + 0x1000000) as *mut Allocator;
-        &mut *adata
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        kfree(ptr)
     }
 }
 
+#[cfg(not(test))]
+#[global_allocator]
+static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "out of memory: allocation of {} bytes (align {}) failed, {} frames left",
+        layout.size(),
+        layout.align(),
+        buddy::free_frames()
+    );
+}
+
 pub unsafe fn init_alloc() {
-    Allocator::new(); // figure out singletons.
-    alloc_test();
+    buddy::init();
+    #[cfg(feature = "self-test")]
+    {
+        alloc_test();
+        slab_test();
+        global_alloc_test();
+    }
 }
 
+#[cfg(feature = "self-test")]
 fn alloc_test() {
-    unsafe {
-        let ptra = kalloc(512);
-        //qemu_fmt_println("{}", format_args!("{:#x}", ptra as u64));
-        let ptrb = kalloc(512);
-        assert!((ptrb as u64) - (ptra as u64) == 0x200);
-        //qemu_fmt_println("{}", format_args!("{:#x}", ptrb as u64));
-        //qemu_fmt_println("{}", format_args!("{:#x}", (ptrb as u64) - (ptra as u64)));
+    let free_before = buddy::free_frames();
+
+    // small allocations come from the slab caches and don't take a page each
+    let ptra = kalloc(512);
+    let ptrb = kalloc(512);
+    assert!(!ptra.is_null() && !ptrb.is_null());
+    assert!(ptra != ptrb);
+    assert!(slab::is_slab_object(ptra) && ptra as u64 % 512 == 0);
+
+    // a block of 3 pages is rounded up to an order 2 block, aligned to its size
+    let ptrc = kalloc(3 * FRAME_SIZE as usize);
+    assert!(ptrc as u64 % (4 * FRAME_SIZE) == 0);
+
+    kfree(ptra);
+    kfree(ptrb);
+    kfree(ptrc);
+
+    // everything coalesced back once the empty slabs are released
+    slab::shrink_all();
+    assert!(buddy::free_frames() == free_before);
+}
+
+#[cfg(feature = "self-test")]
+static TEST_CACHE: SlabCache = SlabCache::new("test", 24, Some(test_ctor));
+
+#[cfg(feature = "self-test")]
+fn test_ctor(object: *mut u8) {
+    unsafe { ptr::write_bytes(object, 0x5A, 24) }
+}
+
+#[cfg(feature = "self-test")]
+fn slab_test() {
+    let free_before = buddy::free_frames();
+    let mut objects: [*mut u8; 300] = [null_mut(); 300];
+    for object in objects.iter_mut() {
+        *object = TEST_CACHE.alloc();
+        assert!(!object.is_null() && unsafe { **object } == 0x5A);
+    }
+    // 300 objects of 24 bytes span two slabs
+    assert!(TEST_CACHE.stats().in_use == 300 && TEST_CACHE.stats().slabs == 2);
+
+    for object in objects.iter() {
+        slab::free(*object);
     }
+    assert!(TEST_CACHE.stats().in_use == 0);
+    TEST_CACHE.shrink();
+    assert!(TEST_CACHE.stats().slabs == 0);
+    assert!(buddy::free_frames() == free_before);
 }
 
+#[cfg(feature = "self-test")]
+fn global_alloc_test() {
+    let free_before = buddy::free_frames();
+    {
+        let mut v: Vec<u64> = Vec::new();
+        for i in 0..2048 {
+            v.push(i);
+        }
+        assert!(v.iter().sum::<u64>() == 2047 * 2048 / 2);
+
+        let b = Box::new([0xABu8; 64]);
+        assert!(b[63] == 0xAB);
+
+        let mut s = String::from("peepo");
+        s.push_str("64");
+        assert!(s == "peepo64");
+
+        let mut map: BTreeMap<u32, &str> = BTreeMap::new();
+        map.insert(2, "two");
+        map.insert(1, "one");
+        assert!(map.keys().copied().collect::<Vec<u32>>() == [1, 2]);
+    }
+    slab::shrink_all();
+    assert!(buddy::free_frames() == free_before);
+}
+
+/// Number of frames (as an order) needed to fit `size` bytes
+pub fn size_to_order(size: usize) -> usize {
+    let frames = (size + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+    frames.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+/// Allocates at least `size` bytes, null if out of memory. Up to `slab::MAX_SIZE`
+/// bytes are served by the slab caches (aligned to the size class), larger allocations
+/// are page aligned blocks from the buddy allocator
 pub fn kalloc(size: usize) -> *mut u8 {
-    unsafe { kalloc_linear(size) }
+    let ptr = if size <= slab::MAX_SIZE {
+        slab::alloc(size)
+    } else if size_to_order(size) <= MAX_ORDER {
+        buddy::alloc(size_to_order(size))
+    } else {
+        null_mut()
+    };
+
+    if !ptr.is_null() {
+        stats::record_alloc(ptr, size);
+    }
+    ptr
+}
+
+/// Frees memory returned by `kalloc`
+pub fn kfree(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+
+    stats::record_free(ptr);
+    if slab::is_slab_object(ptr) {
+        slab::free(ptr);
+    } else {
+        buddy::free(ptr);
+    }
 }
 
-pub fn kalloc_linear(size: usize) -> *mut u8 {
-    unsafe {
-        let mut allocator: &mut Allocator = Allocator::get();
-        allocator.head = (allocator.head as u64 + size as u64) as *mut u8;
-        allocator.head
+#[cfg(test)]
+mod alloc_tests {
+    use super::*;
+
+    #[test]
+    fn test_size_to_order() {
+        let frame = FRAME_SIZE as usize;
+        assert!(size_to_order(0) == 0 && size_to_order(1) == 0);
+        assert!(size_to_order(frame) == 0);
+        assert!(size_to_order(frame + 1) == 1);
+        assert!(size_to_order(3 * frame) == 2);
+        assert!(size_to_order(frame << MAX_ORDER) == MAX_ORDER);
+        assert!(size_to_order((frame << MAX_ORDER) + 1) == MAX_ORDER + 1);
     }
 }
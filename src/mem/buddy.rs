@@ -1,4 +1,26 @@
+use crate::mem::e820::{self, RegionType};
+use crate::mem::frame::{self, FRAME_ALLOCATOR, FRAME_SIZE};
+use crate::mem::memory::{self, phys_to_virt, PHYS_MAP_BASE};
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+use core::ptr::null_mut;
+
+/* Power-of-two buddy allocator. Takes over every free frame from the frame allocator
+ * that is reachable through the direct map and hands out naturally aligned
+ * blocks of 2^order frames. Free blocks are kept in one doubly linked list per order,
+ * where the list node is stored in the first bytes of the free block itself. */
+
+/* Largest block is 2^MAX_ORDER frames (4 MiB) */
+pub const MAX_ORDER: usize = 10;
+
+/* `frame_info` encoding, one byte per frame: the order of the block starting at the
+ * frame, with USED_BIT set if it is allocated. Frames that aren't the first frame of
+ * a block are marked NOT_HEAD */
+const USED_BIT: u8 = 0x80;
+const NOT_HEAD: u8 = 0xFF;
+
 #[repr(u8)]
+#[derive(PartialEq)]
 enum Flag {
     Free,
     Used,
@@ -11,6 +33,384 @@ struct ChunkMetaData {
     pub prev: *mut ChunkMetaData,
 }
 
-pub fn free(ptr: *mut u8) {}
+pub struct BuddyAllocator {
+    free_lists: [*mut ChunkMetaData; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+    /* Where physical address 0 is mapped, the free list nodes are written through it */
+    base: u64,
+    frame_info: *mut u8,
+    /* Number of frames covered by `frame_info`, starting at physical address 0 */
+    frames: usize,
+    ready: bool,
+}
+
+/* The pointers are into the direct map, valid on every CPU */
+unsafe impl Send for BuddyAllocator {}
+
+static BUDDY: IrqSpinlock<BuddyAllocator> = IrqSpinlock::new(BuddyAllocator {
+    free_lists: [null_mut(); MAX_ORDER + 1],
+    free_blocks: [0; MAX_ORDER + 1],
+    base: PHYS_MAP_BASE,
+    frame_info: null_mut(),
+    frames: 0,
+    ready: false,
+});
+
+#[inline]
+fn window(phys: u64) -> *mut u8 {
+    phys_to_virt(phys) as *mut u8
+}
+
+impl BuddyAllocator {
+    fn node(&self, frame: usize) -> *mut ChunkMetaData {
+        (self.base + frame as u64 * FRAME_SIZE) as *mut ChunkMetaData
+    }
+
+    /// Adds the frames `[first, last)` as free blocks, as large as their alignment allows
+    fn add_region(&mut self, first: usize, last: usize) {
+        let mut frame = first;
+        while frame < last {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > last {
+                order -= 1;
+            }
+            self.push(order, frame);
+            frame += 1 << order;
+        }
+    }
+
+    fn push(&mut self, order: usize, frame: usize) {
+        let node = self.node(frame);
+        let head = self.free_lists[order];
+        unsafe {
+            *node = ChunkMetaData {
+                chunk_flag: Flag::Free,
+                order: order as i16,
+                next: head,
+                prev: null_mut(),
+            };
+            if !head.is_null() {
+                (*head).prev = node;
+            }
+            *self.frame_info.add(frame) = order as u8;
+        }
+        self.free_lists[order] = node;
+        self.free_blocks[order] += 1;
+    }
+
+    fn unlink(&mut self, order: usize, frame: usize) {
+        let node = self.node(frame);
+        unsafe {
+            if (*node).chunk_flag != Flag::Free || (*node).order != order as i16 {
+                panic!("buddy: corrupted free block at frame {:#x}", frame);
+            }
+
+            if (*node).prev.is_null() {
+                self.free_lists[order] = (*node).next;
+            } else {
+                (*(*node).prev).next = (*node).next;
+            }
+            if !(*node).next.is_null() {
+                (*(*node).next).prev = (*node).prev;
+            }
+            (*node).chunk_flag = Flag::Used;
+        }
+        self.free_blocks[order] -= 1;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let node = self.free_lists[order];
+        if node.is_null() {
+            return None;
+        }
+
+        let frame = ((node as u64 - self.base) / FRAME_SIZE) as usize;
+        self.unlink(order, frame);
+        Some(frame)
+    }
+
+    /// Returns the physical address of a block of 2^`order` frames
+    pub fn alloc(&mut self, order: usize) -> Option<u64> {
+        if !self.ready || order > MAX_ORDER {
+            return None;
+        }
+
+        /* Find the smallest free block that fits */
+        let mut current = order;
+        while self.free_lists[current].is_null() {
+            current += 1;
+            if current > MAX_ORDER {
+                return None;
+            }
+        }
+
+        let frame = self.pop(current).unwrap();
+
+        /* Split it, giving back the upper halves, until it has the requested size */
+        while current > order {
+            current -= 1;
+            self.push(current, frame + (1 << current));
+        }
+
+        unsafe {
+            *self.frame_info.add(frame) = order as u8 | USED_BIT;
+        }
+        Some(frame as u64 * FRAME_SIZE)
+    }
+
+    pub fn free(&mut self, addr: u64) {
+        let mut frame = (addr / FRAME_SIZE) as usize;
+        if addr % FRAME_SIZE != 0 || frame >= self.frames {
+            panic!("buddy: tried to free an invalid block {:#x}", addr);
+        }
+
+        let info = unsafe { *self.frame_info.add(frame) };
+        if info == NOT_HEAD || info & USED_BIT == 0 {
+            panic!("buddy: double free or free of a non-block {:#x}", addr);
+        }
+
+        /* Merge with the buddy as long as it is free and of the same size */
+        let mut order = (info & !USED_BIT) as usize;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy + (1 << order) > self.frames
+                || unsafe { *self.frame_info.add(buddy) } != order as u8
+            {
+                break;
+            }
+
+            self.unlink(order, buddy);
+            unsafe {
+                /* The upper half stops being the start of a block */
+                *self.frame_info.add(frame.max(buddy)) = NOT_HEAD;
+            }
+            frame = frame.min(buddy);
+            order += 1;
+        }
+
+        self.push(order, frame);
+    }
+
+    /// Returns the order of the allocated block at `addr`, if there is one
+    pub fn allocated_order(&self, addr: u64) -> Option<usize> {
+        let frame = (addr / FRAME_SIZE) as usize;
+        if !self.ready || addr % FRAME_SIZE != 0 || frame >= self.frames {
+            return None;
+        }
+
+        let info = unsafe { *self.frame_info.add(frame) };
+        if info == NOT_HEAD || info & USED_BIT == 0 {
+            return None;
+        }
+        Some((info & !USED_BIT) as usize)
+    }
+
+    pub fn manages(&self, addr: u64) -> bool {
+        self.ready && ((addr / FRAME_SIZE) as usize) < self.frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        let mut total = 0;
+        for order in 0..=MAX_ORDER {
+            total += self.free_blocks[order] << order;
+        }
+        total
+    }
+
+    pub fn dump(&self) {
+        qemu_println!("buddy: {} free frames", self.free_frames());
+        for order in 0..=MAX_ORDER {
+            qemu_println!(
+                "  order {:2} ({:5} KiB): {} free",
+                order,
+                (FRAME_SIZE << order) / 1024,
+                self.free_blocks[order]
+            );
+        }
+    }
+}
+
+/// Moves all free frames inside the direct map from the frame allocator
+/// into the buddy allocator. From then on `frame::alloc_frame` is served from here
+pub fn init() {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let mut buddy = BUDDY.lock();
+    unsafe {
+        let mut highest: u64 = 0;
+        for entry in e820::entries() {
+            if entry.region_type() == RegionType::Usable {
+                highest = highest.max(entry.end());
+            }
+        }
+        let frames = (highest
+            .min(frame::MAX_PHYS_ADDR)
+            .min(memory::phys_map_size())
+            / FRAME_SIZE) as usize;
 
-pub fn alloc() {}
+        /* The per frame info lives in frames taken from the frame allocator */
+        let info_frames = (frames + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+        let info_addr = frame_allocator
+            .alloc_contiguous(info_frames)
+            .expect("buddy: no memory for the frame info");
+        buddy.frame_info = window(info_addr);
+        buddy.frames = frames;
+        core::ptr::write_bytes(buddy.frame_info, NOT_HEAD, frames);
+
+        /* Claim every run of free frames */
+        let mut frame = 0;
+        while frame < frames {
+            if !frame_allocator.is_free(frame as u64 * FRAME_SIZE) {
+                frame += 1;
+                continue;
+            }
+
+            let first = frame;
+            while frame < frames && frame_allocator.is_free(frame as u64 * FRAME_SIZE) {
+                frame += 1;
+            }
+
+            frame_allocator.reserve_range(first as u64 * FRAME_SIZE, frame as u64 * FRAME_SIZE);
+            buddy.add_region(first, frame);
+        }
+
+        buddy.ready = true;
+        buddy.dump();
+    }
+}
+
+#[inline]
+pub fn is_ready() -> bool {
+    BUDDY.lock().ready
+}
+
+/// Whether the frame at `addr` belongs to the buddy allocator
+pub fn manages(addr: u64) -> bool {
+    BUDDY.lock().manages(addr)
+}
+
+pub fn free_frames() -> usize {
+    BUDDY.lock().free_frames()
+}
+
+/// Physical address of 2^`order` contiguous frames
+pub fn alloc_pages(order: usize) -> Option<u64> {
+    BUDDY.lock().alloc(order)
+}
+
+pub fn free_pages(addr: u64) {
+    BUDDY.lock().free(addr)
+}
+
+/// Allocates 2^`order` frames and returns them through the direct map, null if out
+/// of memory
+pub fn alloc(order: usize) -> *mut u8 {
+    match alloc_pages(order) {
+        Some(addr) => window(addr),
+        None => null_mut(),
+    }
+}
+
+/// Frees a block returned by `alloc`
+pub fn free(ptr: *mut u8) {
+    free_pages(ptr as u64 - PHYS_MAP_BASE)
+}
+
+#[cfg(test)]
+mod buddy_tests {
+    use super::*;
+
+    const FRAMES: usize = 16;
+
+    /* Host memory standing in for the direct map of the first FRAMES frames */
+    struct Memory {
+        buddy: BuddyAllocator,
+        _pages: Vec<u8>,
+        _info: Vec<u8>,
+    }
+
+    // a buddy allocator that got the frames `[first, last)`
+    fn memory(first: usize, last: usize) -> Memory {
+        let mut pages = vec![0u8; (FRAMES + 1) * FRAME_SIZE as usize];
+        let mut info = vec![NOT_HEAD; FRAMES];
+        let base = (pages.as_mut_ptr() as u64 + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        let mut buddy = BuddyAllocator {
+            free_lists: [null_mut(); MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            base,
+            frame_info: info.as_mut_ptr(),
+            frames: FRAMES,
+            ready: true,
+        };
+        buddy.add_region(first, last);
+        Memory {
+            buddy,
+            _pages: pages,
+            _info: info,
+        }
+    }
+
+    fn frame(n: u64) -> u64 {
+        n * FRAME_SIZE
+    }
+
+    #[test]
+    fn test_region_is_split_by_alignment() {
+        let memory = memory(3, FRAMES);
+        let buddy = &memory.buddy;
+        assert!(buddy.free_blocks[0] == 1 && buddy.free_blocks[1] == 0);
+        assert!(buddy.free_blocks[2] == 1 && buddy.free_blocks[3] == 1);
+        assert!(buddy.free_frames() == FRAMES - 3);
+    }
+
+    #[test]
+    fn test_alloc_splits_and_free_merges() {
+        let mut memory = memory(0, FRAMES);
+        let buddy = &mut memory.buddy;
+        assert!(buddy.free_blocks[4] == 1);
+
+        // the order 4 block is halved down to a single frame, the upper halves stay free
+        assert!(buddy.alloc(0) == Some(frame(0)));
+        assert!(buddy.free_blocks[..5] == [1, 1, 1, 1, 0]);
+        assert!(buddy.alloc(1) == Some(frame(2)));
+        assert!(buddy.allocated_order(frame(2)) == Some(1));
+        assert!(buddy.allocated_order(frame(3)).is_none());
+
+        // frame 0 merges with frame 1 only, its order 1 buddy is still allocated
+        buddy.free(frame(0));
+        assert!(buddy.free_blocks[..5] == [0, 1, 1, 1, 0]);
+        buddy.free(frame(2));
+        assert!(buddy.free_blocks[..5] == [0, 0, 0, 0, 1]);
+        assert!(buddy.free_frames() == FRAMES);
+    }
+
+    #[test]
+    fn test_blocks_are_aligned_to_their_size() {
+        let mut memory = memory(0, FRAMES);
+        let buddy = &mut memory.buddy;
+        assert!(buddy.alloc(0) == Some(frame(0)));
+        assert!(buddy.alloc(2) == Some(frame(4)));
+        assert!(buddy.alloc(3) == Some(frame(8)));
+        assert!(buddy.alloc(1) == Some(frame(2)));
+        assert!(buddy.alloc(0) == Some(frame(1)));
+        assert!(buddy.alloc(0).is_none() && buddy.free_frames() == 0);
+    }
+
+    #[test]
+    fn test_too_large_requests_fail() {
+        let mut memory = memory(0, FRAMES);
+        let buddy = &mut memory.buddy;
+        assert!(buddy.alloc(5).is_none());
+        assert!(buddy.alloc(MAX_ORDER + 1).is_none());
+        assert!(buddy.free_frames() == FRAMES);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_double_free_panics() {
+        let mut memory = memory(0, FRAMES);
+        let buddy = &mut memory.buddy;
+        let addr = buddy.alloc(0).unwrap();
+        buddy.free(addr);
+        buddy.free(addr);
+    }
+}
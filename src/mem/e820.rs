@@ -1,11 +1,34 @@
-/* This struct is not being used, but take it as a reference for development */
+use crate::mem::memory::phys_to_virt;
+
+/* The memory map is placed by the MBR (see `get_e820_memory_map` in bootloader/mbr.s)
+ * as a u32 entry count at 0x7e00, followed by the 24 byte entries at 0x7e04 */
+pub const E820_MAP_BASE: u64 = 0x7e00;
+pub const E820_ENTRIES_BASE: u64 = E820_MAP_BASE + 4;
+/* The MBR stops there, smp.rs copies the AP trampoline to 0x8000 (E820_MAX_ENTRIES in
+ * bootloader/asm_include/defines.s) */
+pub const E820_MAX_ENTRIES: usize = 21;
+
 #[repr(u32)]
-enum RegionType {
-    USABLE = 0x00,
-    RESERVED = 0x01,
-    ACPI_RECLAIMABLE = 0x02,
-    ACPI_NVS = 0x03,
-    BAD_MEM = 0x04,
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RegionType {
+    Usable = 0x01,
+    Reserved = 0x02,
+    AcpiReclaimable = 0x03,
+    AcpiNvs = 0x04,
+    BadMem = 0x05,
+}
+
+impl RegionType {
+    /// Types not described by the spec are treated as reserved
+    pub fn from_u32(region_type: u32) -> Self {
+        match region_type {
+            0x01 => RegionType::Usable,
+            0x03 => RegionType::AcpiReclaimable,
+            0x04 => RegionType::AcpiNvs,
+            0x05 => RegionType::BadMem,
+            _ => RegionType::Reserved,
+        }
+    }
 }
 
 #[repr(C, packed)]
@@ -17,10 +40,36 @@ pub struct E820 {
     acpi_ext: u32, /* Doesn't always exist */
 }
 
-fn how_to_use() {
-    let entries_num: u32 = *(0x7e00 as *const u32);
-    let entries = 0x7e00 as *const E820;
+impl E820 {
+    #[inline]
+    pub fn base(&self) -> u64 {
+        self.addr_base
+    }
+
+    #[inline]
+    pub fn length(&self) -> u64 {
+        self.addr_length
+    }
+
+    /// First address after the region
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.addr_base.saturating_add(self.addr_length)
+    }
+
+    #[inline]
+    pub fn region_type(&self) -> RegionType {
+        RegionType::from_u32(self.region_type)
+    }
+}
 
-    /* Hopefully so */
-    entries.offset(0);
+/// Returns the memory map left behind by the bootloader
+pub fn entries() -> &'static [E820] {
+    unsafe {
+        let entries_num: u32 = *(phys_to_virt(E820_MAP_BASE) as *const u32);
+        core::slice::from_raw_parts(
+            phys_to_virt(E820_ENTRIES_BASE) as *const E820,
+            (entries_num as usize).min(E820_MAX_ENTRIES),
+        )
+    }
 }
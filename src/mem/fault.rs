@@ -0,0 +1,214 @@
+use crate::mem::memory::{get_cr3, AddrSpace, PHYS_MAP_BASE, PTE};
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+use core::fmt;
+
+/* Page fault handling. `handlers::page_fault` turns the CR2 value and the error code
+ * pushed by the CPU into a `PageFault` and offers it to the handler registered for the
+ * faulting address range. Handlers resolve faults (demand paging, stack growth,
+ * copy-on-write...) by fixing the mapping and returning true, the faulting instruction
+ * is then retried. Faults nobody resolves end in a full diagnostic and a panic. */
+
+const MAX_FAULT_REGIONS: usize = 16;
+
+/// Returns true if the fault was resolved and the access can be retried
+pub type FaultHandler = fn(&PageFault) -> bool;
+
+#[derive(Copy, Clone)]
+struct FaultRegion {
+    start: u64,
+    end: u64,
+    name: &'static str,
+    handler: FaultHandler,
+}
+
+/* Not held while a handler runs, handlers can take faults of their own */
+static FAULT_REGIONS: IrqSpinlock<[Option<FaultRegion>; MAX_FAULT_REGIONS]> =
+    IrqSpinlock::new([None; MAX_FAULT_REGIONS]);
+
+pub struct PageFault {
+    /// Address that was accessed (CR2)
+    pub addr: u64,
+    pub error_code: u64,
+    /// Instruction that caused the fault
+    pub ip: u64,
+    pub sp: u64,
+}
+
+impl PageFault {
+    const PRESENT: u64 = 1 << 0;
+    const WRITE: u64 = 1 << 1;
+    const USER: u64 = 1 << 2;
+    const RESERVED_WRITE: u64 = 1 << 3;
+    const INSTRUCTION_FETCH: u64 = 1 << 4;
+    const PROTECTION_KEY: u64 = 1 << 5;
+    const SHADOW_STACK: u64 = 1 << 6;
+
+    /// The page was present, so this is a protection violation
+    pub fn present(&self) -> bool {
+        self.error_code & Self::PRESENT != 0
+    }
+
+    pub fn write(&self) -> bool {
+        self.error_code & Self::WRITE != 0
+    }
+
+    /// The access was made in ring 3
+    pub fn user(&self) -> bool {
+        self.error_code & Self::USER != 0
+    }
+
+    /// A reserved bit was set in one of the paging structures
+    pub fn reserved_bit(&self) -> bool {
+        self.error_code & Self::RESERVED_WRITE != 0
+    }
+
+    pub fn instruction_fetch(&self) -> bool {
+        self.error_code & Self::INSTRUCTION_FETCH != 0
+    }
+
+    pub fn protection_key(&self) -> bool {
+        self.error_code & Self::PROTECTION_KEY != 0
+    }
+
+    pub fn shadow_stack(&self) -> bool {
+        self.error_code & Self::SHADOW_STACK != 0
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.instruction_fetch() {
+            "instruction fetch"
+        } else if self.write() {
+            "write"
+        } else {
+            "read"
+        };
+        write!(
+            f,
+            "page fault: {} of {:#x} ({} page, {} mode) at ip {:#x}, error code {:#x}",
+            access,
+            self.addr,
+            if self.present() { "protected" } else { "not present" },
+            if self.user() { "user" } else { "kernel" },
+            self.ip,
+            self.error_code
+        )?;
+        if self.reserved_bit() {
+            write!(f, ", reserved bit set")?;
+        }
+        if self.protection_key() {
+            write!(f, ", protection key")?;
+        }
+        if self.shadow_stack() {
+            write!(f, ", shadow stack")?;
+        }
+        Ok(())
+    }
+}
+
+/// Makes `handler` responsible for faults in `[start, end)`. Ranges can't overlap
+pub fn register_handler(
+    start: u64,
+    end: u64,
+    name: &'static str,
+    handler: FaultHandler,
+) -> Result<(), &'static str> {
+    if start >= end {
+        return Err("empty fault region");
+    }
+
+    let mut regions = FAULT_REGIONS.lock();
+    for region in regions.iter().flatten() {
+        if start < region.end && region.start < end {
+            return Err("fault region overlaps with an existing one");
+        }
+    }
+
+    for slot in regions.iter_mut() {
+        if slot.is_none() {
+            *slot = Some(FaultRegion {
+                start,
+                end,
+                name,
+                handler,
+            });
+            return Ok(());
+        }
+    }
+    Err("no free fault region slots")
+}
+
+/// Removes the handler of the region starting at `start`
+pub fn unregister_handler(start: u64) -> Result<(), &'static str> {
+    for slot in FAULT_REGIONS.lock().iter_mut() {
+        if matches!(slot, Some(region) if region.start == start) {
+            *slot = None;
+            return Ok(());
+        }
+    }
+    Err("no fault region starts at this address")
+}
+
+fn region_of(addr: u64) -> Option<FaultRegion> {
+    FAULT_REGIONS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|region| region.start <= addr && addr < region.end)
+        .copied()
+}
+
+/// Offers the fault to the handler of its region, returns true if it was resolved
+pub fn handle(fault: &PageFault) -> bool {
+    let region = region_of(fault.addr);
+
+    match region {
+        /* A reserved bit is a corrupted page table, nothing a handler should paper over */
+        Some(region) if !fault.reserved_bit() => (region.handler)(fault),
+        _ => false,
+    }
+}
+
+/// Prints the fault and the page table entries used to translate the address
+pub fn report(fault: &PageFault) {
+    qemu_println!("{}", fault);
+    qemu_println!("  stack pointer: {:#x}", fault.sp);
+
+    if let Some(region) = region_of(fault.addr) {
+        qemu_println!(
+            "  in region {} [{:#x} - {:#x}]",
+            region.name,
+            region.start,
+            region.end
+        );
+    }
+
+    unsafe {
+        let aspace = AddrSpace {
+            phys_base: PHYS_MAP_BASE,
+            pml4: get_cr3(),
+        };
+        let trace = aspace.translate_trace(fault.addr);
+        for (level, name) in [(3, "PML4E"), (2, "PDPTE"), (1, "PDE"), (0, "PTE")] {
+            let pte: *const PTE = trace[level];
+            if pte.is_null() {
+                qemu_println!("  {:5}: -", name);
+                continue;
+            }
+
+            let pte = &*pte;
+            qemu_println!(
+                "  {:5}: {:#018x} present={} rw={} user={} nx={} huge={}",
+                name,
+                pte.value(),
+                pte.page_present(),
+                pte.page_rw(),
+                pte.page_user(),
+                pte.page_nx(),
+                level > 0 && level < 3 && pte.page_pse()
+            );
+        }
+    }
+}
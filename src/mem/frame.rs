@@ -0,0 +1,222 @@
+use crate::mem::buddy;
+use crate::mem::e820::{self, RegionType};
+use crate::mem::memory;
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+
+/* Physical frame allocator. Builds a bitmap of the physical memory from the E820 map
+ * left by the bootloader, where every bit represents one 4 KiB frame. Frames are only
+ * handed out from regions the BIOS reported as usable and that aren't occupied by
+ * the kernel image or the structures the bootloader left behind. */
+
+pub const FRAME_SIZE: u64 = 0x1000;
+
+/* Physical memory above this address is ignored (the bitmap would get too large) */
+pub const MAX_PHYS_ADDR: u64 = 1 << 32;
+const MAX_FRAMES: usize = (MAX_PHYS_ADDR / FRAME_SIZE) as usize;
+const BITMAP_WORDS: usize = MAX_FRAMES / 64;
+
+/* Lower memory: IVT, BIOS data, bootloaders, E820 map and the boot page tables at 0x70000 */
+const LOW_MEMORY_END: u64 = 0x100000;
+/* Where bootloader/mbr.s copies the kernel image to (see KERNEL_LOAD_BASE in defines.s) */
+const KERNEL_LOAD_BASE: u64 = 0x100000;
+/* Kernel page tables created by `memory::init` (0x200000..) and the boot stack (grows from
+ * 0x400000) */
+const BOOT_PAGING_START: u64 = 0x200000;
+const BOOT_STACK_TOP: u64 = 0x400000;
+
+extern "C" {
+    /* Defined in linkscript.ld */
+    static _kernel_end: u8;
+}
+
+pub struct FrameAllocator {
+    /* A set bit means that the frame is used or not backed by usable RAM */
+    bitmap: [u64; BITMAP_WORDS],
+    /* Word in the bitmap where the next search for a free frame starts */
+    next_word: usize,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+pub static FRAME_ALLOCATOR: IrqSpinlock<FrameAllocator> = IrqSpinlock::new(FrameAllocator::empty());
+
+impl FrameAllocator {
+    /* Zeroed so that the bitmap ends up in .bss instead of the kernel image */
+    const fn empty() -> Self {
+        Self {
+            bitmap: [0; BITMAP_WORDS],
+            next_word: 0,
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Marks every frame touched by `[start, end)` as used
+    pub fn reserve_range(&mut self, start: u64, end: u64) {
+        let first = start / FRAME_SIZE;
+        let last = (end.min(MAX_PHYS_ADDR) + FRAME_SIZE - 1) / FRAME_SIZE;
+        for frame in first..last {
+            self.set_used(frame as usize);
+        }
+    }
+
+    /// Marks every frame fully contained in `[start, end)` as free
+    pub fn release_range(&mut self, start: u64, end: u64) {
+        let first = (start + FRAME_SIZE - 1) / FRAME_SIZE;
+        let last = end.min(MAX_PHYS_ADDR) / FRAME_SIZE;
+        for frame in first..last {
+            self.set_free(frame as usize);
+        }
+    }
+
+    /// Returns the physical address of a free frame. The content of the frame is
+    /// undefined
+    pub fn alloc(&mut self) -> Option<u64> {
+        for i in 0..BITMAP_WORDS {
+            let word = (self.next_word + i) % BITMAP_WORDS;
+            if self.bitmap[word] == u64::MAX {
+                continue;
+            }
+
+            let frame = word * 64 + self.bitmap[word].trailing_ones() as usize;
+            self.set_used(frame);
+            self.next_word = word;
+            return Some(frame as u64 * FRAME_SIZE);
+        }
+        None
+    }
+
+    /// Returns the physical address of `count` contiguous free frames
+    pub fn alloc_contiguous(&mut self, count: usize) -> Option<u64> {
+        let mut run_start: usize = 0;
+        let mut run_len: usize = 0;
+        for frame in 0..MAX_FRAMES {
+            if self.is_used(frame) {
+                run_len = 0;
+                continue;
+            }
+
+            if run_len == 0 {
+                run_start = frame;
+            }
+            run_len += 1;
+
+            if run_len == count {
+                for used in run_start..run_start + count {
+                    self.set_used(used);
+                }
+                return Some(run_start as u64 * FRAME_SIZE);
+            }
+        }
+        None
+    }
+
+    pub fn free(&mut self, addr: u64) {
+        if addr % FRAME_SIZE != 0 || addr >= MAX_PHYS_ADDR {
+            panic!("tried to free an invalid frame {:#x}", addr);
+        }
+
+        let frame = (addr / FRAME_SIZE) as usize;
+        if !self.is_used(frame) {
+            panic!("double free of frame {:#x}", addr);
+        }
+
+        self.set_free(frame);
+        /* Keep handing out low frames first */
+        self.next_word = self.next_word.min(frame / 64);
+    }
+
+    pub fn is_free(&self, addr: u64) -> bool {
+        addr < MAX_PHYS_ADDR && !self.is_used((addr / FRAME_SIZE) as usize)
+    }
+
+    #[inline]
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    #[inline]
+    fn set_used(&mut self, frame: usize) {
+        if !self.is_used(frame) {
+            self.bitmap[frame / 64] |= 1 << (frame % 64);
+            self.free_frames -= 1;
+        }
+    }
+
+    #[inline]
+    fn set_free(&mut self, frame: usize) {
+        if self.is_used(frame) {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+            self.free_frames += 1;
+        }
+    }
+}
+
+pub fn init() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.bitmap.fill(u64::MAX);
+    allocator.next_word = 0;
+    allocator.free_frames = 0;
+
+    for entry in e820::entries() {
+        qemu_println!(
+            "e820: [{:#012x} - {:#012x}] {:?}",
+            entry.base(),
+            entry.end(),
+            entry.region_type()
+        );
+        if entry.region_type() == RegionType::Usable {
+            allocator.release_range(entry.base(), entry.end());
+        }
+    }
+
+    /* BIOSes may report overlapping entries, the reserved ones win */
+    for entry in e820::entries() {
+        if entry.region_type() != RegionType::Usable {
+            allocator.reserve_range(entry.base(), entry.end());
+        }
+    }
+
+    /* The kernel is linked in the higher half */
+    let kernel_end = memory::virt_to_phys(unsafe { core::ptr::addr_of!(_kernel_end) } as u64).unwrap();
+    allocator.reserve_range(0, LOW_MEMORY_END);
+    allocator.reserve_range(KERNEL_LOAD_BASE, kernel_end);
+    allocator.reserve_range(BOOT_PAGING_START, BOOT_STACK_TOP);
+
+    allocator.total_frames = allocator.free_frames;
+    qemu_println!(
+        "frame allocator: {} free frames ({} KiB), kernel ends at {:#x}",
+        allocator.free_frames,
+        allocator.free_frames as u64 * FRAME_SIZE / 1024,
+        kernel_end
+    );
+}
+
+/* Once the buddy allocator is initialized it owns every free frame it can reach, the
+ * bitmap only keeps the memory above the direct map */
+pub fn alloc_frame() -> Option<u64> {
+    if buddy::is_ready() {
+        if let Some(addr) = buddy::alloc_pages(0) {
+            return Some(addr);
+        }
+    }
+    FRAME_ALLOCATOR.lock().alloc()
+}
+
+pub fn free_frame(addr: u64) {
+    if buddy::manages(addr) {
+        buddy::free_pages(addr);
+    } else {
+        FRAME_ALLOCATOR.lock().free(addr);
+    }
+}
+
+pub fn free_frames() -> usize {
+    let free = FRAME_ALLOCATOR.lock().free_frames;
+    free + buddy::free_frames()
+}
+
+pub fn total_frames() -> usize {
+    FRAME_ALLOCATOR.lock().total_frames
+}
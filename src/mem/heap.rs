@@ -0,0 +1,175 @@
+use crate::mem::fault::{self, PageFault};
+use crate::mem::frame::{self, FRAME_SIZE};
+use crate::mem::memory::{phys_to_virt, PageFlags, PAGE_SIZE, PT};
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+
+/* Demand paged kernel heap. A large virtual range is set aside for the kernel, parts of
+ * it are handed out by `reserve` without any physical memory behind them. The first
+ * access to a page takes a page fault and `heap_fault` backs the page with a zeroed
+ * frame. This makes it cheap to set aside big buffers (framebuffers, FAT caches...) of
+ * which only a part gets used. The reserved part can grow up to a configurable limit. */
+
+/* PML4 entry 384, far away from the direct map and the kernel image */
+pub const HEAP_BASE: u64 = 0xFFFF_C000_0000_0000;
+/* All of the PML4 entry */
+pub const HEAP_REGION_SIZE: u64 = 512 << 30;
+pub const DEFAULT_HEAP_LIMIT: u64 = 1 << 30;
+
+struct Heap {
+    /* Bytes reserved from HEAP_BASE, always page aligned */
+    brk: u64,
+    limit: u64,
+    backed_pages: u64,
+}
+
+/* Also taken by `heap_fault`, which runs with whatever locks the faulting code holds,
+ * so nothing that touches the heap may run under it */
+static HEAP: IrqSpinlock<Heap> = IrqSpinlock::new(Heap {
+    brk: 0,
+    limit: DEFAULT_HEAP_LIMIT,
+    backed_pages: 0,
+});
+
+fn page_flags() -> PageFlags {
+    PageFlags::WRITABLE | PageFlags::NO_EXECUTE | PageFlags::GLOBAL
+}
+
+/// Backs the page at `fault.addr` if it has been reserved and isn't mapped yet
+fn heap_fault(fault: &PageFault) -> bool {
+    let mut heap = HEAP.lock();
+    unsafe {
+        if fault.present() || fault.user() || fault.addr >= HEAP_BASE + heap.brk {
+            return false;
+        }
+
+        let page = fault.addr & !(PAGE_SIZE - 1);
+        let phys = match frame::alloc_frame() {
+            Some(phys) => phys,
+            None => {
+                qemu_println!("heap: no frame left to back {:#x}", page);
+                return false;
+            }
+        };
+        core::ptr::write_bytes(phys_to_virt(phys) as *mut u8, 0, FRAME_SIZE as usize);
+
+        if PT::kernel().map_page(page, phys, page_flags()).is_err() {
+            frame::free_frame(phys);
+            return false;
+        }
+        heap.backed_pages += 1;
+        true
+    }
+}
+
+pub fn init() {
+    fault::register_handler(HEAP_BASE, HEAP_BASE + HEAP_REGION_SIZE, "heap", heap_fault)
+        .unwrap();
+
+    /* Back the first page right away, so the PML4 entry of the heap exists before any
+     * other address space copies the kernel half */
+    let first = reserve(PAGE_SIZE).unwrap();
+    unsafe {
+        *first = 0;
+    }
+    release(first, PAGE_SIZE);
+
+    #[cfg(feature = "self-test")]
+    heap_test();
+    qemu_println!(
+        "heap: {:#x} - {:#x}, limit {} MiB",
+        HEAP_BASE,
+        HEAP_BASE + HEAP_REGION_SIZE,
+        limit() >> 20
+    );
+}
+
+#[cfg(feature = "self-test")]
+fn heap_test() {
+    let backed_before = backed_pages();
+
+    // far more than the buddy allocator could hand out in one block
+    let size: u64 = 64 << 20;
+    let buffer = reserve(size).unwrap();
+    assert!(backed_pages() == backed_before);
+
+    unsafe {
+        *buffer = 1;
+        *buffer.add(size as usize / 2) = 2;
+        *buffer.add(size as usize - 1) = 3;
+        assert!(*buffer.add(1) == 0 && *buffer.add(size as usize - 1) == 3);
+    }
+    assert!(backed_pages() == backed_before + 3);
+
+    // the page tables created on the way stay, the three frames come back
+    let free_before = frame::free_frames();
+    release(buffer, size);
+    assert!(backed_pages() == backed_before);
+    assert!(frame::free_frames() == free_before + 3);
+}
+
+/// Sets aside `size` bytes (rounded up to whole pages) of the heap. Nothing is backed
+/// until it is touched
+pub fn reserve(size: u64) -> Result<*mut u8, &'static str> {
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let mut heap = HEAP.lock();
+    if size == 0 || heap.brk + size > heap.limit {
+        return Err("heap limit reached");
+    }
+
+    let start = HEAP_BASE + heap.brk;
+    heap.brk += size;
+    Ok(start as *mut u8)
+}
+
+/// Unmaps the pages of a reserved range and frees the frames behind them. If the range
+/// is at the end of the heap the heap shrinks, otherwise the virtual range stays unused
+pub fn release(ptr: *mut u8, size: u64) {
+    let start = ptr as u64;
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let mut heap = HEAP.lock();
+    if start % PAGE_SIZE != 0 || start < HEAP_BASE || start + size > HEAP_BASE + heap.brk {
+        panic!("heap: tried to release an invalid range {:#x} (+{:#x})", start, size);
+    }
+
+    let pml4 = unsafe { PT::kernel() };
+    for page in (start..start + size).step_by(PAGE_SIZE as usize) {
+        if let Some(phys) = pml4.translate(page) {
+            pml4.unmap(page, 1).unwrap();
+            frame::free_frame(phys);
+            heap.backed_pages -= 1;
+        }
+    }
+
+    if start + size == HEAP_BASE + heap.brk {
+        heap.brk -= size;
+    }
+}
+
+/// Changes how far the heap may grow, can't go below what is already reserved
+pub fn set_limit(limit: u64) -> Result<(), &'static str> {
+    let limit = (limit + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let mut heap = HEAP.lock();
+    if limit > HEAP_REGION_SIZE {
+        return Err("heap limit is larger than the heap region");
+    }
+    if limit < heap.brk {
+        return Err("heap limit is below the reserved size");
+    }
+    heap.limit = limit;
+    Ok(())
+}
+
+pub fn limit() -> u64 {
+    HEAP.lock().limit
+}
+
+/// Bytes handed out by `reserve`
+pub fn reserved() -> u64 {
+    HEAP.lock().brk
+}
+
+/// Pages that are actually backed by frames
+pub fn backed_pages() -> u64 {
+    HEAP.lock().backed_pages
+}
@@ -1,6 +1,11 @@
 // TODO: Move the stack
 
+use crate::bord::{load_gdt, store_gdt};
 use crate::mem::alloc;
+use crate::mem::e820::{self, RegionType};
+use crate::mem::frame;
+use crate::mem::heap;
+use crate::mem::stats;
 use crate::tooling::qemu_io::qemu_fmt_println;
 use crate::tooling::qemu_io::qemu_println;
 use core::arch::asm;
@@ -9,6 +14,50 @@ use core::mem::size_of;
 use core::ptr;
 
 use core::fmt::Arguments;
+use core::ops::{BitOr, BitOrAssign};
+
+/* Virtual memory layout:
+ *   0x0000000000000000 - 0x00007FFFFFFFFFFF  lower half, free for user processes
+ *   0xFFFF800000000000 - ...                 direct map of the physical memory
+ *   0xFFFFFFFF80000000 - 0xFFFFFFFFBFFFFFFF  first GiB of physical memory, the kernel
+ *                                            is linked here (linkscript.ld)
+ * The bootloader only maps the first 6 MiB at each of these (bootloader/asm_include/
+ * paging.s), `init` replaces that with the final tables and drops the identity mapping */
+pub const PHYS_MAP_BASE: u64 = 0xFFFF_8000_0000_0000;
+pub const KERNEL_VIRT_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+const KERNEL_MAP_SIZE: u64 = 1 << 30;
+
+// the direct map covers at least the 32 bit address space so ACPI tables and MMIO are
+// reachable, and at most what the PDs in the kernel table area can map
+const PHYS_MAP_MIN_SIZE: u64 = 1 << 32;
+const PHYS_MAP_MAX_SIZE: u64 = 64 << 30;
+// what paging.s maps until `init` is done
+const BOOT_MAP_SIZE: u64 = 6 << 20;
+
+// the kernel page tables are placed from here, reserved in frame.rs. The PML4 comes first
+const KERNEL_TABLES_BASE: u64 = 0x200000;
+const HUGE_PAGE_SIZE: u64 = 0x200000;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+// bits 12..52 of an entry hold the physical address of the next table or the page
+const PTE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+static mut PHYS_MAP_SIZE: u64 = BOOT_MAP_SIZE;
+
+const IA32_EFER: u32 = 0xC0000080;
+const EFER_NXE: u64 = 1 << 11;
+const CR4_PGE: u64 = 1 << 7;
+
+extern "C" {
+    /* Defined in linkscript.ld */
+    static _kernel_end: u8;
+}
+
+pub unsafe fn get_cr0() -> u64 {
+    let mut reg_val: u64;
+    asm!("mov {}, cr0", out(reg) reg_val, options(nomem, nostack, preserves_flags));
+    reg_val
+}
 
 pub fn set_cr3(mut reg_val: u64) {
     unsafe {
@@ -16,12 +65,49 @@ pub fn set_cr3(mut reg_val: u64) {
     }
 }
 
+/// Physical address of the kernel PML4, the address space of every kernel task
+pub const fn kernel_pml4() -> u64 {
+    KERNEL_TABLES_BASE
+}
+
+/// Loads the PML4 at `pml4` into cr3. User PML4s share the PDPTs of the upper half with
+/// the kernel PML4 (see `populate_kernel_half`), so there is nothing to bring up to date
+pub fn switch_address_space(pml4: u64) {
+    unsafe {
+        if get_cr3() & PTE_ADDR_MASK != pml4 {
+            set_cr3(pml4);
+        }
+    }
+}
+
+// gives every upper half entry of the kernel PML4 a PDPT, so the entries never change
+// again and a copy of them (a user PML4) sees every later kernel mapping
+fn populate_kernel_half(pml4: &mut PT) {
+    for entry in pml4.entries[256..].iter_mut() {
+        if *entry & PageFlags::PRESENT.bits() != 0 {
+            continue;
+        }
+        let frame = frame::alloc_frame().expect("no frame for a kernel PDPT");
+        unsafe {
+            PT::new_at(phys_to_virt(frame));
+        }
+        *entry = frame | (PageFlags::PRESENT | PageFlags::WRITABLE).bits();
+    }
+}
+
 pub unsafe fn get_cr3() -> u64 {
     let mut reg_val: u64 = 0xf00dbabe;
     asm!("mov {}, cr3", out(reg) reg_val);
     assert!(reg_val != 0xf00dbabe);
     reg_val
 }
+// address that caused the last page fault
+pub unsafe fn get_cr2() -> u64 {
+    let mut reg_val: u64;
+    asm!("mov {}, cr2", out(reg) reg_val, options(nomem, nostack, preserves_flags));
+    reg_val
+}
+
 pub fn set_cr4(mut reg_val: u64) {
     unsafe {
         asm!("mov cr4, {}", in(reg) reg_val);
@@ -35,6 +121,72 @@ pub unsafe fn get_cr4() -> u64 {
     reg_val
 }
 
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    ((high as u64) << 32) | low as u64
+}
+
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nomem, nostack)
+    );
+}
+
+// drop the TLB entry of a single page
+#[inline]
+pub fn invlpg(virt: u64) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags));
+    }
+}
+
+/// Virtual address of `phys` in the direct map
+#[inline]
+pub const fn phys_to_virt(phys: u64) -> u64 {
+    PHYS_MAP_BASE + phys
+}
+
+/// Physical address behind `virt`. The kernel image and the direct map are translated
+/// by offset, anything else by walking the active page tables
+pub fn virt_to_phys(virt: u64) -> Option<u64> {
+    if virt >= KERNEL_VIRT_BASE {
+        if virt - KERNEL_VIRT_BASE < KERNEL_MAP_SIZE {
+            return Some(virt - KERNEL_VIRT_BASE);
+        }
+    } else if virt >= PHYS_MAP_BASE && virt - PHYS_MAP_BASE < phys_map_size() {
+        return Some(virt - PHYS_MAP_BASE);
+    }
+    unsafe { PT::from_cr3().translate(virt) }
+}
+
+/// Bytes of physical memory reachable through `phys_to_virt`
+pub fn phys_map_size() -> u64 {
+    unsafe { PHYS_MAP_SIZE }
+}
+
+/// Maps device registers at `phys` uncached into the heap region. The direct map is
+/// write-back cached, which memory mapped I/O must not be. The mapping is never released
+pub fn map_mmio(phys: u64, size: u64) -> Result<*mut u8, &'static str> {
+    let offset = phys & (PAGE_SIZE - 1);
+    let pages = (offset + size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let virt = heap::reserve(pages * PAGE_SIZE)? as u64;
+
+    let flags = PageFlags::WRITABLE
+        | PageFlags::CACHE_DISABLE
+        | PageFlags::WRITE_THROUGH
+        | PageFlags::NO_EXECUTE
+        | PageFlags::GLOBAL;
+    unsafe {
+        PT::kernel().map(virt, phys - offset, pages, flags)?;
+    }
+    Ok((virt + offset) as *mut u8)
+}
+
 pub struct AddrSpace {
     pub phys_base: u64,
     pub pml4: u64,
@@ -42,136 +194,173 @@ pub struct AddrSpace {
 }
 
 pub fn init() -> AddrSpace {
+    frame::init();
+
     unsafe {
+        // NX and global pages are off after boot, `PageFlags` needs both
+        wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_NXE);
+        set_cr4(get_cr4() | CR4_PGE);
+
+        // all RAM the BIOS knows about, rounded up to whole GiB (one PD each)
+        let mut highest: u64 = 0;
+        for entry in e820::entries() {
+            if entry.region_type() != RegionType::Reserved {
+                highest = highest.max(entry.end());
+            }
+        }
+        let phys_map_size = ((highest + (1 << 30) - 1) & !((1 << 30) - 1))
+            .clamp(PHYS_MAP_MIN_SIZE, PHYS_MAP_MAX_SIZE);
+
         let mut aspace = AddrSpace {
-            phys_base: 0,
+            phys_base: PHYS_MAP_BASE,
             pml4: get_cr3(),
         };
-        let new_map = aspace.create_setup_mapping();
+        let new_map = aspace.create_kernel_mapping(phys_map_size);
+
+        // the GDT is still the one in the VBR, reload it through the direct map before
+        // the identity mapping goes away
+        let mut gdtr = store_gdt();
+        gdtr.base = phys_to_virt(gdtr.base);
+        load_gdt(&gdtr);
 
-        set_cr3(new_map as u64);
+        set_cr3(new_map);
+        PHYS_MAP_SIZE = phys_map_size;
         let cr3 = get_cr3();
-        assert!(cr3 == 0x200 * 0x1000);
+        assert!(cr3 == KERNEL_TABLES_BASE);
 
         aspace = AddrSpace {
-            phys_base: 0,
+            phys_base: PHYS_MAP_BASE,
             pml4: cr3,
         };
         let mut pt = PT::from_cr3();
+        populate_kernel_half(pt);
         //let taddr = ((1 as u64) << (30)) + (511 * 512 * 0x1000) as u64;
         //let trace = aspace.translate_trace(taddr);
 
-        assert!(
-            *(0x71000 as *mut u64) == *((0x71000 + 0x40000000) as *mut u64)
-                && *(0x71000 as *mut u64) != 0
+        // the kernel and the direct map agree, and the lower half is empty
+        let kernel_end = ptr::addr_of!(_kernel_end) as u64;
+        assert!(pt.translate(kernel_end - 1) == Some(kernel_end - 1 - KERNEL_VIRT_BASE));
+        assert!(pt.translate(phys_to_virt(phys_map_size - 1)) == Some(phys_map_size - 1));
+        assert!(pt.translate(0x100000).is_none());
+        qemu_fmt_println(
+            "{}",
+            format_args!(
+                "direct map: {:#x} - {:#x}",
+                PHYS_MAP_BASE,
+                PHYS_MAP_BASE + phys_map_size
+            ),
         );
+
         alloc::init_alloc();
+        #[cfg(feature = "self-test")]
+        paging_test();
+        heap::init();
+        stats::init();
         aspace
     }
 }
 
+#[cfg(feature = "self-test")]
+fn paging_test() {
+    // somewhere in the lower half that nothing maps yet, so every level gets created
+    let virt: u64 = 0x0000_7000_0000_0000;
+    unsafe {
+        let pml4 = PT::from_cr3();
+        let phys = frame::alloc_frame().expect("no frame for the paging test");
+        pml4.map(virt, phys, 1, PageFlags::WRITABLE | PageFlags::NO_EXECUTE)
+            .unwrap();
+        assert!(pml4.translate(virt + 0x123) == Some(phys + 0x123));
+
+        *(virt as *mut u64) = 0xdeadbeef;
+        assert!(*(phys_to_virt(phys) as *const u64) == 0xdeadbeef);
+
+        pml4.protect(virt, 1, PageFlags::NO_EXECUTE).unwrap();
+        assert!(pml4.flags(virt) == Some(PageFlags::PRESENT | PageFlags::NO_EXECUTE));
+
+        assert!(pml4.unmap(virt, 1).is_ok());
+        assert!(pml4.translate(virt).is_none());
+        frame::free_frame(phys);
+    }
+}
+
 // chungus code will be refactored. It looks like this because I had to root out a bug
 impl AddrSpace {
-    // return the entire chain of translation
+    // return the entire chain of translation, indexed by table level (3 = PML4 entry,
+    // 0 = PT entry). The walk stops at entries that aren't present or map a huge page,
+    // the levels below are null
     pub unsafe fn translate_trace(&self, addr: u64) -> [*const PTE; 4] {
-        // This will technically not be PTE during initialization, but this is fine
-        let mut curr_pte: &mut PTE = &mut *((self.phys_base + self.pml4) as *mut PTE);
-        let mut i = 3;
         let mut ptes: [*const PTE; 4] = [
             core::ptr::null(),
             core::ptr::null(),
             core::ptr::null(),
             core::ptr::null(),
         ];
+        let mut table = self.pml4 & PTE_ADDR_MASK;
+        let mut i = 3;
         loop {
-            // update variables to reflect having advanced to the next table
-            qemu_fmt_println(
-                "{}",
-                format_args!("curr {:x}", ptr::addr_of_mut!(*curr_pte) as u64),
-            );
-
-            let next_addr: u64 =
-                (curr_pte.next_table_addr() + curr_pte.extract_offset(addr, i) * 8);
-            qemu_fmt_println(
-                "{}",
-                format_args!(
-                    "PT idx {}, Next addr {:#x}, Next aligned {:#x}, Next offset {}",
-                    i,
-                    next_addr,
-                    curr_pte.next_table_addr(),
-                    curr_pte.extract_offset(addr, i)
-                ),
-            );
-
-            //qemu_fmt_println("{}", format_args!("Next {:x}", *(next_addr as *mut u64)));
-
+            let curr_pte: &PTE = &*((self.phys_base
+                + table
+                + table_index(addr, i) as u64 * size_of::<PTE>() as u64)
+                as *const PTE);
             ptes[i as usize] = curr_pte;
-            curr_pte = &mut *(next_addr as *mut PTE);
 
-            if i == 0 {
+            if i == 0 || !curr_pte.page_present() || curr_pte.page_pse() {
                 break;
             }
+            table = curr_pte.next_table_addr();
             i -= 1;
         }
-        qemu_println("trace completed");
 
         ptes
     }
-    // FIXME hardcoded for the bootloader offset
-    // Assumes identity mapping
-    pub unsafe fn create_setup_mapping(&mut self) -> *const PT {
-        // offset found in bootloader/paging.s
-        let mut PDPT = &mut *(0x71000 as *mut PT);
-        let relocated_pml4_addr: u64 = (0x200 * 0x1000);
-
-        kmemcpy(
-            0x70000 as *const u8,
-            relocated_pml4_addr as *mut u8,
-            0x1000 * 5,
-        );
 
-        let mut new_pml4 = (relocated_pml4_addr as *mut PT);
-        let mut paging_offset = 0x0 + 0x200 * 0x1000 + (0x1000 * 5); // phys_base must be 0. Start the paging after the first 2 MiB, we are aiming for 4 MiB so we can map 1 GiB
-        let mut new_pdpt = PDPT;
-        let mut new_pdt = PT::new_at(paging_offset);
-        new_pdpt.entries[1] = paging_offset + 3; // 3 = page_rw | page_present
+    // Builds the kernel page tables in the reserved area at KERNEL_TABLES_BASE and
+    // returns the physical address of the new PML4. Both the kernel mapping and the
+    // direct map use 2 MiB pages, the lower half stays empty. Only works while the
+    // bootloader tables are active, since the tables are written through them
+    pub unsafe fn create_kernel_mapping(&mut self, phys_map_size: u64) -> u64 {
+        let mut next_table = KERNEL_TABLES_BASE;
+        let mut new_table = || {
+            let table = next_table;
+            PT::new_at(phys_to_virt(table));
+            next_table += PAGE_SIZE;
+            table
+        };
 
-        paging_offset += 0x1000;
-        let mut i = 0;
+        let table_flags = (PageFlags::PRESENT | PageFlags::WRITABLE).bits();
+        let huge_flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::HUGE | PageFlags::GLOBAL;
 
-        let mut j = 0;
-        // hard coded mapping
-        while j < 400 {
-            let mut new_pt = &mut *(paging_offset as *mut PT);
-            new_pdt.entries[j] = paging_offset + 3; // 3 = page_rw | page_present
-            paging_offset += 0x1000;
+        let pml4 = new_table();
 
-            let mut k = 0;
-            while k < 512 {
-                // + paging_offset + 0xFFFFFFFFFF
-                // 0b1011 -- cache disable bit, rw bit, present bit
-                new_pt.entries[k] = 16 + 3 + ((((i << 21) + (j << 12) + k * 0x1000) as u64) as u64); // 3 = page_rw | page_present
+        // kernel: the first GiB at KERNEL_VIRT_BASE, executable
+        let kernel_pdpt = new_table();
+        let kernel_pd = new_table();
+        table_at(pml4).entries[table_index(KERNEL_VIRT_BASE, 3)] = kernel_pdpt | table_flags;
+        table_at(kernel_pdpt).entries[table_index(KERNEL_VIRT_BASE, 2)] = kernel_pd | table_flags;
+        for i in 0..512 {
+            table_at(kernel_pd).entries[i] = i as u64 * HUGE_PAGE_SIZE | huge_flags.bits();
+        }
 
-                k += 1;
+        // direct map: one PD per GiB, never executable
+        let phys_pdpt = new_table();
+        table_at(pml4).entries[table_index(PHYS_MAP_BASE, 3)] = phys_pdpt | table_flags;
+        for gib in 0..(phys_map_size >> 30) {
+            let pd = new_table();
+            table_at(phys_pdpt).entries[gib as usize] = pd | table_flags;
+            for i in 0..512 {
+                table_at(pd).entries[i] = ((gib << 30) + i as u64 * HUGE_PAGE_SIZE)
+                    | (huge_flags | PageFlags::NO_EXECUTE).bits();
             }
-            j += 1;
         }
-        i += 1;
-
-        //qemu_fmt_println("paging_offset   {}", format_args!("pdt:   {:#x}", (&mut *(71008 as *mut PTE)).next_table_addr()));
 
-        let highest_address =
-            0b111111111111 + (0b111111111 << 12) + (0x400 << (12 + 9)) + (1 << (12 + 9 + 9));
         qemu_fmt_println(
-            "paging_offset   {}",
-            format_args!("virt base:   {:#x}", 0x40000000),
+            "{}",
+            format_args!(
+                "kernel page tables: {:#x} - {:#x}",
+                KERNEL_TABLES_BASE, next_table
+            ),
         );
-        qemu_fmt_println(
-            "highest_address {}",
-            format_args!("virt length: {:#x}", 400 * 512 * 4096),
-        );
-
-        new_pml4 as *const PT
+        pml4
     }
 }
 
@@ -181,9 +370,244 @@ pub struct PT {
     pub entries: [u64; 512],
 }
 
-// FIXME: we are assuming an identity mapping by the bootloader
+// flags of a page table entry
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    pub const PRESENT: Self = Self(1 << 0);
+    pub const WRITABLE: Self = Self(1 << 1);
+    pub const USER: Self = Self(1 << 2);
+    pub const WRITE_THROUGH: Self = Self(1 << 3);
+    pub const CACHE_DISABLE: Self = Self(1 << 4);
+    pub const ACCESSED: Self = Self(1 << 5);
+    pub const DIRTY: Self = Self(1 << 6);
+    pub const HUGE: Self = Self(1 << 7);
+    pub const GLOBAL: Self = Self(1 << 8);
+    pub const NO_EXECUTE: Self = Self(1 << 63);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    // keeps the flag bits of a raw entry
+    pub const fn from_entry(entry: u64) -> Self {
+        Self(entry & !PTE_ADDR_MASK)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PageFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PageFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+// page tables are accessed through the direct map, wherever they are in memory
+unsafe fn table_at(phys: u64) -> &'static mut PT {
+    &mut *(phys_to_virt(phys) as *mut PT)
+}
+
+// index into the table of `level` (3 = PML4, 0 = PT) used when translating `virt`
+fn table_index(virt: u64, level: u64) -> usize {
+    ((virt >> (12 + level * 9)) & 511) as usize
+}
+
+// bits 48..64 have to be copies of bit 47
+fn is_canonical(virt: u64) -> bool {
+    let upper = virt >> 47;
+    upper == 0 || upper == 0x1FFFF
+}
+
+fn check_range(virt: u64, page_cnt: u64) -> Result<(), &'static str> {
+    if virt % PAGE_SIZE != 0 {
+        return Err("virtual address is not page aligned");
+    }
+    if page_cnt == 0 {
+        return Err("empty virtual range");
+    }
+    let last = page_cnt
+        .checked_mul(PAGE_SIZE)
+        .and_then(|len| virt.checked_add(len - 1))
+        .ok_or("virtual range overflows")?;
+    if !is_canonical(virt) || !is_canonical(last) {
+        return Err("virtual range is not canonical");
+    }
+    Ok(())
+}
+
+// The methods below expect `self` to be a PML4. Missing PDPTs, PDs and PTs are
+// allocated from the frame allocator, the TLB entries of changed pages are flushed
 impl PT {
-    pub fn map_page(&mut self, addr: u64, page_cnt: u64) {}
+    /// Maps `page_cnt` pages starting at `virt` to the physical range starting at `phys`.
+    /// Fails without mapping anything if any of the pages is already mapped or the page
+    /// tables run out of memory. Tables allocated before running out stay, empty
+    pub fn map(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        page_cnt: u64,
+        flags: PageFlags,
+    ) -> Result<(), &'static str> {
+        check_range(virt, page_cnt)?;
+        if phys % PAGE_SIZE != 0 || phys & !PTE_ADDR_MASK != 0 {
+            return Err("invalid physical address");
+        }
+        for i in 0..page_cnt {
+            if self.translate(virt + i * PAGE_SIZE).is_some() {
+                return Err("page is already mapped");
+            }
+        }
+
+        for i in 0..page_cnt {
+            let entry = match unsafe { self.walk(virt + i * PAGE_SIZE, Some(flags)) } {
+                Ok(entry) => entry,
+                Err(err) => {
+                    /* Undo the pages mapped so far */
+                    if i > 0 {
+                        self.unmap(virt, i)?;
+                    }
+                    return Err(err);
+                }
+            };
+            *entry = (phys + i * PAGE_SIZE) | (flags | PageFlags::PRESENT).bits();
+            invlpg(virt + i * PAGE_SIZE);
+        }
+        Ok(())
+    }
+
+    /// Maps a single page
+    pub fn map_page(&mut self, virt: u64, phys: u64, flags: PageFlags) -> Result<(), &'static str> {
+        self.map(virt, phys, 1, flags)
+    }
+
+    /// Removes the mappings of `page_cnt` pages starting at `virt`. The frames behind them
+    /// are not freed, that is up to the owner. Unmapped pages in the range are skipped
+    pub fn unmap(&mut self, virt: u64, page_cnt: u64) -> Result<(), &'static str> {
+        check_range(virt, page_cnt)?;
+        for i in 0..page_cnt {
+            if let Ok(entry) = unsafe { self.walk(virt + i * PAGE_SIZE, None) } {
+                *entry = 0;
+                invlpg(virt + i * PAGE_SIZE);
+            }
+        }
+        Ok(())
+    }
+
+    /// Points the already mapped page at `virt` to `phys` with new flags, returns the
+    /// physical address it pointed to before
+    pub fn remap(&mut self, virt: u64, phys: u64, flags: PageFlags) -> Result<u64, &'static str> {
+        check_range(virt, 1)?;
+        if phys % PAGE_SIZE != 0 || phys & !PTE_ADDR_MASK != 0 {
+            return Err("invalid physical address");
+        }
+
+        let entry = unsafe { self.walk(virt, None)? };
+        if *entry & PageFlags::PRESENT.bits() == 0 {
+            return Err("page is not mapped");
+        }
+        let old = *entry & PTE_ADDR_MASK;
+        *entry = phys | (flags | PageFlags::PRESENT).bits();
+        invlpg(virt);
+        Ok(old)
+    }
+
+    /// Replaces the flags of `page_cnt` mapped pages starting at `virt`
+    pub fn protect(&mut self, virt: u64, page_cnt: u64, flags: PageFlags) -> Result<(), &'static str> {
+        check_range(virt, page_cnt)?;
+        for i in 0..page_cnt {
+            if self.translate(virt + i * PAGE_SIZE).is_none() {
+                return Err("page is not mapped");
+            }
+        }
+
+        for i in 0..page_cnt {
+            let entry = unsafe { self.walk(virt + i * PAGE_SIZE, None)? };
+            *entry = (*entry & PTE_ADDR_MASK) | (flags | PageFlags::PRESENT).bits();
+            invlpg(virt + i * PAGE_SIZE);
+        }
+        Ok(())
+    }
+
+    /// Physical address `virt` is mapped to, huge pages included
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        if !is_canonical(virt) {
+            return None;
+        }
+
+        let mut table: &PT = self;
+        for level in (0..4).rev() {
+            let entry = table.entries[table_index(virt, level)];
+            if entry & PageFlags::PRESENT.bits() == 0 {
+                return None;
+            }
+
+            let page_mask = (1u64 << (12 + level * 9)) - 1;
+            if level == 0 || (level < 3 && entry & PageFlags::HUGE.bits() != 0) {
+                return Some((entry & PTE_ADDR_MASK & !page_mask) | (virt & page_mask));
+            }
+            table = unsafe { table_at(entry & PTE_ADDR_MASK) };
+        }
+        None
+    }
+
+    /// Flags of the 4 KiB page mapping `virt`
+    pub fn flags(&mut self, virt: u64) -> Option<PageFlags> {
+        let entry = unsafe { self.walk(virt, None).ok()? };
+        if *entry & PageFlags::PRESENT.bits() == 0 {
+            return None;
+        }
+        Some(PageFlags::from_entry(*entry))
+    }
+
+    // returns the PT entry for `virt`. With `create` missing tables are allocated, user
+    // mappings need the user bit on every level
+    unsafe fn walk(
+        &mut self,
+        virt: u64,
+        create: Option<PageFlags>,
+    ) -> Result<&'static mut u64, &'static str> {
+        let mut table: *mut PT = self;
+        for level in (1..4).rev() {
+            let entry = &mut (*table).entries[table_index(virt, level)];
+
+            if *entry & PageFlags::PRESENT.bits() == 0 {
+                let flags = create.ok_or("page is not mapped")?;
+                let frame = frame::alloc_frame().ok_or("out of memory for page tables")?;
+                PT::new_at(phys_to_virt(frame));
+
+                let mut table_flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+                if flags.contains(PageFlags::USER) {
+                    table_flags |= PageFlags::USER;
+                }
+                *entry = frame | table_flags.bits();
+            } else if *entry & PageFlags::HUGE.bits() != 0 {
+                return Err("address is mapped by a huge page");
+            } else if let Some(flags) = create {
+                if flags.contains(PageFlags::USER) {
+                    *entry |= PageFlags::USER.bits();
+                }
+            }
+
+            table = table_at(*entry & PTE_ADDR_MASK);
+        }
+        Ok(&mut (*table).entries[table_index(virt, 0)])
+    }
 
     pub fn copy_from(to_copy: &PT, target_addr: *mut u8) -> &'static mut Self {
         let pt: *const PT = ptr::from_ref(to_copy);
@@ -215,7 +639,40 @@ impl PT {
 
     // dereference pointer in cr3 and return a (safer) borrow to the page table
     pub unsafe fn from_cr3() -> &'static mut Self {
-        &mut *(get_cr3() as *mut PT)
+        table_at(get_cr3() & PTE_ADDR_MASK)
+    }
+
+    // the kernel PML4, whatever address space is active. Mappings in the upper half go
+    // here, user address spaces share its PDPTs
+    pub unsafe fn kernel() -> &'static mut Self {
+        table_at(KERNEL_TABLES_BASE)
+    }
+
+    // the table at physical address `phys`
+    pub unsafe fn at(phys: u64) -> &'static mut Self {
+        table_at(phys & PTE_ADDR_MASK)
+    }
+
+    // frees every page mapped in the lower half of this PML4 and the tables mapping them.
+    // Only for user address spaces, which own all their lower half frames
+    pub unsafe fn free_lower_half(&mut self) {
+        unsafe fn free_table(phys: u64, level: u64) {
+            if level > 0 {
+                for &entry in table_at(phys).entries.iter() {
+                    if entry & PageFlags::PRESENT.bits() != 0 {
+                        free_table(entry & PTE_ADDR_MASK, level - 1);
+                    }
+                }
+            }
+            frame::free_frame(phys);
+        }
+
+        for entry in self.entries[..256].iter_mut() {
+            if *entry & PageFlags::PRESENT.bits() != 0 {
+                free_table(*entry & PTE_ADDR_MASK, 2);
+            }
+            *entry = 0;
+        }
     }
 }
 pub struct PTE(u64);
@@ -231,9 +688,13 @@ impl PTE {
         return &mut *(next_addr as *mut PTE);
     }
 
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
     pub fn next_table_addr(&self) -> u64 {
-        // remove bottom 12 bits (one page = 4kib = 12 bits) and remove the flags above the 48th bit
-        return self.0 & (!(((1 << 12) - 1) as u64)); // bits 12 to 12 + 9 determine the addr the next pte
+        // remove bottom 12 bits (one page = 4kib = 12 bits) and remove the flags above the 52nd bit
+        return self.0 & PTE_ADDR_MASK;
     }
 
     pub fn extract_offset(&self, addr: u64, table_idx: u64) -> u64 {
@@ -241,15 +702,15 @@ impl PTE {
         // bits 12 to 12 + 9 determine the addr the next pte
     }
 
-    fn page_present(&self) -> bool {
+    pub fn page_present(&self) -> bool {
         return ((self.0 & 1) << 0) != 0;
     }
 
-    fn page_rw(&self) -> bool {
+    pub fn page_rw(&self) -> bool {
         return (self.0 & (1 << 1)) != 0;
     }
 
-    fn page_user(&self) -> bool {
+    pub fn page_user(&self) -> bool {
         return (self.0 & (1 << 2)) != 0;
     }
 
@@ -262,13 +723,17 @@ impl PTE {
         return (self.0 & (1 << 6)) != 0;
     }
 
-    fn page_pse(&self) -> bool {
+    pub fn page_pse(&self) -> bool {
         return (self.0 & (1 << 7)) != 0;
     }
 
     fn page_global(&self) -> bool {
         return (self.0 & (1 << 8)) != 0;
     }
+
+    pub fn page_nx(&self) -> bool {
+        return (self.0 & (1 << 63)) != 0;
+    }
     // ... add the rest
 }
 
@@ -316,10 +781,10 @@ pub fn kmemcpy(from: *const u8, to: *mut u8, sz: usize) {
     unsafe {
         asm!(
             "rep movsb",
-            in("rcx") sz,
-            in("rsi") from as u64,
-            in("rdi") to as u64,
-            options(nostack, preserves_flags, nomem)
+            inout("rcx") sz => _,
+            inout("rsi") from as u64 => _,
+            inout("rdi") to as u64 => _,
+            options(nostack, preserves_flags)
         );
     }
 }
@@ -327,10 +792,10 @@ pub fn kmemset(str: *const u8, c: u8, n: usize) {
     unsafe {
         asm!(
             "rep stosb",
-            in("rcx") n,
-            in("rdi") str as u64,
+            inout("rcx") n => _,
+            inout("rdi") str as u64 => _,
             in("al") c,
-            options(nostack, preserves_flags, nomem)
+            options(nostack, preserves_flags)
         )
     }
 }
@@ -1,2 +1,10 @@
 pub mod alloc;
+pub mod buddy;
+pub mod e820;
+pub mod fault;
+pub mod frame;
+pub mod heap;
 pub mod memory;
+pub mod slab;
+pub mod stack;
+pub mod stats;
@@ -0,0 +1,383 @@
+use crate::mem::buddy;
+use crate::mem::frame::FRAME_SIZE;
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+/* Slab allocator for small kernel objects. Every cache hands out objects of one size,
+ * carved out of single pages taken from the buddy allocator. A page (slab) starts with
+ * a `Slab` header followed by the objects, free objects are chained through their first
+ * bytes. Since no object starts at the beginning of a page, `kfree` can tell slab
+ * objects apart from page sized buddy blocks by the alignment alone.
+ *
+ * `kalloc` serves everything up to MAX_SIZE from the generic size classes below,
+ * subsystems with many objects of the same type can create their own cache with
+ * `SlabCache::new` and a constructor that runs on every object handed out. Every cache
+ * has its own lock, taken before the buddy allocator's when a cache grows or shrinks. */
+
+/* Generic caches used by `kalloc`. Objects are aligned to their size class */
+const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+pub const MAX_SIZE: usize = 1024;
+
+/* Caches that get listed by `dump` */
+const MAX_CACHES: usize = 32;
+
+const SLAB_MAGIC: u32 = 0x51AB51AB;
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/* Lives at the start of every slab page */
+struct Slab {
+    magic: u32,
+    cache: *const SlabCache,
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+    capacity: usize,
+}
+
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    ctor: Option<fn(*mut u8)>,
+    lists: IrqSpinlock<SlabLists>,
+}
+
+struct SlabLists {
+    /* Slabs with at least one free object, full slabs are in no list */
+    partial: *mut Slab,
+    slabs: usize,
+    in_use: usize,
+    free: usize,
+    registered: bool,
+}
+
+/* The slabs are pages in the direct map, valid on every CPU */
+unsafe impl Send for SlabLists {}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    pub free: usize,
+}
+
+static GENERIC_CACHES: [SlabCache; SIZE_CLASSES.len()] = [
+    SlabCache::new("kmalloc-16", 16, None),
+    SlabCache::new("kmalloc-32", 32, None),
+    SlabCache::new("kmalloc-64", 64, None),
+    SlabCache::new("kmalloc-128", 128, None),
+    SlabCache::new("kmalloc-256", 256, None),
+    SlabCache::new("kmalloc-512", 512, None),
+    SlabCache::new("kmalloc-1024", 1024, None),
+];
+
+static CACHES: IrqSpinlock<heapless::Vec<&'static SlabCache, MAX_CACHES>> =
+    IrqSpinlock::new(heapless::Vec::new());
+
+impl SlabCache {
+    /// Creates an empty cache for objects of `object_size` bytes. `ctor` is called on
+    /// every object right before it is handed out by `alloc`
+    pub const fn new(name: &'static str, object_size: usize, ctor: Option<fn(*mut u8)>) -> Self {
+        /* Free objects have to fit the free list link */
+        let mut size = if object_size < size_of::<FreeObject>() {
+            size_of::<FreeObject>()
+        } else {
+            object_size
+        };
+        size = (size + 7) & !7;
+
+        Self {
+            name,
+            object_size: size,
+            ctor,
+            lists: IrqSpinlock::new(SlabLists {
+                partial: null_mut(),
+                slabs: 0,
+                in_use: 0,
+                free: 0,
+                registered: false,
+            }),
+        }
+    }
+
+    /// Offset of the first object in a slab. Power of two sized objects are aligned to
+    /// their size, everything else to 8 bytes
+    fn first_object(&self) -> usize {
+        let align = if self.object_size.is_power_of_two() {
+            self.object_size
+        } else {
+            8
+        };
+        (size_of::<Slab>() + align - 1) & !(align - 1)
+    }
+
+    fn capacity(&self) -> usize {
+        (FRAME_SIZE as usize - self.first_object()) / self.object_size
+    }
+
+    /// Takes a page from the buddy allocator and puts all of its objects on the free list
+    fn grow(&'static self, lists: &mut SlabLists) -> bool {
+        let page = buddy::alloc(0);
+        if page.is_null() {
+            return false;
+        }
+
+        if !lists.registered {
+            lists.registered = true;
+            /* Caches past MAX_CACHES work, `dump` just doesn't list them */
+            let _ = CACHES.lock().push(self);
+        }
+        let capacity = self.capacity();
+        let first = self.first_object();
+        let slab = page as *mut Slab;
+        unsafe {
+            /* Chain the objects front to back */
+            let mut free: *mut FreeObject = null_mut();
+            for i in (0..capacity).rev() {
+                let object = page.add(first + i * self.object_size) as *mut FreeObject;
+                (*object).next = free;
+                free = object;
+            }
+
+            *slab = Slab {
+                magic: SLAB_MAGIC,
+                cache: self as *const SlabCache,
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                in_use: 0,
+                capacity,
+            };
+        }
+
+        lists.link(slab);
+        lists.slabs += 1;
+        lists.free += capacity;
+        true
+    }
+
+    /// Returns an object of the cache, null if out of memory
+    pub fn alloc(&'static self) -> *mut u8 {
+        let object = {
+            let mut lists = self.lists.lock();
+            if lists.partial.is_null() && !self.grow(&mut lists) {
+                return null_mut();
+            }
+
+            let slab = lists.partial;
+            let object = unsafe {
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                (*slab).in_use += 1;
+                if (*slab).free.is_null() {
+                    lists.unlink(slab);
+                }
+                object as *mut u8
+            };
+            lists.in_use += 1;
+            lists.free -= 1;
+            object
+        };
+
+        if let Some(ctor) = self.ctor {
+            ctor(object);
+        }
+        object
+    }
+
+    /// Gives an object back to the cache it was allocated from
+    pub fn free(&self, ptr: *mut u8) {
+        let slab = slab_of(ptr);
+        let mut lists = self.lists.lock();
+        unsafe {
+            if (*slab).cache != self as *const SlabCache {
+                panic!("slab: {:p} doesn't belong to cache {}", ptr, self.name);
+            }
+
+            let offset = ptr as usize - slab as usize;
+            if offset < self.first_object() || (offset - self.first_object()) % self.object_size != 0 {
+                panic!("slab: tried to free a misaligned object {:p}", ptr);
+            }
+
+            let object = ptr as *mut FreeObject;
+            /* The slab was full and isn't in the partial list */
+            if (*slab).free.is_null() {
+                lists.link(slab);
+            }
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+        }
+
+        lists.in_use -= 1;
+        lists.free += 1;
+    }
+
+    /// Returns the pages of all empty slabs to the buddy allocator
+    pub fn shrink(&self) {
+        let mut lists = self.lists.lock();
+        let mut slab = lists.partial;
+        while !slab.is_null() {
+            unsafe {
+                let next = (*slab).next;
+                if (*slab).in_use == 0 {
+                    lists.unlink(slab);
+                    lists.slabs -= 1;
+                    lists.free -= (*slab).capacity;
+                    (*slab).magic = 0;
+                    buddy::free(slab as *mut u8);
+                }
+                slab = next;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let lists = self.lists.lock();
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            slabs: lists.slabs,
+            in_use: lists.in_use,
+            free: lists.free,
+        }
+    }
+}
+
+impl SlabLists {
+    fn link(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    fn unlink(&mut self, slab: *mut Slab) {
+        unsafe {
+            if (*slab).prev.is_null() {
+                self.partial = (*slab).next;
+            } else {
+                (*(*slab).prev).next = (*slab).next;
+            }
+            if !(*slab).next.is_null() {
+                (*(*slab).next).prev = (*slab).prev;
+            }
+            (*slab).next = null_mut();
+            (*slab).prev = null_mut();
+        }
+    }
+}
+
+/// Header of the slab `ptr` points into
+fn slab_of(ptr: *mut u8) -> *mut Slab {
+    let slab = (ptr as u64 & !(FRAME_SIZE - 1)) as *mut Slab;
+    unsafe {
+        if ptr as u64 % FRAME_SIZE == 0 || (*slab).magic != SLAB_MAGIC {
+            panic!("slab: {:p} is not a slab object", ptr);
+        }
+    }
+    slab
+}
+
+/// Whether `ptr` can be a slab object, as opposed to a page aligned buddy block
+#[inline]
+pub fn is_slab_object(ptr: *mut u8) -> bool {
+    ptr as u64 % FRAME_SIZE != 0
+}
+
+/// Allocates `size` bytes from the smallest fitting size class, null if `size` is larger
+/// than MAX_SIZE or out of memory
+pub fn alloc(size: usize) -> *mut u8 {
+    for (i, class) in SIZE_CLASSES.iter().enumerate() {
+        if size <= *class {
+            return GENERIC_CACHES[i].alloc();
+        }
+    }
+    null_mut()
+}
+
+/// Frees an object of any cache
+pub fn free(ptr: *mut u8) {
+    unsafe { (*(*slab_of(ptr)).cache).free(ptr) }
+}
+
+/// Returns the empty slabs of all caches to the buddy allocator
+pub fn shrink_all() {
+    /* Copied out, a cache that grows holds its own lock while it takes this one */
+    let caches = CACHES.lock().clone();
+    for cache in caches {
+        cache.shrink();
+    }
+}
+
+pub fn dump() {
+    qemu_println!("slab: {:<16} {:>6} {:>6} {:>8} {:>8}", "cache", "size", "slabs", "in use", "free");
+    let caches = CACHES.lock().clone();
+    for cache in caches {
+        let stats = cache.stats();
+        qemu_println!(
+            "      {:<16} {:>6} {:>6} {:>8} {:>8}",
+            stats.name,
+            stats.object_size,
+            stats.slabs,
+            stats.in_use,
+            stats.free
+        );
+    }
+}
+
+#[cfg(test)]
+mod slab_tests {
+    use super::*;
+
+    #[test]
+    fn test_objects_fit_the_free_list_link() {
+        assert!(SlabCache::new("test", 1, None).object_size == 8);
+        assert!(SlabCache::new("test", 12, None).object_size == 16);
+        assert!(SlabCache::new("test", 24, None).object_size == 24);
+    }
+
+    #[test]
+    fn test_size_classes_are_aligned_to_their_size() {
+        for class in SIZE_CLASSES {
+            let cache = SlabCache::new("test", class, None);
+            assert!(cache.first_object() >= size_of::<Slab>());
+            assert!(cache.first_object() % class == 0);
+        }
+    }
+
+    #[test]
+    fn test_capacity_fills_the_page() {
+        let cache = SlabCache::new("test", 24, None);
+        assert!(cache.first_object() == (size_of::<Slab>() + 7) & !7);
+        let end = cache.first_object() + cache.capacity() * 24;
+        assert!(end <= FRAME_SIZE as usize && end + 24 > FRAME_SIZE as usize);
+
+        // the header takes the first of the four 1024 byte slots
+        assert!(SlabCache::new("test", 1024, None).capacity() == 3);
+    }
+
+    #[test]
+    fn test_objects_are_never_page_aligned() {
+        let page = FRAME_SIZE as usize * 3;
+        for class in SIZE_CLASSES {
+            let cache = SlabCache::new("test", class, None);
+            for i in 0..cache.capacity() {
+                let object = page + cache.first_object() + i * cache.object_size;
+                assert!(is_slab_object(object as *mut u8));
+            }
+        }
+        assert!(!is_slab_object(page as *mut u8));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::mem::frame;
+use crate::mem::memory::{PageFlags, PAGE_SIZE, PT};
+use crate::sync::IrqSpinlock;
+
+/* Kernel stacks. Every stack lives in its own slot of a dedicated virtual region, at the
+ * top of the slot. Everything in the slot below the stack (at least one page) stays
+ * unmapped, so running off the end of a stack faults instead of silently overwriting
+ * whatever lies below it. Stacks are named after the task using them, which lets the
+ * fault handlers say whose stack overflowed. */
+
+/* PML4 entry 448 */
+pub const STACKS_BASE: u64 = 0xFFFF_E000_0000_0000;
+const SLOT_SIZE: u64 = 0x40000;
+const MAX_STACKS: usize = 256;
+/* One page of every slot is always left as guard */
+pub const MAX_STACK_PAGES: u64 = SLOT_SIZE / PAGE_SIZE - 1;
+
+#[derive(Copy, Clone)]
+struct StackSlot {
+    name: &'static str,
+    pages: u64,
+}
+
+/* Irq safe, the fault handlers look up the owner of a guard page */
+static STACK_SLOTS: IrqSpinlock<[Option<StackSlot>; MAX_STACKS]> = IrqSpinlock::new([None; MAX_STACKS]);
+
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    fn slot_base(&self) -> u64 {
+        STACKS_BASE + self.slot as u64 * SLOT_SIZE
+    }
+
+    /// Initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> u64 {
+        self.slot_base() + SLOT_SIZE
+    }
+
+    /// Lowest mapped address of the stack
+    pub fn bottom(&self) -> u64 {
+        self.top() - STACK_SLOTS.lock()[self.slot].unwrap().pages * PAGE_SIZE
+    }
+
+    pub fn name(&self) -> &'static str {
+        STACK_SLOTS.lock()[self.slot].unwrap().name
+    }
+
+    pub fn rename(&mut self, name: &'static str) {
+        if let Some(slot) = STACK_SLOTS.lock()[self.slot].as_mut() {
+            slot.name = name;
+        }
+    }
+}
+
+/// Maps a new stack of `pages` pages with a guard below it
+pub fn alloc(name: &'static str, pages: u64) -> Result<KernelStack, &'static str> {
+    if pages == 0 || pages > MAX_STACK_PAGES {
+        return Err("invalid kernel stack size");
+    }
+
+    let stack = {
+        let mut slots = STACK_SLOTS.lock();
+        let slot = slots
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or("no free kernel stack slots")?;
+        slots[slot] = Some(StackSlot { name, pages });
+        KernelStack { slot }
+    };
+
+    let pml4 = unsafe { PT::kernel() };
+    let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE | PageFlags::GLOBAL;
+    for page in (stack.bottom()..stack.top()).step_by(PAGE_SIZE as usize) {
+        let mapped = match frame::alloc_frame() {
+            Some(phys) => pml4.map_page(page, phys, flags).map_err(|err| {
+                frame::free_frame(phys);
+                err
+            }),
+            None => Err("out of memory for kernel stack"),
+        };
+
+        if let Err(err) = mapped {
+            free(stack);
+            return Err(err);
+        }
+    }
+    Ok(stack)
+}
+
+/// Unmaps the stack and gives its frames back
+pub fn free(stack: KernelStack) {
+    let pml4 = unsafe { PT::kernel() };
+    for page in (stack.bottom()..stack.top()).step_by(PAGE_SIZE as usize) {
+        if let Some(phys) = pml4.translate(page) {
+            pml4.unmap(page, 1).unwrap();
+            frame::free_frame(phys);
+        }
+    }
+    STACK_SLOTS.lock()[stack.slot] = None;
+}
+
+/// Name of the stack whose guard area contains `addr`, if any
+pub fn guard_owner(addr: u64) -> Option<&'static str> {
+    if addr < STACKS_BASE || addr >= STACKS_BASE + MAX_STACKS as u64 * SLOT_SIZE {
+        return None;
+    }
+
+    let slot = ((addr - STACKS_BASE) / SLOT_SIZE) as usize;
+    let slot_top = STACKS_BASE + (slot as u64 + 1) * SLOT_SIZE;
+    let stack = STACK_SLOTS.lock()[slot]?;
+    if addr < slot_top - stack.pages * PAGE_SIZE {
+        return Some(stack.name);
+    }
+    None
+}
@@ -0,0 +1,370 @@
+use crate::mem::frame::{self, FRAME_SIZE};
+use crate::mem::heap;
+use crate::mem::slab;
+use crate::percpu;
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::Ordering;
+
+/* Memory usage statistics. Every `kalloc` is charged to the subsystem that is current
+ * when it happens (see `scope`). The current subsystem is kept per CPU and saved in the
+ * task when it is switched out, so a preempted scope doesn't leak into other tasks.
+ *
+ * The live allocations are kept in a hash table in the demand paged heap so `kfree` can
+ * find their size and owner again. Allocations that don't get an entry (before `init`
+ * or with the table full) can't be taken back off on free, so they are only counted as
+ * untracked: once that count isn't 0 the byte counts and peaks under-report. With call
+ * site tracking on, the return addresses of the allocating call chain are recorded too,
+ * and `dump_allocations` lists every allocation that is still alive, which shows leaks.
+ *
+ * The numbers can be dumped over serial with the M (stats) and L (live allocations)
+ * keys, K turns call site tracking on and off, see `key_event` in main.rs. */
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Subsystem {
+    Kernel = 0,
+    Fs,
+    Graphics,
+    Drivers,
+}
+
+const SUBSYSTEMS: [Subsystem; 4] = [
+    Subsystem::Kernel,
+    Subsystem::Fs,
+    Subsystem::Graphics,
+    Subsystem::Drivers,
+];
+
+impl Subsystem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subsystem::Kernel => "kernel",
+            Subsystem::Fs => "fs",
+            Subsystem::Graphics => "graphics",
+            Subsystem::Drivers => "drivers",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Counters {
+    /// Bytes currently allocated
+    pub bytes: u64,
+    /// Allocations currently alive
+    pub allocations: u64,
+    /// Allocations ever made
+    pub total_allocations: u64,
+    /// Highest value `bytes` has had
+    pub peak_bytes: u64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            bytes: 0,
+            allocations: 0,
+            total_allocations: 0,
+            peak_bytes: 0,
+        }
+    }
+
+    fn add(&mut self, size: u64) {
+        self.bytes += size;
+        self.allocations += 1;
+        self.total_allocations += 1;
+        self.peak_bytes = self.peak_bytes.max(self.bytes);
+    }
+
+    fn remove(&mut self, size: u64) {
+        self.bytes -= size;
+        self.allocations -= 1;
+    }
+}
+
+/* Return addresses recorded per allocation with call site tracking */
+const CALLER_DEPTH: usize = 3;
+
+#[derive(Copy, Clone)]
+struct Allocation {
+    /* 0 marks an empty slot */
+    addr: u64,
+    size: u64,
+    subsystem: Subsystem,
+    callers: [u64; CALLER_DEPTH],
+}
+
+/* Open addressing with linear probing, stops tracking new allocations at 3/4 load */
+const TABLE_BITS: u32 = 15;
+const TABLE_SIZE: usize = 1 << TABLE_BITS;
+const TABLE_MAX_LOAD: usize = TABLE_SIZE / 4 * 3;
+
+struct Stats {
+    table: *mut Allocation,
+    tracked: usize,
+    /* Allocations that didn't fit the table or were made before `init`, they are in none
+     * of the counters */
+    untracked: u64,
+    call_sites: bool,
+    subsystems: [Counters; SUBSYSTEMS.len()],
+    total: Counters,
+}
+
+/* The table is in the kernel heap, mapped in every address space */
+unsafe impl Send for Stats {}
+
+/* Taken before the heap lock, the first access to a page of the table backs it */
+static STATS: IrqSpinlock<Stats> = IrqSpinlock::new(Stats {
+    table: null_mut(),
+    tracked: 0,
+    untracked: 0,
+    call_sites: false,
+    subsystems: [Counters::new(); SUBSYSTEMS.len()],
+    total: Counters::new(),
+});
+
+/// Charges allocations to a subsystem until it is dropped
+pub struct SubsystemScope {
+    previous: Subsystem,
+}
+
+impl Drop for SubsystemScope {
+    fn drop(&mut self) {
+        set_current(self.previous);
+    }
+}
+
+/// `let _mem = stats::scope(Subsystem::Fs);` charges every allocation of the calling task
+/// to the file system until the end of the block
+pub fn scope(subsystem: Subsystem) -> SubsystemScope {
+    let previous = current();
+    set_current(subsystem);
+    SubsystemScope { previous }
+}
+
+/// Subsystem the allocations of the running task are charged to
+pub fn current() -> Subsystem {
+    SUBSYSTEMS[percpu::current().mem_subsystem.load(Ordering::Relaxed) as usize]
+}
+
+/// Makes `subsystem` current on this CPU, for task switches
+pub fn set_current(subsystem: Subsystem) {
+    percpu::current()
+        .mem_subsystem
+        .store(subsystem as u8, Ordering::Relaxed);
+}
+
+/// Reserves the allocation table. Allocations made before this aren't tracked
+pub fn init() {
+    let table = heap::reserve((TABLE_SIZE * size_of::<Allocation>()) as u64)
+        .expect("stats: no room for the allocation table");
+    STATS.lock().table = table as *mut Allocation;
+}
+
+/// Records the call chain of every allocation from now on. Needs frame pointers
+pub fn set_call_site_tracking(enabled: bool) {
+    STATS.lock().call_sites = enabled;
+}
+
+pub fn call_site_tracking() -> bool {
+    STATS.lock().call_sites
+}
+
+#[inline]
+fn slot_of(addr: u64) -> usize {
+    ((addr >> 4).wrapping_mul(0x9E3779B97F4A7C15) >> (64 - TABLE_BITS)) as usize
+}
+
+// walks the rbp chain, skipping the allocator's own frames
+#[inline(never)]
+unsafe fn callers() -> [u64; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let mut rbp: *const u64;
+    asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+
+    /* The return addresses into record_alloc and kalloc */
+    let skip = 2;
+    for i in 0..skip + CALLER_DEPTH {
+        /* Only follow frames that are in the kernel half */
+        if (rbp as u64) < 0xFFFF_8000_0000_0000 || rbp as u64 % 8 != 0 {
+            break;
+        }
+        if i >= skip {
+            callers[i - skip] = *rbp.add(1);
+        }
+        rbp = *rbp as *const u64;
+    }
+    callers
+}
+
+pub fn record_alloc(ptr: *mut u8, size: usize) {
+    let mut guard = STATS.lock();
+    let stats = &mut *guard;
+    unsafe {
+        let size = size as u64;
+        /* Without an entry the free couldn't be accounted for either */
+        if stats.table.is_null() || stats.tracked >= TABLE_MAX_LOAD {
+            stats.untracked += 1;
+            return;
+        }
+        let subsystem = current();
+        stats.subsystems[subsystem as usize].add(size);
+        stats.total.add(size);
+
+        let callers = if stats.call_sites {
+            callers()
+        } else {
+            [0; CALLER_DEPTH]
+        };
+
+        let mut slot = slot_of(ptr as u64);
+        while (*stats.table.add(slot)).addr != 0 {
+            slot = (slot + 1) % TABLE_SIZE;
+        }
+        *stats.table.add(slot) = Allocation {
+            addr: ptr as u64,
+            size,
+            subsystem,
+            callers,
+        };
+        stats.tracked += 1;
+    }
+}
+
+pub fn record_free(ptr: *mut u8) {
+    let mut guard = STATS.lock();
+    let stats = &mut *guard;
+    unsafe {
+        if stats.table.is_null() {
+            return;
+        }
+
+        let mut slot = slot_of(ptr as u64);
+        loop {
+            let addr = (*stats.table.add(slot)).addr;
+            if addr == ptr as u64 {
+                break;
+            }
+            /* Allocated before `init` or while the table was full */
+            if addr == 0 {
+                return;
+            }
+            slot = (slot + 1) % TABLE_SIZE;
+        }
+
+        let allocation = *stats.table.add(slot);
+        stats.subsystems[allocation.subsystem as usize].remove(allocation.size);
+        stats.total.remove(allocation.size);
+        stats.tracked -= 1;
+
+        /* Backward shift deletion, so no later entry of the probe chain gets cut off */
+        let mut hole = slot;
+        let mut next = (slot + 1) % TABLE_SIZE;
+        loop {
+            let entry = *stats.table.add(next);
+            if entry.addr == 0 {
+                break;
+            }
+
+            let home = slot_of(entry.addr);
+            /* The entry can fill the hole if its home slot isn't between hole and next */
+            let distance_home = (next + TABLE_SIZE - home) % TABLE_SIZE;
+            let distance_hole = (next + TABLE_SIZE - hole) % TABLE_SIZE;
+            if distance_home >= distance_hole {
+                *stats.table.add(hole) = entry;
+                hole = next;
+            }
+            next = (next + 1) % TABLE_SIZE;
+        }
+        (*stats.table.add(hole)).addr = 0;
+    }
+}
+
+pub fn counters(subsystem: Subsystem) -> Counters {
+    STATS.lock().subsystems[subsystem as usize]
+}
+
+pub fn total() -> Counters {
+    STATS.lock().total
+}
+
+pub fn dump() {
+    qemu_println!(
+        "memory: {:<10} {:>12} {:>8} {:>10} {:>12}",
+        "subsystem",
+        "bytes",
+        "allocs",
+        "total",
+        "peak bytes"
+    );
+    for subsystem in SUBSYSTEMS {
+        let c = counters(subsystem);
+        qemu_println!(
+            "        {:<10} {:>12} {:>8} {:>10} {:>12}",
+            subsystem.name(),
+            c.bytes,
+            c.allocations,
+            c.total_allocations,
+            c.peak_bytes
+        );
+    }
+    let c = total();
+    qemu_println!(
+        "        {:<10} {:>12} {:>8} {:>10} {:>12}",
+        "all",
+        c.bytes,
+        c.allocations,
+        c.total_allocations,
+        c.peak_bytes
+    );
+
+    let (untracked, call_sites) = {
+        let stats = STATS.lock();
+        (stats.untracked, stats.call_sites)
+    };
+    qemu_println!(
+        "memory: {} untracked allocations, call site tracking {}",
+        untracked,
+        if call_sites { "on" } else { "off" }
+    );
+    qemu_println!(
+        "memory: {} of {} frames free ({} KiB), heap {} KiB reserved, {} pages backed",
+        frame::free_frames(),
+        frame::total_frames(),
+        frame::free_frames() as u64 * FRAME_SIZE / 1024,
+        heap::reserved() / 1024,
+        heap::backed_pages()
+    );
+    slab::dump();
+}
+
+/// Lists the live allocations of `subsystem` (or all of them), with their call sites if
+/// those were recorded
+pub fn dump_allocations(subsystem: Option<Subsystem>) {
+    for slot in 0..TABLE_SIZE {
+        /* One slot at a time, the lock isn't held while printing */
+        let allocation = {
+            let stats = STATS.lock();
+            if stats.table.is_null() {
+                return;
+            }
+            unsafe { *stats.table.add(slot) }
+        };
+        if allocation.addr == 0 || subsystem.is_some_and(|s| s != allocation.subsystem) {
+            continue;
+        }
+
+        qemu_println!(
+            "alloc {:#x} {:>8} bytes {:<10} from {:#x} < {:#x} < {:#x}",
+            allocation.addr,
+            allocation.size,
+            allocation.subsystem.name(),
+            allocation.callers[0],
+            allocation.callers[1],
+            allocation.callers[2]
+        );
+    }
+}
@@ -1,10 +1,11 @@
 // pseudorandom number generator
+use crate::sync::IrqSpinlock;
 use crate::time::get_millis;
 const FLOAT_DIVISOR: f32 = 8_388_607.0;
 const DOUBLE_DIVISOR: f64 = 9_007_199_254_740_991.0;
 const FLOAT_DIVISOR_U32: u32 = 8388607;
 const DOUBLE_DIVISOR_U64: u64 = 9007199254740991;
-pub static mut RNG: Rng = Rng::test();
+pub static RNG: IrqSpinlock<Rng> = IrqSpinlock::new(Rng::test());
 
 pub struct Rng {
     current: u64,
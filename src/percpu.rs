@@ -0,0 +1,113 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::apic::MAX_CPUS;
+use crate::gdt::{self, TaskStateSegment, GDT_ENTRIES};
+use crate::mem::memory::wrmsr;
+
+/* Data every CPU has its own copy of. The GS base of a CPU points at its `PerCpu`, whose
+ * first field points back at it, so `current` finds it with a single load through GS.
+ * Entry 0 is the boot CPU, the application processors get the next free entries as
+ * smp.rs starts them. */
+
+const IA32_GS_BASE: u32 = 0xC0000101;
+const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+
+#[repr(C)]
+pub struct PerCpu {
+    /* Must stay the first field, see `current` */
+    self_ptr: *mut PerCpu,
+    /// Index into the per-CPU tables, 0 is the boot CPU
+    pub index: usize,
+    pub apic_id: u8,
+    /// Set once the CPU is up
+    pub online: AtomicBool,
+    /* fn() the idle loop runs next, 0 if there is none (see smp::run_on) */
+    pub(crate) work: AtomicUsize,
+    /* Running task, 0 if the CPU doesn't run tasks (see task.rs) */
+    pub(crate) current_task: AtomicU64,
+    /* Task this CPU just switched away from, until the next one picked it up */
+    pub(crate) prev_task: AtomicU64,
+    /* Runs when nothing else is ready */
+    pub(crate) idle_task: AtomicU64,
+    /* Ticks left of the time slice of the running task */
+    pub(crate) slice_left: AtomicU64,
+    /* now_ns() of the last task switch, for the CPU time of tasks */
+    pub(crate) switched_at: AtomicU64,
+    /* Switch tasks once the interrupt handler is done */
+    pub(crate) need_resched: AtomicBool,
+    /* Subsystem allocations are charged to, task switches save and restore it (see
+     * stats::scope) */
+    pub(crate) mem_subsystem: AtomicU8,
+    /* now_ns() the deadline interrupt is due at, 0 if none (see time::set_deadline) */
+    pub(crate) deadline: AtomicU64,
+    /* Stack the syscall entry switches to, the same as the TSS rsp0 (see gdt.rs) */
+    pub(crate) kernel_stack: u64,
+    /* User stack pointer while the syscall entry switches stacks */
+    pub(crate) user_rsp: u64,
+    /* Loaded by gdt::init, the CPU marks its TSS busy so every CPU needs its own */
+    pub(crate) gdt: [u64; GDT_ENTRIES],
+    pub(crate) tss: TaskStateSegment,
+}
+
+const PER_CPU_INIT: PerCpu = PerCpu {
+    self_ptr: null_mut(),
+    index: 0,
+    apic_id: 0,
+    online: AtomicBool::new(false),
+    work: AtomicUsize::new(0),
+    current_task: AtomicU64::new(0),
+    prev_task: AtomicU64::new(0),
+    idle_task: AtomicU64::new(0),
+    slice_left: AtomicU64::new(0),
+    switched_at: AtomicU64::new(0),
+    need_resched: AtomicBool::new(false),
+    mem_subsystem: AtomicU8::new(0),
+    deadline: AtomicU64::new(0),
+    kernel_stack: 0,
+    user_rsp: 0,
+    gdt: gdt::GDT_INIT,
+    tss: gdt::TSS_INIT,
+};
+
+// every CPU only writes its own entry after it has been set up
+static mut CPUS: [PerCpu; MAX_CPUS] = [PER_CPU_INIT; MAX_CPUS];
+
+/// APIC id of the calling CPU, works before its local APIC is mapped
+pub fn cpuid_apic_id() -> u8 {
+    unsafe { (__cpuid(1).ebx >> 24) as u8 }
+}
+
+/// Sets up entry `index` for the calling CPU and points GS at it
+pub fn init(index: usize) {
+    unsafe {
+        let cpu = &mut CPUS[index];
+        cpu.self_ptr = cpu;
+        cpu.index = index;
+        cpu.apic_id = cpuid_apic_id();
+
+        wrmsr(IA32_GS_BASE, cpu.self_ptr as u64);
+        /* The GS base of user code while the kernel runs, swapgs exchanges the two */
+        wrmsr(IA32_KERNEL_GS_BASE, 0);
+    }
+}
+
+/// The `PerCpu` of the CPU this runs on
+pub fn current() -> &'static mut PerCpu {
+    unsafe {
+        let cpu: *mut PerCpu;
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        &mut *cpu
+    }
+}
+
+pub fn get(index: usize) -> &'static PerCpu {
+    unsafe { &CPUS[index] }
+}
+
+/// CPUs that are up, including the boot CPU
+pub fn online_count() -> usize {
+    unsafe { CPUS.iter().filter(|cpu| cpu.online.load(Ordering::Acquire)).count() }
+}
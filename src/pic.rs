@@ -1,6 +1,7 @@
 use core::arch::asm;
 
 use crate::qemu_println;
+use crate::sync::IrqSpinlock;
 use crate::tooling::serial::{inb, outb};
 
 const PIC1_CMD: u16 = 0x20;
@@ -24,36 +25,93 @@ const ICW4_SFNM: u8 = 0x10;
 
 const CMD_EOI: u8 = 0x20;
 
+/* The slave PIC is wired to IRQ 2 of the master */
+const CASCADE_IRQ: u8 = 2;
+
+/* IRQ mask of both PICs (slave in the high byte), a set bit masks the line. Everything
+ * but the cascade starts masked, lines are unmasked as handlers get registered with irq.rs */
+static IRQ_MASK: IrqSpinlock<u16> = IrqSpinlock::new(!(1 << CASCADE_IRQ));
+
 pub fn init() {
-    pic_remap(32, 40);
+    pic_remap(crate::irq::IRQ_BASE, crate::irq::IRQ_BASE + 8);
 
     unsafe {
         asm!("sti");
     }
 }
 
+// https://wiki.osdev.org/PIC#Disabling
+// the PICs stay remapped, so a spurious interrupt they might still raise lands on an
+// IRQ vector instead of an exception
+pub fn disable() {
+    *IRQ_MASK.lock() = 0xFFFF;
+    pic_remap(crate::irq::IRQ_BASE, crate::irq::IRQ_BASE + 8);
+}
+
 // theft from https://wiki.osdev.org/PIC
 fn pic_remap(offset1: u8, offset2: u8) {
-    let a = inb(PIC1_DATA);
-    let b = inb(PIC2_DATA);
     outb(PIC1_CMD, ICW1_INIT | ICW1_ICW4);
     outb(PIC2_CMD, ICW1_INIT | ICW1_ICW4);
 
     outb(PIC1_DATA, offset1);
     outb(PIC2_DATA, offset2);
 
-    outb(PIC1_DATA, 4);
-    outb(PIC2_DATA, 2);
+    outb(PIC1_DATA, 1 << CASCADE_IRQ);
+    outb(PIC2_DATA, CASCADE_IRQ);
 
     outb(PIC1_DATA, ICW4_8086);
     outb(PIC2_DATA, ICW4_8086);
 
-    outb(PIC1_DATA, a);
-    outb(PIC2_DATA, b);
+    write_mask(*IRQ_MASK.lock());
+}
+
+/* Called with the mask locked, so that the PICs see the changes in order */
+fn write_mask(mask: u16) {
+    outb(PIC1_DATA, mask as u8);
+    outb(PIC2_DATA, (mask >> 8) as u8);
+}
+
+pub fn mask(irq: u8) {
+    let mut mask = IRQ_MASK.lock();
+    *mask |= 1 << irq;
+    write_mask(*mask);
+}
+
+pub fn unmask(irq: u8) {
+    let mut mask = IRQ_MASK.lock();
+    *mask &= !(1 << irq);
+    write_mask(*mask);
+}
+
+// the slave only gets an EOI for its own lines, the master always (the slave is
+// connected through the cascade line)
+pub fn send_eoi(irq: u8) {
+    if irq >= 8 {
+        outb(PIC2_CMD, CMD_EOI);
+    }
+    outb(PIC1_CMD, CMD_EOI);
+}
+
+// https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+// IRQ 7 and 15 are what the PICs raise when the line that interrupted went away before
+// the CPU acknowledged it. The in-service bit of a real one is set
+pub fn is_spurious(irq: u8) -> bool {
+    (irq == 7 || irq == 15) && pic_get_isr() & (1 << irq) == 0
+}
+
+/// Lines that have been raised but not yet acknowledged
+pub fn pic_get_irr() -> u16 {
+    pic_get_irq_reg(PIC_READ_IRR as u8)
+}
+
+/// Lines that are being serviced
+pub fn pic_get_isr() -> u16 {
+    pic_get_irq_reg(PIC_READ_ISR as u8)
 }
 
+// OCW3 selects which register the next read of the command port returns
 fn pic_get_irq_reg(ocw3: u8) -> u16 {
     outb(PIC1_CMD, ocw3);
     outb(PIC2_CMD, ocw3);
-    return ((inb(PIC1_DATA) as u16) << 8) | (inb(PIC2_DATA) as u16);
+    return ((inb(PIC2_CMD) as u16) << 8) | (inb(PIC1_CMD) as u16);
 }
@@ -0,0 +1,366 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+
+use crate::gdt;
+use crate::mem::frame;
+use crate::mem::memory::{self, phys_to_virt, PageFlags, PAGE_SIZE, PT};
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+use crate::syscall::File;
+use crate::task::{self, JoinHandle, TaskId};
+
+/* User processes. A process is a task that runs in ring 3 with its own address space:
+ * a PML4 whose lower half holds the user mappings and whose upper half is the kernel's,
+ * the kernel pages aren't user accessible. The task enters user mode with `iretq` and
+ * comes back to the kernel on interrupts and exceptions, on the kernel stack of the task
+ * (the TSS rsp0).
+ *
+ * A fault in user code kills the process (see exceptions.rs), its task exits with
+ * KILLED_EXIT_CODE plus the vector. Exiting frees every frame of the address space.
+ *
+ * Besides its address space a process has a heap that grows with `brk` and a table of
+ * open files, used by the system calls (see syscall.rs). */
+
+/// End of the lower half. User mappings stay below its last page, so no instruction
+/// ends at the non-canonical USER_END and `syscall` always returns to a canonical rip
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+/// The user stack grows down from here, the page above stays unmapped
+pub const USER_STACK_TOP: u64 = 0x0000_7FFF_FFFF_F000;
+pub const USER_STACK_PAGES: u64 = 16;
+/// Where the heap (`brk`) starts unless the loader moves it behind the program
+pub const DEFAULT_HEAP_START: u64 = 0x0000_0000_1000_0000;
+/// Exit code of a process killed by an exception, plus the vector
+pub const KILLED_EXIT_CODE: i64 = 128;
+
+// IF and the reserved bit 1
+const USER_RFLAGS: u64 = 0x202;
+
+/// Processes are identified by the id of their task
+pub type Pid = TaskId;
+
+fn check_user_range(virt: u64, pages: u64) -> Result<(), &'static str> {
+    if virt % PAGE_SIZE != 0 {
+        return Err("user address is not page aligned");
+    }
+    let end = pages
+        .checked_mul(PAGE_SIZE)
+        .and_then(|len| virt.checked_add(len))
+        .ok_or("user range overflows")?;
+    if pages == 0 || end > USER_END - PAGE_SIZE {
+        return Err("user range is not in the lower half");
+    }
+    Ok(())
+}
+
+/// The address space of a process. Every page it maps is a frame of its own, they are
+/// freed together with the page tables when it is dropped
+pub struct UserSpace {
+    pml4: u64,
+    /* The heap is [heap_start, brk), mapped in whole pages */
+    heap_start: u64,
+    brk: u64,
+}
+
+impl UserSpace {
+    pub fn new() -> Result<Self, &'static str> {
+        let pml4 = frame::alloc_frame().ok_or("out of memory for a PML4")?;
+        unsafe {
+            PT::new_at(phys_to_virt(pml4));
+            /* The kernel half points at the same PDPTs, which never change */
+            PT::at(pml4).entries[256..].copy_from_slice(&PT::kernel().entries[256..]);
+        }
+        Ok(Self {
+            pml4,
+            heap_start: DEFAULT_HEAP_START,
+            brk: DEFAULT_HEAP_START,
+        })
+    }
+
+    /// Physical address of the PML4, for `task::set_address_space`
+    pub fn pml4(&self) -> u64 {
+        self.pml4
+    }
+
+    fn table(&self) -> &'static mut PT {
+        unsafe { PT::at(self.pml4) }
+    }
+
+    /// Maps `pages` zeroed pages at `virt`, user accessible with `flags`
+    pub fn map(&mut self, virt: u64, pages: u64, flags: PageFlags) -> Result<(), &'static str> {
+        check_user_range(virt, pages)?;
+        for i in 0..pages {
+            let phys = frame::alloc_frame().ok_or("out of memory for user pages")?;
+            unsafe {
+                core::ptr::write_bytes(phys_to_virt(phys) as *mut u8, 0, PAGE_SIZE as usize);
+            }
+            if let Err(err) = self
+                .table()
+                .map_page(virt + i * PAGE_SIZE, phys, flags | PageFlags::USER)
+            {
+                frame::free_frame(phys);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps `pages` pages starting at `virt` and frees their frames, pages that aren't
+    /// mapped are skipped
+    pub fn unmap(&mut self, virt: u64, pages: u64) -> Result<(), &'static str> {
+        check_user_range(virt, pages)?;
+        for i in 0..pages {
+            let page = virt + i * PAGE_SIZE;
+            if let Some(phys) = self.translate(page) {
+                self.table().unmap(page, 1)?;
+                frame::free_frame(phys);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the flags of `pages` mapped pages starting at `virt`
+    pub fn protect(&mut self, virt: u64, pages: u64, flags: PageFlags) -> Result<(), &'static str> {
+        check_user_range(virt, pages)?;
+        self.table().protect(virt, pages, flags | PageFlags::USER)
+    }
+
+    /// Physical address of the user address `virt`
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        if virt >= USER_END {
+            return None;
+        }
+        self.table().translate(virt)
+    }
+
+    /// Copies `data` to `virt` in this address space, whichever one is active. The pages
+    /// have to be mapped, their flags don't matter
+    pub fn write(&mut self, virt: u64, data: &[u8]) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < data.len() {
+            let addr = virt + done as u64;
+            let phys = self.translate(addr).ok_or("user address is not mapped")?;
+            let chunk = ((PAGE_SIZE - addr % PAGE_SIZE) as usize).min(data.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[done..].as_ptr(),
+                    phys_to_virt(phys) as *mut u8,
+                    chunk,
+                );
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Maps a stack of `pages` pages below USER_STACK_TOP, returns its top
+    pub fn map_stack(&mut self, pages: u64) -> Result<u64, &'static str> {
+        let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+        self.map(USER_STACK_TOP - pages * PAGE_SIZE, pages, flags)?;
+        Ok(USER_STACK_TOP)
+    }
+
+    /// Moves the heap to start at `start` (page aligned), only before it grew
+    pub fn set_heap_start(&mut self, start: u64) -> Result<(), &'static str> {
+        if self.brk != self.heap_start {
+            return Err("the heap is already in use");
+        }
+        check_user_range(start, 1)?;
+        self.heap_start = start;
+        self.brk = start;
+        Ok(())
+    }
+
+    /// End of the heap
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+
+    /// Moves the end of the heap to `end`, new pages are zeroed. The heap can't grow into
+    /// the stack area
+    pub fn set_brk(&mut self, end: u64) -> Result<u64, &'static str> {
+        if end < self.heap_start || end > USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE {
+            return Err("break outside of the heap area");
+        }
+        let page_up = |addr: u64| (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let (old_top, new_top) = (page_up(self.brk), page_up(end));
+
+        if new_top > old_top {
+            let pages = (new_top - old_top) / PAGE_SIZE;
+            let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+            if let Err(err) = self.map(old_top, pages, flags) {
+                /* Take back the pages that did get mapped */
+                self.unmap(old_top, pages)?;
+                return Err(err);
+            }
+        } else if new_top < old_top {
+            self.unmap(new_top, (old_top - new_top) / PAGE_SIZE)?;
+        }
+        self.brk = end;
+        Ok(end)
+    }
+}
+
+impl Drop for UserSpace {
+    fn drop(&mut self) {
+        assert!(
+            unsafe { memory::get_cr3() } & !(PAGE_SIZE - 1) != self.pml4,
+            "dropping the active address space"
+        );
+        unsafe {
+            self.table().free_lower_half();
+        }
+        frame::free_frame(self.pml4);
+    }
+}
+
+pub struct Process {
+    pub name: &'static str,
+    pub space: UserSpace,
+    /// Indexed by file descriptor, 0-2 are stdin, stdout and stderr
+    pub files: Vec<Option<File>>,
+}
+
+impl Process {
+    fn new(name: &'static str, space: UserSpace) -> Self {
+        Self {
+            name,
+            space,
+            files: vec![Some(File::Stdin), Some(File::Stdout), Some(File::Stdout)],
+        }
+    }
+}
+
+static PROCESSES: IrqSpinlock<BTreeMap<Pid, Process>> = IrqSpinlock::new(BTreeMap::new());
+
+// enter_user(entry: u64, stack: u64) -> !
+// leaves the kernel for ring 3 at `entry` with the stack pointer at `stack` and all other
+// registers 0. The GS base is swapped like on a return from an interrupt
+global_asm!(
+    r#"
+.global enter_user
+enter_user:
+    cli
+    swapgs
+    push {user_data}
+    push rsi
+    push {rflags}
+    push {user_code}
+    push rdi
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    iretq
+"#,
+    user_data = const gdt::USER_DATA_SELECTOR as u64,
+    user_code = const gdt::USER_CODE_SELECTOR as u64,
+    rflags = const USER_RFLAGS,
+);
+
+extern "C" {
+    fn enter_user(entry: u64, stack: u64) -> !;
+}
+
+/// Starts a process that runs in `space` from `entry` with the stack pointer at
+/// `stack_top`. Join the handle for the exit code
+pub fn spawn(
+    name: &'static str,
+    space: UserSpace,
+    entry: u64,
+    stack_top: u64,
+) -> Result<JoinHandle, &'static str> {
+    if entry >= USER_END - PAGE_SIZE || stack_top > USER_END - PAGE_SIZE {
+        return Err("process entry or stack is not in the lower half");
+    }
+    task::spawn(name, move || {
+        let pml4 = space.pml4();
+        /* Registered by the task itself, it could be killed before `spawn` returns */
+        PROCESSES
+            .lock()
+            .insert(task::current_id(), Process::new(name, space));
+        task::set_address_space(pml4);
+        unsafe { enter_user(entry, stack_top) }
+    })
+}
+
+/// Name of the process `pid`, None if there is no such process
+pub fn name(pid: Pid) -> Option<&'static str> {
+    PROCESSES.lock().get(&pid).map(|process| process.name)
+}
+
+/// Runs `f` on the process of the calling task, None if the task isn't a process.
+/// Interrupts are off while `f` runs, it must not block
+pub fn with_current<T>(f: impl FnOnce(&mut Process) -> T) -> Option<T> {
+    PROCESSES.lock().get_mut(&task::current_id()).map(f)
+}
+
+/// Ends the calling process: frees its address space and exits its task with `code`
+pub fn exit(code: i64) -> ! {
+    task::set_address_space(memory::kernel_pml4());
+    let process = PROCESSES.lock().remove(&task::current_id());
+    drop(process);
+    task::exit(code);
+}
+
+/// Runs `code` at 0x400000 as a process and checks that it exits with the code
+/// `expected` returns for its pid, and gives back all its frames. For self tests
+#[cfg(feature = "self-test")]
+pub fn run_test_program(
+    code: &[u8],
+    expected: impl FnOnce(Pid) -> i64,
+) -> Result<(), &'static str> {
+    const CODE_ADDR: u64 = 0x40_0000;
+
+    let mut space = UserSpace::new()?;
+    space.map(CODE_ADDR, 1, PageFlags::WRITABLE)?;
+    space.write(CODE_ADDR, code)?;
+    space.protect(CODE_ADDR, 1, PageFlags::empty())?;
+    let stack = space.map_stack(1)?;
+
+    let free = frame::free_frames();
+    let process = spawn("user test", space, CODE_ADDR, stack)?;
+    let expected = expected(process.id());
+    let exit_code = process.join();
+    if exit_code != expected {
+        qemu_println!("user test exited with {}, expected {}", exit_code, expected);
+        return Err("user test program ended the wrong way");
+    }
+    /* Freeing the address space gave back more frames than the task took */
+    if frame::free_frames() < free {
+        return Err("user address space leaked frames");
+    }
+    Ok(())
+}
+
+/// Runs at boot after `task::init`: user code can use its own pages, and touching
+/// kernel memory or an invalid instruction kills the process rather than the kernel
+#[cfg(feature = "self-test")]
+pub fn self_test() -> Result<(), &'static str> {
+    // push rax; mov rax, [0xFFFF800000000000]
+    let mut read_kernel = [0x50, 0x48, 0xA1, 0, 0, 0, 0, 0, 0, 0, 0];
+    read_kernel[3..].copy_from_slice(&memory::PHYS_MAP_BASE.to_le_bytes());
+    run_test_program(&read_kernel, |_| {
+        KILLED_EXIT_CODE + crate::exceptions::PAGE_FAULT as i64
+    })?;
+
+    // ud2
+    let invalid_opcode = [0x0F, 0x0B];
+    run_test_program(&invalid_opcode, |_| {
+        KILLED_EXIT_CODE + crate::exceptions::INVALID_OPCODE as i64
+    })?;
+    qemu_println!("user mode test passed");
+    Ok(())
+}
@@ -0,0 +1,320 @@
+use core::arch::{asm, global_asm};
+use core::sync::atomic::Ordering;
+
+use crate::apic::{self, LAPIC_ICR_HIGH, LAPIC_ICR_LOW};
+use crate::bord::load_idt;
+use crate::gdt;
+use crate::mem::memory::{get_cr3, phys_to_virt, PageFlags, PAGE_SIZE, PT};
+use crate::mem::stack;
+use crate::percpu;
+use crate::qemu_println;
+use crate::time;
+
+/* Application processor bring-up. APs start in real mode at the page the startup IPI
+ * names, so a small trampoline is copied to low memory. It loads a temporary GDT, turns
+ * on long mode with the kernel page tables and jumps to `ap_main` on the stack the boot
+ * CPU allocated for it. While APs are started the trampoline page is identity mapped,
+ * the instruction after enabling paging is still fetched from it.
+ *
+ * Started APs set up their GDT/TSS, per-CPU data and local APIC and then sleep in
+ * `idle_loop` until `run_on` hands them a function, which the wakeup IPI tells them
+ * about. They don't run tasks, the scheduler only runs on the boot CPU. */
+
+/* Physical address of the trampoline, below 1 MiB and page aligned */
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+const AP_STACK_PAGES: u64 = 16;
+/// Wakes a halted CPU so it rechecks what it waits for
+pub const WAKEUP_VECTOR: u8 = 0xF0;
+
+/* ICR delivery modes */
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_FIXED: u32 = 0;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+// https://wiki.osdev.org/SMP
+// The trampoline is assembled into .rodata and copied to TRAMPOLINE_ADDR, addresses in it
+// are computed relative to that
+global_asm!(
+    r#"
+.section .rodata
+.set TRAMPOLINE_ADDR, 0x8000
+.global trampoline_start
+.global trampoline_end
+.global trampoline_params
+
+.code16
+trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+
+    lgdt [TRAMPOLINE_GDTR]
+
+    /* PAE and PGE */
+    mov eax, cr4
+    or eax, (1 << 5) | (1 << 7)
+    mov cr4, eax
+
+    mov eax, [TRAMPOLINE_CR3]
+    mov cr3, eax
+
+    /* Long mode and NX in EFER */
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    /* Protection and paging in one go, straight into long mode */
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+
+    /* ljmp 0x08:trampoline_64 */
+    .byte 0x66, 0xEA
+    .long trampoline_64 - trampoline_start + TRAMPOLINE_ADDR
+    .word 0x08
+
+.code64
+trampoline_64:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [TRAMPOLINE_STACK]
+    mov rdi, [TRAMPOLINE_CPU]
+    xor rbp, rbp
+    mov rax, [TRAMPOLINE_ENTRY]
+    call rax
+    ud2
+
+.balign 8
+trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+trampoline_gdtr:
+    .word 3 * 8 - 1
+    .long trampoline_gdt - trampoline_start + TRAMPOLINE_ADDR
+
+.balign 8
+/* Filled in by `start_ap`, see TrampolineParams */
+trampoline_params:
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad 0
+trampoline_end:
+
+/* Where things end up after the copy */
+.set TRAMPOLINE_GDTR, trampoline_gdtr - trampoline_start + TRAMPOLINE_ADDR
+.set TRAMPOLINE_CR3, trampoline_params - trampoline_start + TRAMPOLINE_ADDR
+.set TRAMPOLINE_STACK, TRAMPOLINE_CR3 + 8
+.set TRAMPOLINE_ENTRY, TRAMPOLINE_CR3 + 16
+.set TRAMPOLINE_CPU, TRAMPOLINE_CR3 + 24
+.text
+"#
+);
+
+extern "C" {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+    static trampoline_params: u8;
+}
+
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+fn send_ipi(apic_id: u8, low: u32) {
+    apic::lapic_write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+    apic::lapic_write(LAPIC_ICR_LOW, low);
+    while apic::lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Starts every AP in the MADT, returns how many CPUs are online afterwards
+pub fn init() -> usize {
+    let bsp = apic::lapic_id();
+    percpu::current().online.store(true, Ordering::Release);
+
+    unsafe {
+        let start = core::ptr::addr_of!(trampoline_start);
+        let size = core::ptr::addr_of!(trampoline_end) as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, phys_to_virt(TRAMPOLINE_ADDR) as *mut u8, size);
+
+        let flags = PageFlags::WRITABLE;
+        PT::from_cr3()
+            .map_page(TRAMPOLINE_ADDR, TRAMPOLINE_ADDR, flags)
+            .unwrap();
+    }
+
+    let mut index = 1;
+    for cpu in apic::cpus() {
+        if cpu.apic_id == bsp {
+            continue;
+        }
+        match start_ap(index, cpu.apic_id) {
+            Ok(()) => index += 1,
+            Err(err) => qemu_println!("smp: cpu with APIC id {}: {}", cpu.apic_id, err),
+        }
+    }
+
+    unsafe {
+        PT::from_cr3().unmap(TRAMPOLINE_ADDR, 1).unwrap();
+    }
+    qemu_println!("smp: {} cpus online", cpu_count());
+    cpu_count()
+}
+
+fn start_ap(index: usize, apic_id: u8) -> Result<(), &'static str> {
+    if index >= apic::MAX_CPUS {
+        return Err("too many cpus");
+    }
+    let stack = stack::alloc("ap idle", AP_STACK_PAGES)?;
+
+    unsafe {
+        let offset = core::ptr::addr_of!(trampoline_params) as u64
+            - core::ptr::addr_of!(trampoline_start) as u64;
+        let params = phys_to_virt(TRAMPOLINE_ADDR + offset) as *mut TrampolineParams;
+        params.write_volatile(TrampolineParams {
+            cr3: get_cr3(),
+            stack_top: stack.top(),
+            entry: ap_main as *const () as u64,
+            cpu: index as u64,
+        });
+    }
+
+    // INIT, then two startup IPIs with the page number of the trampoline
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+    time::sleep(10);
+    for _ in 0..2 {
+        send_ipi(
+            apic_id,
+            ICR_STARTUP | ICR_ASSERT | (TRAMPOLINE_ADDR / PAGE_SIZE) as u32,
+        );
+        time::sleep(1);
+    }
+
+    let deadline = time::get_millis() + 100;
+    while !percpu::get(index).online.load(Ordering::Acquire) {
+        if time::get_millis() >= deadline {
+            /* The stack stays allocated, the AP might still show up and use it */
+            return Err("didn't come up");
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+extern "C" fn ap_main(index: u64) -> ! {
+    let index = index as usize;
+    load_idt(&crate::IDTX);
+    percpu::init(index);
+    gdt::init();
+    crate::syscall::init();
+    apic::init_local();
+
+    percpu::current().online.store(true, Ordering::Release);
+    idle_loop();
+}
+
+// checks for work with interrupts off, `sti; hlt` only lets the wakeup IPI in once the
+// CPU is halted, so it can't get lost in between
+fn idle_loop() -> ! {
+    let cpu = percpu::current();
+    loop {
+        unsafe {
+            asm!("cli");
+        }
+        let work = cpu.work.swap(0, Ordering::AcqRel);
+        if work != 0 {
+            unsafe {
+                asm!("sti");
+                let f: fn() = core::mem::transmute(work);
+                f();
+            }
+            continue;
+        }
+        unsafe {
+            asm!("sti", "hlt");
+        }
+    }
+}
+
+/// Lets the idle CPU `index` run `f`. Fails if the CPU isn't online or hasn't picked up
+/// its last work yet. `f` runs outside of any task, it can't block on task functions
+pub fn run_on(index: usize, f: fn()) -> Result<(), &'static str> {
+    if index >= apic::MAX_CPUS || !percpu::get(index).online.load(Ordering::Acquire) {
+        return Err("cpu is not online");
+    }
+    if index == percpu::current().index {
+        return Err("can't hand work to the calling cpu");
+    }
+
+    let cpu = percpu::get(index);
+    cpu.work
+        .compare_exchange(0, f as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| "cpu already has work queued")?;
+    send_ipi(cpu.apic_id, ICR_FIXED | ICR_ASSERT | WAKEUP_VECTOR as u32);
+    Ok(())
+}
+
+#[cfg(feature = "self-test")]
+static TEST_RUNS: crate::sync::Counter = crate::sync::Counter::new(0);
+
+/// Runs at boot after `init`: every AP runs the work handed to it
+#[cfg(feature = "self-test")]
+pub fn self_test() -> Result<(), &'static str> {
+    fn count() {
+        TEST_RUNS.inc();
+    }
+
+    let mut expected = 0;
+    for index in 1..apic::MAX_CPUS {
+        if percpu::get(index).online.load(Ordering::Acquire) {
+            run_on(index, count)?;
+            expected += 1;
+        }
+    }
+
+    let deadline = time::get_millis() + 100;
+    while TEST_RUNS.get() < expected {
+        if time::get_millis() >= deadline {
+            return Err("an AP didn't run its work");
+        }
+        core::hint::spin_loop();
+    }
+    qemu_println!("smp test passed");
+    Ok(())
+}
+
+/// Wakes CPU `index` from `hlt` so it rechecks whatever it waits for
+pub fn wake(index: usize) {
+    if index >= apic::MAX_CPUS || index == percpu::current().index {
+        return;
+    }
+    let cpu = percpu::get(index);
+    if cpu.online.load(Ordering::Acquire) {
+        send_ipi(cpu.apic_id, ICR_FIXED | ICR_ASSERT | WAKEUP_VECTOR as u32);
+    }
+}
+
+pub fn cpu_count() -> usize {
+    percpu::online_count()
+}
+
+pub extern "x86-interrupt" fn wakeup_handler(_isf: crate::handlers::InterruptStackFrame) {
+    apic::send_eoi();
+}
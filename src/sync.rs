@@ -0,0 +1,343 @@
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+/* Locks and cells for globals shared between CPUs and interrupt handlers.
+ *
+ * `Spinlock` is for data only normal code touches. Data an interrupt handler touches too
+ * needs `IrqSpinlock`, which keeps interrupts off on the local CPU while it is held; with
+ * a plain spinlock the handler would spin forever on a lock the code it interrupted holds.
+ * Neither lock is reentrant, don't call back into code that takes the same lock.
+ *
+ * `Once` and `Lazy` hold values that are set up once and only read afterwards, `Counter`
+ * is a plain atomic counter. */
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags, options(preserves_flags));
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+/// Runs `f` with interrupts off on this CPU, they are turned back on only if they were on
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let enabled = interrupts_enabled();
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+    let result = f();
+    if enabled {
+        unsafe {
+            asm!("sti", options(nomem, nostack));
+        }
+    }
+    result
+}
+
+pub struct Spinlock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Spinlock<T> {}
+unsafe impl<T: Send> Send for Spinlock<T> {}
+
+pub struct SpinlockGuard<'a, T> {
+    lock: &'a Spinlock<T>,
+}
+
+impl<T> Spinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            /* Wait with plain loads, the cache line isn't bounced between CPUs */
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinlockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// The data without locking, for when nothing else can be holding the lock
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+pub struct IrqSpinlock<T> {
+    inner: Spinlock<T>,
+}
+
+pub struct IrqSpinlockGuard<'a, T> {
+    /* Dropped before interrupts are turned back on */
+    guard: Option<SpinlockGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: Spinlock::new(data),
+        }
+    }
+
+    /// Turns interrupts off and takes the lock, interrupts are restored when the guard
+    /// is dropped
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let enabled = interrupts_enabled();
+        unsafe {
+            asm!("cli", options(nomem, nostack));
+        }
+        IrqSpinlockGuard {
+            guard: Some(self.inner.lock()),
+            interrupts_were_enabled: enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let enabled = interrupts_enabled();
+        unsafe {
+            asm!("cli", options(nomem, nostack));
+        }
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinlockGuard {
+                guard: Some(guard),
+                interrupts_were_enabled: enabled,
+            }),
+            None => {
+                if enabled {
+                    unsafe {
+                        asm!("sti", options(nomem, nostack));
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard = None;
+        if self.interrupts_were_enabled {
+            unsafe {
+                asm!("sti", options(nomem, nostack));
+            }
+        }
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is set once, the first caller of `call_once` runs the initializer and
+/// everyone else waits for it
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            unsafe {
+                (*self.value.get()).write(f());
+            }
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+        /* An initializer that calls `call_once` on the same cell ends up here forever */
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            core::hint::spin_loop();
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// The value, if it has been set
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe {
+                self.value.get_mut().assume_init_drop();
+            }
+        }
+    }
+}
+
+/// A value computed by `init` the first time it is used
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: F,
+}
+
+unsafe impl<T: Send + Sync, F: Sync> Sync for Lazy<T, F> {}
+
+impl<T, F: Fn() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init,
+        }
+    }
+
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| (this.init)())
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+/// A counter that can be bumped from any CPU or interrupt handler without a lock
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new(value: u64) -> Self {
+        Self(AtomicU64::new(value))
+    }
+
+    /// Adds one and returns the new value
+    pub fn inc(&self) -> u64 {
+        self.add(1)
+    }
+
+    /// Adds `n` and returns the new value
+    pub fn add(&self, n: u64) -> u64 {
+        self.0.fetch_add(n, Ordering::Relaxed) + n
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+/// Runs at boot, checks the primitives work before anything relies on them
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let lock = Spinlock::new(1);
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(*lock.lock() == 2);
+
+    let enabled = interrupts_enabled();
+    let irq_lock = IrqSpinlock::new(0);
+    {
+        let _guard = irq_lock.lock();
+        assert!(!interrupts_enabled());
+        assert!(irq_lock.try_lock().is_none());
+    }
+    assert!(interrupts_enabled() == enabled);
+
+    let once = Once::new();
+    assert!(once.get().is_none());
+    assert!(*once.call_once(|| 5) == 5);
+    assert!(*once.call_once(|| 6) == 5);
+
+    let counter = Counter::new(0);
+    assert!(counter.inc() == 1 && counter.add(2) == 3 && counter.get() == 3);
+}
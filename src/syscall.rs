@@ -0,0 +1,590 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+
+use crate::fat32::{self, FsError};
+use crate::gdt;
+use crate::input::keyboard;
+use crate::mem::memory::{rdmsr, wrmsr, PageFlags, PAGE_SIZE, PT};
+use crate::percpu::PerCpu;
+use crate::process::{self, USER_END};
+use crate::qemu_println;
+use crate::task;
+use crate::time;
+use crate::tooling::errno::Errno::{self, *};
+use crate::tooling::qemu_io::qemu_print_bytes;
+
+/* System calls. User code enters the kernel with `syscall`, or with `int 0x80` where
+ * that is more convenient. Both take the same registers:
+ *
+ *   rax                          system call number
+ *   rdi, rsi, rdx, r10, r8, r9   arguments
+ *   rax                          result, -errno on failure (tooling/errno.rs)
+ *
+ * `syscall` clobbers rcx and r11, all other registers are preserved. The numbers are
+ * the ABI, they never change and new calls get new numbers:
+ *
+ *   0  read(fd, buf, len) -> bytes read, 0 at the end of a file
+ *   1  write(fd, buf, len) -> bytes written
+ *   2  open(path, flags) -> fd. `path` is NUL terminated, flags are O_*
+ *   3  close(fd) -> 0
+ *   4  exit(code), doesn't return
+ *   5  sleep(ms) -> 0
+ *   6  getpid() -> pid
+ *   7  brk(end) -> end of the heap. brk(0) only returns it
+ *
+ * fd 0 reads characters typed on the keyboard, one per call; 1 and 2 write to the serial
+ * port. Files are read whole when they are opened, writes go to the end of the file. Open
+ * and write fail with ENOMEM if the copy of the file doesn't fit the kernel heap.
+ *
+ * Every pointer a process passes is checked against its page tables before the kernel
+ * touches it, a bad one fails the call with EFAULT. */
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_OPEN: u64 = 2;
+pub const SYS_CLOSE: u64 = 3;
+pub const SYS_EXIT: u64 = 4;
+pub const SYS_SLEEP: u64 = 5;
+pub const SYS_GETPID: u64 = 6;
+pub const SYS_BRK: u64 = 7;
+
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_CREAT: u64 = 0x40;
+const O_ACCMODE: u64 = 3;
+
+/// The vector of `int 0x80`
+pub const INT_VECTOR: u8 = 0x80;
+
+const PATH_MAX: usize = 256;
+const MAX_FILES: usize = 32;
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+const EFER_SCE: u64 = 1 << 0;
+// TF, IF, DF and AC are cleared on entry
+const SYSCALL_FMASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+// SYSRET takes SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16
+const _: () = assert!(gdt::USER_CODE_SELECTOR == gdt::USER_DATA_SELECTOR + 8);
+
+/// A file a process has open
+pub enum File {
+    Stdin,
+    Stdout,
+    Disk {
+        path: String,
+        data: Vec<u8>,
+        pos: usize,
+        readable: bool,
+        writable: bool,
+    },
+}
+
+/// What the entries push, lowest address first. The same for both, `syscall_entry`
+/// builds the part the CPU pushes on `int 0x80`
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// SYSCALL leaves the user rip in rcx, rflags in r11 and doesn't switch stacks. The entry
+// switches to the task's kernel stack (percpu `kernel_stack`) and builds an interrupt
+// frame there, so the rest of the kernel can't tell it from `int 0x80`. SYSRET with a
+// non-canonical rip faults in ring 0, after the switch to the user stack and GS, so
+// such a return goes through `iretq` instead, which faults in user mode
+global_asm!(
+    r#"
+.macro push_registers
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+.endm
+
+.macro pop_registers
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+.endm
+
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_rsp}], rsp
+    mov rsp, gs:[{kernel_stack}]
+    push {user_data}
+    push qword ptr gs:[{user_rsp}]
+    push r11
+    push {user_code}
+    push rcx
+    push_registers
+
+    mov rdi, rsp
+    call syscall_dispatch
+
+    pop_registers
+    /* rip, cs, rflags, rsp. rcx and r11 are clobbered either way */
+    mov rcx, [rsp]
+    movabs r11, {user_end}
+    cmp rcx, r11
+    jae 2f
+    mov r11, [rsp + 16]
+    mov rsp, [rsp + 24]
+    swapgs
+    sysretq
+2:
+    swapgs
+    iretq
+
+.global syscall_int_entry
+syscall_int_entry:
+    test byte ptr [rsp + 8], 3
+    jz 1f
+    swapgs
+1:
+    push_registers
+
+    /* The CPU frame and 15 registers keep the stack aligned */
+    mov rdi, rsp
+    call syscall_dispatch
+
+    pop_registers
+    test byte ptr [rsp + 8], 3
+    jz 1f
+    swapgs
+1:
+    iretq
+"#,
+    user_rsp = const core::mem::offset_of!(PerCpu, user_rsp),
+    kernel_stack = const core::mem::offset_of!(PerCpu, kernel_stack),
+    user_data = const gdt::USER_DATA_SELECTOR as u64,
+    user_code = const gdt::USER_CODE_SELECTOR as u64,
+    user_end = const USER_END,
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_int_entry();
+}
+
+/// Address of the `int 0x80` entry, for the IDT. The gate needs DPL 3
+pub fn int_entry() -> u64 {
+    syscall_int_entry as *const () as u64
+}
+
+/// Turns on SYSCALL/SYSRET on the calling CPU
+pub fn init() {
+    let star = ((gdt::USER_DATA_SELECTOR as u64 - 8) << 48)
+        | ((gdt::KERNEL_CODE_SELECTOR as u64) << 32);
+    unsafe {
+        wrmsr(IA32_STAR, star);
+        wrmsr(IA32_LSTAR, syscall_entry as *const () as u64);
+        wrmsr(IA32_FMASK, SYSCALL_FMASK);
+        wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_SCE);
+    }
+}
+
+type Handler = fn(&[u64; 6]) -> Result<u64, Errno>;
+
+/* Indexed by system call number */
+const SYSCALLS: [Handler; 8] = [
+    sys_read, sys_write, sys_open, sys_close, sys_exit, sys_sleep, sys_getpid, sys_brk,
+];
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    /* Both entries come in with interrupts off, system calls may block and take long */
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    let result = match SYSCALLS.get(frame.rax as usize) {
+        Some(handler) => handler(&args),
+        None => Err(ENOSYS),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => errno.to_return(),
+    };
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+}
+
+// checks that the calling process may access [addr, addr + len), and write to it with
+// `write`. It is single threaded, nothing can unmap the range while the call runs
+fn check_user(addr: u64, len: u64, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(EFAULT)?;
+    if end > USER_END {
+        return Err(EFAULT);
+    }
+    let pml4 = unsafe { PT::from_cr3() };
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let flags = pml4.flags(page).ok_or(EFAULT)?;
+        if !flags.contains(PageFlags::USER) || (write && !flags.contains(PageFlags::WRITABLE)) {
+            return Err(EFAULT);
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+fn user_slice(addr: u64, len: u64) -> Result<&'static [u8], Errno> {
+    check_user(addr, len, false)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+fn user_slice_mut(addr: u64, len: u64) -> Result<&'static mut [u8], Errno> {
+    check_user(addr, len, true)?;
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+// a NUL terminated string of at most `max` bytes. Every page is checked once and copied
+// up to the NUL in one go
+fn user_str(addr: u64, max: usize) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    loop {
+        let start = addr + bytes.len() as u64;
+        /* The NUL may follow the last allowed byte */
+        let left = (max - bytes.len()) as u64 + 1;
+        let chunk = user_slice(start, (PAGE_SIZE - start % PAGE_SIZE).min(left))?;
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(len) => {
+                bytes.extend_from_slice(&chunk[..len]);
+                break;
+            }
+            None if chunk.len() as u64 == left => return Err(ENAMETOOLONG),
+            None => bytes.extend_from_slice(chunk),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| EINVAL)
+}
+
+fn current_process<T>(f: impl FnOnce(&mut process::Process) -> T) -> Result<T, Errno> {
+    process::with_current(f).ok_or(ESRCH)
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound | FsError::NotMounted => ENOENT,
+            FsError::IsDirectory => EISDIR,
+            FsError::NotDirectory => ENOTDIR,
+            FsError::Exists => EEXIST,
+            FsError::DirectoryFull => ENOSPC,
+            FsError::InvalidName => EINVAL,
+            FsError::NotFat32 | FsError::AlreadyMounted => EIO,
+        }
+    }
+}
+
+fn sys_read(args: &[u64; 6]) -> Result<u64, Errno> {
+    let (fd, buf, len) = (args[0] as usize, args[1], args[2]);
+    let buf = user_slice_mut(buf, len)?;
+    /* None for stdin, which blocks */
+    let read = current_process(|process| match process.files.get_mut(fd) {
+        Some(Some(File::Stdin)) => Ok(None),
+        Some(Some(File::Disk {
+            data,
+            pos,
+            readable: true,
+            ..
+        })) => {
+            let count = buf.len().min(data.len() - *pos);
+            buf[..count].copy_from_slice(&data[*pos..*pos + count]);
+            *pos += count;
+            Ok(Some(count as u64))
+        }
+        _ => Err(EBADF),
+    })??;
+    if let Some(count) = read {
+        return Ok(count);
+    }
+
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    loop {
+        if let Some(c) = keyboard::to_ascii(keyboard::sleep_until_input()) {
+            buf[0] = c;
+            return Ok(1);
+        }
+    }
+}
+
+fn sys_write(args: &[u64; 6]) -> Result<u64, Errno> {
+    let (fd, buf, len) = (args[0] as usize, args[1], args[2]);
+    let buf = user_slice(buf, len)?;
+    let path = current_process(|process| match process.files.get_mut(fd) {
+        Some(Some(File::Stdout)) => Ok(None),
+        Some(Some(File::Disk {
+            path,
+            data,
+            writable: true,
+            ..
+        })) => {
+            /* Room to append to the open copy, before anything goes to the disk */
+            data.try_reserve(buf.len()).map_err(|_| ENOMEM)?;
+            Ok(Some(path.clone()))
+        }
+        _ => Err(EBADF),
+    })??;
+
+    let Some(path) = path else {
+        qemu_print_bytes(buf);
+        return Ok(buf.len() as u64);
+    };
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    /* The disk isn't touched with interrupts off, the file may be closed meanwhile */
+    fat32::with_filesystem(|fs| fs.write_file(&path, buf, buf.len()))?;
+    current_process(|process| {
+        if let Some(Some(File::Disk { data, .. })) = process.files.get_mut(fd) {
+            data.extend_from_slice(buf);
+        }
+    })?;
+    Ok(buf.len() as u64)
+}
+
+fn sys_open(args: &[u64; 6]) -> Result<u64, Errno> {
+    let (path, flags) = (args[0], args[1]);
+    let path = user_str(path, PATH_MAX)?;
+    let path = String::from(path.trim_start_matches('/'));
+    let mode = flags & O_ACCMODE;
+    if mode > O_RDWR || flags & !(O_ACCMODE | O_CREAT) != 0 || path.is_empty() {
+        return Err(EINVAL);
+    }
+
+    let data = fat32::with_filesystem(|fs| -> Result<Vec<u8>, Errno> {
+        let size = match fs.get_file_size(&path) {
+            Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+                let (directory, name) = path.rsplit_once('/').unwrap_or(("", &path));
+                fs.create_file(directory, name)?;
+                0
+            }
+            size => size?,
+        };
+        /* The file is read whole, one too large for the heap fails the call */
+        let mut data = Vec::new();
+        data.try_reserve_exact(size as usize).map_err(|_| ENOMEM)?;
+        data.resize(size as usize, 0);
+        fs.read_file(&path, &mut data, size as usize)?;
+        Ok(data)
+    })?;
+
+    let file = File::Disk {
+        path,
+        data,
+        pos: 0,
+        readable: mode != O_WRONLY,
+        writable: mode != O_RDONLY,
+    };
+    current_process(|process| {
+        let files = &mut process.files;
+        let fd = match files.iter().position(|file| file.is_none()) {
+            Some(fd) => fd,
+            None if files.len() < MAX_FILES => {
+                files.push(None);
+                files.len() - 1
+            }
+            None => return Err(EMFILE),
+        };
+        files[fd] = Some(file);
+        Ok(fd as u64)
+    })?
+}
+
+fn sys_close(args: &[u64; 6]) -> Result<u64, Errno> {
+    let fd = args[0] as usize;
+    current_process(|process| match process.files.get_mut(fd) {
+        Some(file @ Some(_)) => {
+            *file = None;
+            Ok(0)
+        }
+        _ => Err(EBADF),
+    })?
+}
+
+fn sys_exit(args: &[u64; 6]) -> Result<u64, Errno> {
+    current_process(|_| ())?;
+    process::exit(args[0] as i64);
+}
+
+fn sys_sleep(args: &[u64; 6]) -> Result<u64, Errno> {
+    time::sleep(args[0]);
+    Ok(0)
+}
+
+fn sys_getpid(_args: &[u64; 6]) -> Result<u64, Errno> {
+    current_process(|_| task::current_id())
+}
+
+fn sys_brk(args: &[u64; 6]) -> Result<u64, Errno> {
+    let end = args[0];
+    current_process(|process| {
+        if end == 0 {
+            return Ok(process.space.brk());
+        }
+        process.space.set_brk(end).map_err(|_| ENOMEM)
+    })?
+}
+
+// a user program that goes through the ABI, see `self_test`
+#[cfg(feature = "self-test")]
+global_asm!(
+    r#"
+.section .rodata
+.global syscall_test_program
+.global syscall_test_program_end
+syscall_test_program:
+    /* write(1, message, 8) */
+    mov eax, {write}
+    mov edi, 1
+    lea rsi, [rip + syscall_test_message]
+    mov edx, 8
+    syscall
+    cmp rax, 8
+    jne 9f
+
+    /* A kernel address fails with EFAULT */
+    mov eax, {write}
+    mov edi, 1
+    movabs rsi, 0xFFFF800000000000
+    mov edx, 1
+    syscall
+    cmp rax, -14
+    jne 9f
+
+    /* Unknown numbers fail with ENOSYS */
+    mov eax, 1000
+    syscall
+    cmp rax, -38
+    jne 9f
+
+    /* Grow the heap by a page and use it */
+    mov eax, {brk}
+    xor edi, edi
+    syscall
+    mov rbx, rax
+    lea rdi, [rbx + 4096]
+    mov eax, {brk}
+    syscall
+    lea rcx, [rbx + 4096]
+    cmp rax, rcx
+    jne 9f
+    mov qword ptr [rbx], 42
+
+    /* exit(getpid()), through int 0x80 */
+    mov eax, {getpid}
+    int 0x80
+    mov rdi, rax
+    mov eax, {exit}
+    syscall
+
+9:
+    mov rdi, -1
+    mov eax, {exit}
+    syscall
+
+syscall_test_message:
+    .ascii "syscall\n"
+syscall_test_program_end:
+.text
+"#,
+    write = const SYS_WRITE,
+    brk = const SYS_BRK,
+    getpid = const SYS_GETPID,
+    exit = const SYS_EXIT,
+);
+
+#[cfg(feature = "self-test")]
+extern "C" {
+    static syscall_test_program: u8;
+    static syscall_test_program_end: u8;
+}
+
+/// Runs at boot after `process::self_test`: a process that calls through both entries
+/// gets its results and errors back
+#[cfg(feature = "self-test")]
+pub fn self_test() -> Result<(), &'static str> {
+    let code = unsafe {
+        let start = core::ptr::addr_of!(syscall_test_program);
+        let end = core::ptr::addr_of!(syscall_test_program_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    process::run_test_program(code, |pid| pid as i64)?;
+    qemu_println!("syscall test passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod syscall_tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_errors_map_to_errno() {
+        assert!(Errno::from(FsError::NotFound) == ENOENT);
+        assert!(Errno::from(FsError::NotMounted) == ENOENT);
+        assert!(Errno::from(FsError::IsDirectory) == EISDIR);
+        assert!(Errno::from(FsError::NotDirectory) == ENOTDIR);
+        assert!(Errno::from(FsError::NotFat32) == EIO);
+    }
+}
@@ -0,0 +1,666 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use core::arch::{asm, global_asm};
+use core::sync::atomic::Ordering;
+
+use crate::apic::MAX_CPUS;
+use crate::gdt;
+use crate::mem::memory;
+use crate::mem::stack::{self, KernelStack};
+use crate::mem::stats::{self, Subsystem};
+use crate::percpu;
+use crate::smp;
+use crate::qemu_println;
+use crate::sync::IrqSpinlock;
+use crate::time;
+use crate::wait::WaitQueue;
+
+/* Kernel threads. Every task has its own kernel stack, while it isn't running its
+ * callee-saved registers sit on that stack and its saved stack pointer in `Task::rsp`.
+ * `switch_context` swaps stacks, the rest of the state is already saved by the ABI when
+ * the switch is called as a function, or by the interrupt entry when a task is
+ * preempted.
+ *
+ * Ready tasks wait in one queue per priority. The highest priority runs, tasks of the
+ * same priority take turns every TIME_SLICE_MS: the PIT tick counts down the slice and
+ * the task is switched out at the end of the interrupt (`preempt`). When nothing is
+ * ready the CPU runs its idle task, which halts.
+ *
+ * A task that switches away is only put back on a ready queue (or has its stack freed)
+ * by the task that runs next, in `finish_switch`, so no other CPU can pick it up before
+ * its registers are saved.
+ *
+ * Tasks of user processes have their own PML4, `schedule` loads the PML4 of the task it
+ * switches to.
+ *
+ * The flow that calls `init` becomes the task "main", on the stack it already has. */
+
+const TASK_STACK_PAGES: u64 = 16;
+const IDLE_STACK_PAGES: u64 = 4;
+/// PIT ticks (ms) a task runs before others of its priority get a turn
+pub const TIME_SLICE_MS: u64 = 10;
+
+pub type TaskId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Blocked,
+    /* Blocked in `sleep` */
+    Sleeping,
+    Exited(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+const PRIORITIES: usize = 3;
+
+struct Task {
+    id: TaskId,
+    name: &'static str,
+    state: State,
+    priority: Priority,
+    /* Never queued, runs when no other task is ready */
+    idle: bool,
+    /* Stack pointer while the task isn't running, see switch_context */
+    rsp: u64,
+    /* None for "main", which runs on the stack it was adopted on */
+    stack: Option<KernelStack>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /* Physical address of the PML4 the task runs with, see `set_address_space` */
+    cr3: u64,
+    /* Still running on some CPU, possibly in the middle of switching away */
+    on_cpu: bool,
+    /* `wake` came before the task got to block, the next `block` returns right away */
+    wakeup_pending: bool,
+    /* Nobody is going to join it, the task is removed as soon as it exited */
+    detached: bool,
+    /* What its allocations are charged to while it is switched out */
+    mem_subsystem: Subsystem,
+    /* For `dump` */
+    cpu_ns: u64,
+    wakeups: u64,
+    switches: u64,
+}
+
+impl Task {
+    fn new(id: TaskId, name: &'static str, priority: Priority) -> Self {
+        Self {
+            id,
+            name,
+            state: State::Ready,
+            priority,
+            idle: false,
+            rsp: 0,
+            stack: None,
+            entry: None,
+            cr3: memory::kernel_pml4(),
+            on_cpu: false,
+            wakeup_pending: false,
+            detached: false,
+            mem_subsystem: Subsystem::Kernel,
+            cpu_ns: 0,
+            wakeups: 0,
+            switches: 0,
+        }
+    }
+}
+
+struct Scheduler {
+    next_id: TaskId,
+    tasks: BTreeMap<TaskId, Box<Task>>,
+    /* Indexed by Priority */
+    ready: [VecDeque<TaskId>; PRIORITIES],
+}
+
+impl Scheduler {
+    fn new_id(&mut self) -> TaskId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    // makes room for every task in every ready queue, so that queueing a task from an
+    // interrupt handler never allocates
+    fn reserve_ready(&mut self) {
+        let tasks = self.tasks.len();
+        for queue in &mut self.ready {
+            queue.reserve(tasks.saturating_sub(queue.len()));
+        }
+    }
+
+    // queues a task that just became ready and gets a CPU to run it. Doesn't allocate, see
+    // `reserve_ready`
+    fn make_ready(&mut self, id: TaskId) {
+        let Some(task) = self.tasks.get_mut(&id) else {
+            return;
+        };
+        task.state = State::Ready;
+        let priority = task.priority;
+        self.ready[priority as usize].push_back(id);
+
+        /* Preempt this CPU at the end of the interrupt if the task beats the running one */
+        let cpu = percpu::current();
+        let current = cpu.current_task.load(Ordering::Acquire);
+        if let Some(running) = self.tasks.get(&current) {
+            if running.idle || running.priority < priority {
+                cpu.need_resched.store(true, Ordering::Release);
+            }
+        }
+        /* Idle CPUs halt, make them look at the queue */
+        for index in 0..MAX_CPUS {
+            let other = percpu::get(index);
+            let idle = other.idle_task.load(Ordering::Acquire);
+            if index != cpu.index && idle != 0 && other.current_task.load(Ordering::Acquire) == idle {
+                smp::wake(index);
+            }
+        }
+    }
+
+    // takes the next task of at least priority `min` off the queues
+    fn pop_ready(&mut self, min: Priority) -> Option<TaskId> {
+        self.ready[min as usize..]
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())
+    }
+}
+
+static SCHEDULER: IrqSpinlock<Scheduler> = IrqSpinlock::new(Scheduler {
+    next_id: 1,
+    tasks: BTreeMap::new(),
+    ready: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+});
+
+/* Woken whenever a task exits, `join` waits on it */
+static EXITED: WaitQueue = WaitQueue::new();
+
+// switch_context(save_rsp: *mut u64, new_rsp: u64)
+// pushes the callee-saved registers, stores the stack pointer in `save_rsp` and pops the
+// registers of the task `new_rsp` belongs to, returning into that task
+global_asm!(
+    r#"
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+extern "C" {
+    fn switch_context(save_rsp: *mut u64, new_rsp: u64);
+}
+
+/* Registers switch_context pops before returning into `task_start` */
+const SAVED_REGISTERS: u64 = 6;
+
+/// Turns the calling flow into the task "main" so it can spawn others and block, and
+/// starts the idle task of the calling CPU
+pub fn init() -> Result<(), &'static str> {
+    let cpu = percpu::current();
+    let idle = new_task("idle", Priority::Low, IDLE_STACK_PAGES, Box::new(|| idle_loop()))?;
+
+    let mut scheduler = SCHEDULER.lock();
+    let main = scheduler.new_id();
+    let mut task = Task::new(main, "main", Priority::Normal);
+    task.state = State::Running;
+    task.on_cpu = true;
+    task.detached = true;
+    scheduler.tasks.insert(main, Box::new(task));
+
+    let mut idle_task = idle;
+    idle_task.idle = true;
+    idle_task.detached = true;
+    let idle = idle_task.id;
+    scheduler.tasks.insert(idle, idle_task);
+    scheduler.reserve_ready();
+
+    cpu.idle_task.store(idle, Ordering::Release);
+    cpu.slice_left.store(TIME_SLICE_MS, Ordering::Relaxed);
+    cpu.switched_at.store(time::now_ns(), Ordering::Relaxed);
+    cpu.current_task.store(main, Ordering::Release);
+    Ok(())
+}
+
+fn idle_loop() {
+    loop {
+        unsafe {
+            asm!("cli", options(nomem, nostack));
+        }
+        schedule();
+        /* Nothing to run, an interrupt that wakes a task ends the halt */
+        unsafe {
+            asm!("sti", "hlt", options(nomem, nostack));
+        }
+    }
+}
+
+/// Whether the calling CPU runs tasks, i.e. blocking switches to another task
+pub fn running() -> bool {
+    percpu::current().current_task.load(Ordering::Acquire) != 0
+}
+
+/// Id of the running task, 0 if the CPU doesn't run tasks
+pub fn current_id() -> TaskId {
+    percpu::current().current_task.load(Ordering::Acquire)
+}
+
+/// Joins a task spawned with `spawn`. Dropping it detaches the task
+pub struct JoinHandle {
+    id: TaskId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Blocks until the task exited, returns its exit code
+    pub fn join(self) -> i64 {
+        let id = self.id;
+        let mut code = 0;
+        EXITED.wait_until(|| match SCHEDULER.lock().tasks.get(&id).map(|task| task.state) {
+            Some(State::Exited(exit_code)) => {
+                code = exit_code;
+                true
+            }
+            _ => false,
+        });
+        /* The stack went away with the switch out of the task */
+        SCHEDULER.lock().tasks.remove(&id);
+        code
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(task) = scheduler.tasks.get_mut(&self.id) {
+            task.detached = true;
+            if let State::Exited(_) = task.state {
+                if !task.on_cpu {
+                    scheduler.tasks.remove(&self.id);
+                }
+            }
+        }
+    }
+}
+
+// sets up a task that starts in `task_start`, it isn't known to the scheduler yet
+fn new_task(
+    name: &'static str,
+    priority: Priority,
+    pages: u64,
+    entry: Box<dyn FnOnce() + Send>,
+) -> Result<Box<Task>, &'static str> {
+    let stack = stack::alloc(name, pages)?;
+
+    /* What switch_context pops: the saved registers (all 0, rbp 0 ends stack traces),
+     * then the return address. Above it a 0 return address for `task_start`, which
+     * leaves rsp aligned like after a call */
+    let top = stack.top();
+    let rsp = top - (SAVED_REGISTERS + 2) * 8;
+    unsafe {
+        let frame = rsp as *mut u64;
+        for i in 0..SAVED_REGISTERS as usize {
+            frame.add(i).write(0);
+        }
+        frame.add(SAVED_REGISTERS as usize).write(task_start as *const () as u64);
+        frame.add(SAVED_REGISTERS as usize + 1).write(0);
+    }
+
+    let id = SCHEDULER.lock().new_id();
+    let mut task = Task::new(id, name, priority);
+    task.rsp = rsp;
+    task.stack = Some(stack);
+    task.entry = Some(entry);
+    Ok(Box::new(task))
+}
+
+/// Starts `f` as a new task on its own stack. The task exits with 0 when `f` returns
+pub fn spawn(name: &'static str, f: impl FnOnce() + Send + 'static) -> Result<JoinHandle, &'static str> {
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+pub fn spawn_with_priority(
+    name: &'static str,
+    priority: Priority,
+    f: impl FnOnce() + Send + 'static,
+) -> Result<JoinHandle, &'static str> {
+    let task = new_task(name, priority, TASK_STACK_PAGES, Box::new(f))?;
+    let id = task.id;
+
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.tasks.insert(id, task);
+    scheduler.reserve_ready();
+    scheduler.make_ready(id);
+    Ok(JoinHandle { id })
+}
+
+/// Changes the priority of a task, it takes effect the next time the task is queued
+pub fn set_priority(id: TaskId, priority: Priority) -> Result<(), &'static str> {
+    let mut scheduler = SCHEDULER.lock();
+    let task = scheduler.tasks.get_mut(&id).ok_or("no such task")?;
+    if task.idle {
+        return Err("the idle task has no priority");
+    }
+    let old = task.priority;
+    task.priority = priority;
+    if task.state == State::Ready {
+        /* Move it to the queue of its new priority */
+        scheduler.ready[old as usize].retain(|&queued| queued != id);
+        scheduler.ready[priority as usize].push_back(id);
+    }
+    Ok(())
+}
+
+extern "C" fn task_start() -> ! {
+    finish_switch();
+    let entry = SCHEDULER
+        .lock()
+        .tasks
+        .get_mut(&current_id())
+        .and_then(|task| task.entry.take());
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit(0);
+}
+
+/// Ends the calling task, `join` returns `code`
+pub fn exit(code: i64) -> ! {
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let id = current_id();
+        if let Some(task) = scheduler.tasks.get_mut(&id) {
+            task.state = State::Exited(code);
+        }
+    }
+    EXITED.wake_all();
+    schedule();
+    unreachable!("task: exited task was scheduled again");
+}
+
+/// Makes the calling task run with the PML4 at `pml4` from now on, also after it was
+/// switched out. The upper half of every address space is the kernel's
+pub fn set_address_space(pml4: u64) {
+    crate::sync::without_interrupts(|| {
+        if let Some(task) = SCHEDULER.lock().tasks.get_mut(&current_id()) {
+            task.cr3 = pml4;
+        }
+        memory::switch_address_space(pml4);
+    });
+}
+
+/// Lets the other ready tasks of the same or a higher priority run first
+pub fn yield_now() {
+    if !running() {
+        return;
+    }
+    let enabled = crate::sync::interrupts_enabled();
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+    schedule();
+    if enabled {
+        unsafe {
+            asm!("sti", options(nomem, nostack));
+        }
+    }
+}
+
+/// Blocks the calling task until `wake`. Returns right away if `wake` was called since
+/// the last `block`. Interrupts have to be off, they are still off when it returns
+pub fn block() {
+    block_as(State::Blocked);
+}
+
+fn block_as(state: State) {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(task) = scheduler.tasks.get_mut(&current_id()) else {
+            return;
+        };
+        if task.wakeup_pending {
+            task.wakeup_pending = false;
+            return;
+        }
+        task.state = state;
+    }
+    schedule();
+}
+
+/// Blocks the calling task for `ms` milliseconds
+pub fn sleep(ms: u64) {
+    let until = time::get_millis().saturating_add(ms);
+    let id = current_id();
+    let timer = time::after(ms, move || wake(id));
+
+    let enabled = crate::sync::interrupts_enabled();
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+    while time::get_millis() < until {
+        block_as(State::Sleeping);
+    }
+    if enabled {
+        unsafe {
+            asm!("sti", options(nomem, nostack));
+        }
+    }
+    timer.cancel();
+}
+
+/// Makes a blocked or sleeping task ready again, a running task won't block the next
+/// time it tries
+pub fn wake(id: TaskId) {
+    let mut scheduler = SCHEDULER.lock();
+    let Some(task) = scheduler.tasks.get_mut(&id) else {
+        return;
+    };
+    match task.state {
+        State::Blocked | State::Sleeping => {
+            task.wakeups += 1;
+            if task.on_cpu {
+                /* Still switching away, `finish_switch` queues it */
+                task.wakeup_pending = true;
+            } else {
+                scheduler.make_ready(id);
+            }
+        }
+        State::Running => task.wakeup_pending = true,
+        State::Ready | State::Exited(_) => (),
+    }
+}
+
+/// Counts down the time slice of the running task, called on every PIT tick
+pub fn tick() {
+    let cpu = percpu::current();
+    if cpu.current_task.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let left = cpu.slice_left.load(Ordering::Relaxed);
+    if left <= 1 {
+        cpu.need_resched.store(true, Ordering::Release);
+    } else {
+        cpu.slice_left.store(left - 1, Ordering::Relaxed);
+    }
+}
+
+/// Switches tasks if the interrupt that is about to return asked for it. Runs at the
+/// end of interrupt handlers, after the EOI
+pub fn preempt() {
+    let cpu = percpu::current();
+    if cpu.current_task.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    if cpu.need_resched.swap(false, Ordering::AcqRel) {
+        schedule();
+    }
+}
+
+// switches to the next ready task. The caller has set the state of the current task, if
+// it is still Running it keeps the CPU unless a task of the same or a higher priority is
+// ready. Interrupts must be off
+fn schedule() {
+    let cpu = percpu::current();
+    let current = cpu.current_task.load(Ordering::Acquire);
+    if current == 0 {
+        return;
+    }
+    let mut scheduler = SCHEDULER.lock();
+    cpu.need_resched.store(false, Ordering::Release);
+
+    let task = scheduler.tasks.get_mut(&current).unwrap();
+    if task.wakeup_pending && matches!(task.state, State::Blocked | State::Sleeping) {
+        task.wakeup_pending = false;
+        task.state = State::Running;
+    }
+    let runnable = task.state == State::Running;
+    let min = if runnable && !task.idle {
+        task.priority
+    } else {
+        Priority::Low
+    };
+
+    let next = match scheduler.pop_ready(min) {
+        Some(next) => next,
+        None if runnable => {
+            cpu.slice_left.store(TIME_SLICE_MS, Ordering::Relaxed);
+            return;
+        }
+        None => cpu.idle_task.load(Ordering::Acquire),
+    };
+
+    let now = time::now_ns();
+    let ran = now - cpu.switched_at.swap(now, Ordering::Relaxed);
+    let task = scheduler.tasks.get_mut(&current).unwrap();
+    task.cpu_ns += ran;
+    task.mem_subsystem = stats::current();
+    let save_rsp: *mut u64 = &mut task.rsp;
+
+    let next_task = scheduler.tasks.get_mut(&next).unwrap();
+    next_task.state = State::Running;
+    next_task.on_cpu = true;
+    next_task.switches += 1;
+    stats::set_current(next_task.mem_subsystem);
+    let new_rsp = next_task.rsp;
+    let stack_top = next_task.stack.as_ref().map(|stack| stack.top());
+    let cr3 = next_task.cr3;
+
+    cpu.prev_task.store(current, Ordering::Release);
+    cpu.current_task.store(next, Ordering::Release);
+    cpu.slice_left.store(TIME_SLICE_MS, Ordering::Relaxed);
+    drop(scheduler);
+
+    if let Some(top) = stack_top {
+        gdt::set_kernel_stack(top);
+    }
+    memory::switch_address_space(cr3);
+    unsafe {
+        switch_context(save_rsp, new_rsp);
+    }
+    /* Back in `current`, switched to by some other task */
+    finish_switch();
+}
+
+// runs right after a switch, on the new task, and takes care of the task that was left
+fn finish_switch() {
+    let prev = percpu::current().prev_task.swap(0, Ordering::AcqRel);
+    if prev == 0 {
+        return;
+    }
+
+    let mut scheduler = SCHEDULER.lock();
+    let Some(task) = scheduler.tasks.get_mut(&prev) else {
+        return;
+    };
+    task.on_cpu = false;
+    match task.state {
+        State::Running if task.idle => task.state = State::Ready,
+        State::Running => scheduler.make_ready(prev),
+        State::Blocked | State::Sleeping if task.wakeup_pending => {
+            task.wakeup_pending = false;
+            scheduler.make_ready(prev);
+        }
+        State::Exited(_) => {
+            if let Some(stack) = task.stack.take() {
+                stack::free(stack);
+            }
+            if task.detached {
+                scheduler.tasks.remove(&prev);
+            }
+        }
+        State::Blocked | State::Sleeping | State::Ready => (),
+    }
+}
+
+/// Prints every task with its state, CPU time and how often it was woken up and
+/// switched to
+pub fn dump() {
+    let scheduler = SCHEDULER.lock();
+    let running = current_id();
+    let ran = time::now_ns() - percpu::current().switched_at.load(Ordering::Relaxed);
+    qemu_println!(
+        "{:>4} {:<16} {:<10} {:<6} {:>10} {:>8} {:>8}",
+        "id",
+        "name",
+        "state",
+        "prio",
+        "cpu ms",
+        "wakeups",
+        "switches"
+    );
+    for task in scheduler.tasks.values() {
+        let state = match task.state {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Blocked => "blocked",
+            State::Sleeping => "sleeping",
+            State::Exited(_) => "exited",
+        };
+        let priority = match task.priority {
+            _ if task.idle => "-",
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        };
+        /* The running task hasn't been charged for its current slice yet */
+        let cpu_ns = task.cpu_ns + if task.id == running { ran } else { 0 };
+        qemu_println!(
+            "{:>4} {:<16} {:<10} {:<6} {:>10} {:>8} {:>8}",
+            task.id,
+            task.name,
+            state,
+            priority,
+            cpu_ns / 1_000_000,
+            task.wakeups,
+            task.switches
+        );
+    }
+}
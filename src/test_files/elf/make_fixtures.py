@@ -0,0 +1,137 @@
+#!/usr/bin/env python3
+# Writes the ELF fixtures for the parser tests in src/elf.rs and the boot test in
+# loader.rs. Run from this directory: python3 make_fixtures.py
+#
+# hello.elf is a static x86_64 executable with a read/execute segment (headers and code)
+# and a read/write segment whose .bss spills into a page of its own. It prints
+# "hello from <argv[0]>", checks that its .bss is zeroed and exits with argc, or with -1
+# if the .bss wasn't zeroed. It uses the kernel's system call numbers (see syscall.rs).
+
+import struct
+
+BASE = 0x400000
+EHDR_SIZE = 64
+PHDR_SIZE = 56
+PHNUM = 2
+CODE_OFFSET = EHDR_SIZE + PHNUM * PHDR_SIZE
+ENTRY = BASE + CODE_OFFSET
+
+DATA_OFFSET = 0x180
+DATA_VADDR = 0x401000 + DATA_OFFSET
+DATA_MEM_SIZE = 0x1000
+# first qword of the page that only holds .bss
+BSS_VADDR = 0x402000
+
+SYS_WRITE = 1
+SYS_EXIT = 4
+
+PT_LOAD = 1
+PF_X, PF_W, PF_R = 1, 2, 4
+
+DATA = b"hello from \n"
+HELLO = DATA_VADDR
+NEWLINE = DATA_VADDR + len("hello from ")
+
+
+def imm32(value):
+    return struct.pack("<i" if value < 0 else "<I", value)
+
+
+def write(addr, length):
+    # mov eax, SYS_WRITE; mov edi, 1; mov esi, addr; mov edx, length; syscall
+    return (b"\xB8" + imm32(SYS_WRITE) + b"\xBF" + imm32(1) + b"\xBE" + imm32(addr)
+            + b"\xBA" + imm32(length) + b"\x0F\x05")
+
+
+# items are bytes, a label name, or (opcode, label) for a short jump
+PROGRAM = [
+    b"\x4C\x8B\x24\x24",          # mov r12, [rsp]      ; argc
+    b"\x4C\x8B\x6C\x24\x08",      # mov r13, [rsp + 8]  ; argv[0]
+    write(HELLO, len("hello from ")),
+    b"\x31\xD2",                  # xor edx, edx
+    "strlen",
+    b"\x41\x80\x7C\x15\x00\x00",  # cmp byte [r13 + rdx], 0
+    (0x74, "print_name"),         # je
+    b"\x48\xFF\xC2",              # inc rdx
+    (0xEB, "strlen"),             # jmp
+    "print_name",
+    b"\xB8" + imm32(SYS_WRITE),   # mov eax, SYS_WRITE
+    b"\xBF" + imm32(1),           # mov edi, 1
+    b"\x4C\x89\xEE",              # mov rsi, r13
+    b"\x0F\x05",                  # syscall
+    write(NEWLINE, 1),
+    b"\xB8" + imm32(BSS_VADDR),   # mov eax, BSS_VADDR
+    b"\x48\x83\x38\x00",          # cmp qword [rax], 0
+    (0x75, "fail"),               # jne
+    b"\x4C\x89\xE7",              # mov rdi, r12
+    (0xEB, "exit"),               # jmp
+    "fail",
+    b"\x48\xC7\xC7" + imm32(-1),  # mov rdi, -1
+    "exit",
+    b"\xB8" + imm32(SYS_EXIT),    # mov eax, SYS_EXIT
+    b"\x0F\x05",                  # syscall
+    b"\x0F\x0B",                  # ud2
+]
+
+
+def assemble(program):
+    labels, offset = {}, 0
+    for item in program:
+        if isinstance(item, str):
+            labels[item] = offset
+        else:
+            offset += 2 if isinstance(item, tuple) else len(item)
+
+    code = b""
+    for item in program:
+        if isinstance(item, tuple):
+            opcode, label = item
+            code += struct.pack("<Bb", opcode, labels[label] - (len(code) + 2))
+        elif isinstance(item, bytes):
+            code += item
+    return code
+
+
+def ehdr(entry, phnum):
+    ident = b"\x7FELF" + bytes([2, 1, 1]) + bytes(9)
+    # type, machine, version, entry, phoff, shoff, flags, ehsize, phentsize, phnum,
+    # shentsize, shnum, shstrndx
+    return ident + struct.pack("<HHIQQQIHHHHHH", 2, 0x3E, 1, entry, EHDR_SIZE, 0, 0,
+                               EHDR_SIZE, PHDR_SIZE, phnum, 0, 0, 0)
+
+
+def phdr(flags, offset, vaddr, file_size, mem_size):
+    return struct.pack("<IIQQQQQQ", PT_LOAD, flags, offset, vaddr, vaddr, file_size,
+                       mem_size, 0x1000)
+
+
+def hello(data_vaddr=DATA_VADDR):
+    code = assemble(PROGRAM)
+    text_size = CODE_OFFSET + len(code)
+    assert text_size <= DATA_OFFSET
+
+    image = ehdr(ENTRY, PHNUM)
+    image += phdr(PF_R | PF_X, 0, BASE, text_size, text_size)
+    image += phdr(PF_R | PF_W, DATA_OFFSET, data_vaddr, len(DATA), DATA_MEM_SIZE)
+    image += code
+    image += bytes(DATA_OFFSET - len(image)) + DATA
+    return image
+
+
+def main():
+    image = hello()
+    fixtures = {
+        "hello.elf": image,
+        "bad_magic.elf": image[:1] + b"X" + image[2:],
+        # ends in the middle of the program headers
+        "truncated.elf": image[:100],
+        # the data segment is in the kernel half
+        "kernel_segment.elf": hello(0xFFFF_8000_0000_0000 + DATA_VADDR),
+    }
+    for name, data in fixtures.items():
+        with open(name, "wb") as file:
+            file.write(data)
+
+
+if __name__ == "__main__":
+    main()
@@ -10,36 +10,29 @@ somewhat reliable fashion.
     it should be once every 1193 ticks...
 
 */
-use crate::heap::vectors::*;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::cmp::Reverse;
+use core::sync::atomic::Ordering;
+
+use crate::apic;
+use crate::drivers::rtc::{self, DateTime};
+use crate::handlers::InterruptStackFrame;
+use crate::irq;
+use crate::percpu;
+use crate::sync::{self, Counter, IrqSpinlock, Once};
+use crate::task;
 use crate::tooling::serial::*;
+use crate::wait::WaitQueue;
+use crate::{qemu_print, qemu_println};
 
 const DIVISOR: u16 = 1193; // == 1193181 / 1000 hz
 
-pub static mut MILLIS: u64 = 0;
-pub static mut MILLIS_TOTAL: u64 = 0;
-pub static mut TIMER: Timer = Timer {
-    max: 500,
-    cur: 0,
-    func: &say_hi,
-    active: false,
-};
-pub static mut SOUND_TIMER: Timer = Timer::_new(0, &do_nothing);
-pub static mut TIMERS: [Timer; 10] = [Timer::_new(0, &do_nothing); 10];
-pub static mut WAITING_ON_INPUT: bool = false; // todo: implement sleep_until_input
-
-#[repr(C)]
-#[derive(Clone, Copy)]
-pub struct Timer {
-    max: u64,
-    cur: u64,
-    func: &'static dyn Fn(),
-    active: bool,
-}
-
-#[repr(C)]
-pub struct TimerPtr {
-    index: usize,
-}
+/* Milliseconds into the current second, since boot and whole seconds since boot */
+pub static MILLIS: Counter = Counter::new(0);
+pub static MILLIS_TOTAL: Counter = Counter::new(0);
+pub static TIME_ELAPSED: Counter = Counter::new(0);
 
 // set the PIT to 1000 interrupts/sec, instead of the default 18
 pub fn init() {
@@ -47,107 +40,394 @@ pub fn init() {
     outb(0x40, (DIVISOR & 0xff) as u8); // put low byte of DIVISOR in 0x40
     outb(0x40, ((DIVISOR >> 8) & 0xff) as u8); // put high byte
 
-    outb(0x21, inb(0x21) & 0xFE); // chat gippity, i have no idea wtf this does
-                                  // however very ugly panic if this line is omitted
+    irq::register(0, "pit", pit_irq).unwrap();
+}
+
+// run the timers that are due
+fn pit_irq(_line: u8) -> bool {
+    let now = MILLIS_TOTAL.inc();
+    match TIMER_TASK.get() {
+        Some(&id) => {
+            if timers_due(now) {
+                task::wake(id);
+            }
+        }
+        None => run_timers(now),
+    }
+    task::tick();
+
+    if MILLIS.inc() == 1000 {
+        MILLIS.set(0);
+        let elapsed = TIME_ELAPSED.inc();
+        qemu_print!("a second passed! ({})\n", elapsed);
+    }
+    true
 }
 
 #[inline]
 pub fn get_millis() -> u64 {
-    unsafe { MILLIS_TOTAL }
-}
-
-// yeah this rust syntax makes me want to vomit
-// but basically you can pass a fn into Timer and it will be executed when the timer is done
-// only empty void functions are supported atm, hopefully somebody who knows rust can add params and return value (wrapped in Option?)
-impl Timer {
-    const fn _new(time: u64, function: &'static dyn Fn()) -> Self {
-        Timer {
-            max: time,
-            cur: 0,
-            func: function,
-            active: false,
-        }
+    MILLIS_TOTAL.get()
+}
+
+/* High resolution clock. The TSC and the local APIC timer run at frequencies only known
+ * after measuring them against the PIT (channel 2, which doesn't disturb the 1 ms tick on
+ * channel 0). `now_ns` reads the TSC, the LAPIC timer gives every CPU a one-shot deadline
+ * interrupt, which is what a tickless kernel needs instead of the periodic PIT tick. */
+
+const PIT_FREQUENCY: u64 = 1193182;
+const CALIBRATION_MS: u64 = 10;
+
+struct Clock {
+    /* TSC value at `calibrate`, now_ns() counts from there */
+    tsc_base: u64,
+    tsc_hz: u64,
+    /* ns per TSC tick as a 32.32 fixed point number */
+    ns_per_tick: u64,
+    /* LAPIC timer ticks (after the divide by 16) per second, 0 without APIC */
+    lapic_hz: u64,
+    /* millis at `calibrate`, so now_ns() doesn't jump back to 0 */
+    ns_offset: u64,
+}
+
+// set by `calibrate` on the boot CPU before the APs start
+static CLOCK: Once<Clock> = Once::new();
+
+/// Runs when the deadline of a CPU has passed, in interrupt context
+pub type DeadlineHandler = fn(now_ns: u64);
+
+/* The deadlines themselves are per CPU (see `PerCpu::deadline`) */
+static DEADLINE_HANDLER: IrqSpinlock<DeadlineHandler> = IrqSpinlock::new(|_| {});
+
+// busy waits `ms` milliseconds on PIT channel 2, calling `start` right after the PIT has
+// been started and returning what `stop` returns when it ran out
+fn pit_measure<T>(ms: u64, start: impl FnOnce(), stop: impl FnOnce() -> T) -> T {
+    let count = (PIT_FREQUENCY * ms / 1000) as u16;
+
+    /* Gate on, speaker off */
+    let port61 = inb(0x61);
+    outb(0x61, (port61 & !0x02) | 0x01);
+    /* Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count) */
+    outb(0x43, 0b10110000);
+    outb(0x42, count as u8);
+    outb(0x42, (count >> 8) as u8);
+
+    start();
+    /* The output of channel 2 goes high when the count reaches 0 */
+    while inb(0x61) & 0x20 == 0 {
+        core::hint::spin_loop();
     }
+    let result = stop();
 
-    // TODO: make a static mut timers vector
-    // so that we can have multiple timers at the same time
-    pub fn new(time: u64, function: &'static dyn Fn()) -> TimerPtr {
-        unsafe {
-            let mut i = 0;
-            while i < TIMERS.len() {
-                if TIMERS[i].cur >= TIMERS[i].max {
-                    TIMERS[i] = Timer::_new(time, function);
-                    return TimerPtr { index: i };
-                }
-                i += 1;
+    outb(0x61, port61);
+    result
+}
+
+/// Measures the TSC and (if the APICs are up) the LAPIC timer against the PIT
+pub fn calibrate() {
+    let with_apic = apic::enabled();
+    let mut tsc_start = 0;
+    let (tsc_end, lapic_ticks) = pit_measure(
+        CALIBRATION_MS,
+        || {
+            if with_apic {
+                apic::timer_free_run();
             }
-            panic!("Too many timers!")
-        }
+            tsc_start = unsafe { _rdtsc() };
+        },
+        || {
+            let tsc = unsafe { _rdtsc() };
+            let lapic = if with_apic {
+                u32::MAX - apic::timer_current()
+            } else {
+                0
+            };
+            (tsc, lapic)
+        },
+    );
+    if with_apic {
+        apic::timer_stop();
     }
 
-    // (re)start the timer
-    pub fn init(&mut self) {
-        self.active = true;
-        self.cur = 0;
+    let tsc_hz = (tsc_end - tsc_start) * 1000 / CALIBRATION_MS;
+    let clock = CLOCK.call_once(|| Clock {
+        tsc_hz,
+        ns_per_tick: ((1_000_000_000u128 << 32) / tsc_hz as u128) as u64,
+        lapic_hz: lapic_ticks as u64 * 1000 / CALIBRATION_MS,
+        ns_offset: get_millis() * 1_000_000,
+        tsc_base: unsafe { _rdtsc() },
+    });
+
+    /* CPUID 0x80000007 EDX bit 8: the TSC runs at a constant rate in all P/C-states */
+    let invariant = unsafe {
+        __cpuid(0x80000000).eax >= 0x80000007 && __cpuid(0x80000007).edx & (1 << 8) != 0
+    };
+    qemu_println!(
+        "time: TSC at {} MHz{}, LAPIC timer at {} kHz",
+        clock.tsc_hz / 1_000_000,
+        if invariant { "" } else { " (not invariant)" },
+        clock.lapic_hz / 1000
+    );
+}
+
+fn lapic_hz() -> u64 {
+    CLOCK.get().map_or(0, |clock| clock.lapic_hz)
+}
+
+/// Nanoseconds since boot, monotonic. Has millisecond resolution until `calibrate` ran
+pub fn now_ns() -> u64 {
+    let Some(clock) = CLOCK.get() else {
+        return get_millis() * 1_000_000;
+    };
+    let ticks = unsafe { _rdtsc() } - clock.tsc_base;
+    clock.ns_offset + ((ticks as u128 * clock.ns_per_tick as u128) >> 32) as u64
+}
+
+pub fn now_us() -> u64 {
+    now_ns() / 1000
+}
+
+/// Busy waits for `ns` nanoseconds
+pub fn delay_ns(ns: u64) {
+    let end = now_ns() + ns;
+    while now_ns() < end {
+        core::hint::spin_loop();
     }
+}
+
+static BOOT_UNIX_TIME: Once<u64> = Once::new();
 
-    // stop the timer
-    pub fn stop(&mut self) {
-        self.active = false;
+/// Reads the RTC once, from then on the wall clock follows `now_ns`
+pub fn init_wall_clock() {
+    let now = rtc::read();
+    BOOT_UNIX_TIME.call_once(|| now.to_unix() - now_ns() / 1_000_000_000);
+    qemu_println!(
+        "time: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        now.year,
+        now.month,
+        now.day,
+        now.hour,
+        now.minute,
+        now.second
+    );
+}
+
+/// Seconds since the Unix epoch, or since boot before `init_wall_clock`
+pub fn unix_time() -> u64 {
+    BOOT_UNIX_TIME.get().copied().unwrap_or(0) + now_ns() / 1_000_000_000
+}
+
+/// Current date and time (UTC)
+pub fn wall_clock() -> DateTime {
+    DateTime::from_unix(unix_time())
+}
+
+/// Sets what runs when a deadline passes, shared by all CPUs
+pub fn set_deadline_handler(handler: DeadlineHandler) {
+    *DEADLINE_HANDLER.lock() = handler;
+}
+
+/// Raises one interrupt on this CPU once `now_ns()` reaches `deadline_ns`, replacing the
+/// previous deadline. Needs the local APIC
+pub fn set_deadline(deadline_ns: u64) -> Result<(), &'static str> {
+    if lapic_hz() == 0 {
+        return Err("the LAPIC timer isn't calibrated");
     }
+    percpu::current().deadline.store(deadline_ns, Ordering::Relaxed);
+    arm_deadline(deadline_ns);
+    Ok(())
+}
 
-    // increment the timer by 1 ms
-    pub fn tick(&mut self) {
-        if self.active {
-            self.cur += 1;
-            if self.cur >= self.max {
-                (self.func)();
-                self.active = false;
-                // commented out because the timer stops ticking even after restarted
-            }
-        }
+pub fn cancel_deadline() {
+    percpu::current().deadline.store(0, Ordering::Relaxed);
+    if apic::enabled() {
+        apic::timer_stop();
     }
 }
 
-impl TimerPtr {
-    pub fn init(&self) {
-        unsafe {
-            TIMERS[self.index].active = true;
+// deadlines further away than the 32 bit counter reaches take several rounds
+fn arm_deadline(deadline_ns: u64) {
+    let ns = deadline_ns.saturating_sub(now_ns());
+    let ticks = ns as u128 * lapic_hz() as u128 / 1_000_000_000;
+    apic::timer_oneshot(ticks.clamp(1, u32::MAX as u128) as u32);
+}
+
+pub extern "x86-interrupt" fn deadline_interrupt(_isf: InterruptStackFrame) {
+    let cpu = percpu::current();
+    let deadline = cpu.deadline.load(Ordering::Relaxed);
+    let now = now_ns();
+    if deadline != 0 {
+        if now >= deadline {
+            cpu.deadline.store(0, Ordering::Relaxed);
+            let handler = *DEADLINE_HANDLER.lock();
+            handler(now);
+        } else {
+            arm_deadline(deadline);
         }
     }
+    apic::send_eoi();
+}
+
+/* Software timers on the 1 ms PIT tick. A timer owns a boxed closure, so it can carry
+ * whatever state it needs, and either fires once or every `period` ms until cancelled.
+ * Deadlines sit in a min-heap, a tick with nothing due only looks at the top of it.
+ * Cancelling removes the closure right away, its heap entry is skipped once it comes up.
+ *
+ * Callbacks run in the "timers" task once `start_timer_task` ran, the tick only wakes it,
+ * so that starting, cancelling and dropping timers doesn't touch the heap in interrupt
+ * context. Before that (early boot) they run in interrupt context. Callbacks may start or
+ * cancel timers, including their own. */
+
+pub type TimerFn = Box<dyn FnMut() + Send>;
+
+struct TimerEntry {
+    deadline: u64,
+    /* 0 for one-shot timers */
+    period: u64,
+    func: TimerFn,
+}
+
+struct Timers {
+    next_id: u64,
+    /* (deadline, id), ids keep timers with the same deadline in start order */
+    queue: BinaryHeap<Reverse<(u64, u64)>>,
+    entries: BTreeMap<u64, TimerEntry>,
+    /* Timer whose callback is running, it isn't in `entries` meanwhile */
+    running: Option<u64>,
+    running_cancelled: bool,
+}
+
+static TIMERS: IrqSpinlock<Timers> = IrqSpinlock::new(Timers {
+    next_id: 1,
+    queue: BinaryHeap::new(),
+    entries: BTreeMap::new(),
+    running: None,
+    running_cancelled: false,
+});
+
+static TIMER_TASK: Once<task::TaskId> = Once::new();
+
+/// Refers to a timer started by `after` or `every`, copying it is fine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerHandle {
+    id: u64,
+}
 
-    pub fn stop(&self) {
-        unsafe {
-            TIMERS[self.index].active = false;
+impl TimerHandle {
+    /// Stops the timer, returns false if it had already fired (one-shot) or been cancelled
+    pub fn cancel(self) -> bool {
+        let mut timers = TIMERS.lock();
+        if timers.entries.remove(&self.id).is_some() {
+            return true;
         }
+        if timers.running == Some(self.id) && !timers.running_cancelled {
+            timers.running_cancelled = true;
+            return true;
+        }
+        false
     }
 
-    pub fn execute(&self) {
-        unsafe {
-            TIMERS[self.index].active = false;
-            (TIMERS[self.index].func)()
-        }
+    /// Whether the timer is still going to fire
+    pub fn is_pending(&self) -> bool {
+        let timers = TIMERS.lock();
+        timers.entries.contains_key(&self.id)
+            || (timers.running == Some(self.id) && !timers.running_cancelled)
     }
 }
 
-pub fn say_hi() {
-    println!("A random number between 1 and 100: {}", unsafe {
-        crate::misc::rand::RNG.range(1, 100)
-    });
-    unsafe {
-        TIMER.init();
+fn add_timer(delay: u64, period: u64, func: TimerFn) -> TimerHandle {
+    let mut timers = TIMERS.lock();
+    let id = timers.next_id;
+    timers.next_id += 1;
+    let deadline = get_millis().saturating_add(delay.max(1));
+    timers.queue.push(Reverse((deadline, id)));
+    timers.entries.insert(
+        id,
+        TimerEntry {
+            deadline,
+            period,
+            func,
+        },
+    );
+    TimerHandle { id }
+}
+
+/// Runs `f` once in `ms` milliseconds
+pub fn after(ms: u64, f: impl FnMut() + Send + 'static) -> TimerHandle {
+    add_timer(ms, 0, Box::new(f))
+}
+
+/// Runs `f` every `period` milliseconds, the first time `period` ms from now
+pub fn every(period: u64, f: impl FnMut() + Send + 'static) -> Result<TimerHandle, &'static str> {
+    if period == 0 {
+        return Err("a periodic timer needs a period");
     }
+    Ok(add_timer(period, period, Box::new(f)))
 }
 
-pub fn sleep(millis: u64) {
-    let then = get_millis() + millis;
+/// Timers that haven't fired or been cancelled yet
+pub fn pending_timers() -> usize {
+    TIMERS.lock().entries.len()
+}
+
+/// Moves the timer callbacks out of interrupt context into a task of their own. Needs
+/// `task::init`
+pub fn start_timer_task() -> Result<(), &'static str> {
+    let handle = task::spawn_with_priority("timers", task::Priority::High, || loop {
+        run_timers(get_millis());
+        /* A wake while the callbacks ran makes this return right away */
+        sync::without_interrupts(task::block);
+    })?;
+    TIMER_TASK.call_once(|| handle.id());
+    Ok(())
+}
+
+fn timers_due(now: u64) -> bool {
+    matches!(TIMERS.lock().queue.peek(), Some(&Reverse((deadline, _))) if deadline <= now)
+}
+
+// the lock is dropped while a callback runs, so callbacks can start and cancel timers
+fn run_timers(now: u64) {
     loop {
-        if get_millis() >= then {
-            break;
+        let (id, mut entry) = {
+            let mut timers = TIMERS.lock();
+            let id = match timers.queue.peek() {
+                Some(&Reverse((deadline, id))) if deadline <= now => id,
+                _ => break,
+            };
+            timers.queue.pop();
+            let Some(entry) = timers.entries.remove(&id) else {
+                /* Cancelled */
+                continue;
+            };
+            timers.running = Some(id);
+            timers.running_cancelled = false;
+            (id, entry)
+        };
+
+        (entry.func)();
+
+        let mut timers = TIMERS.lock();
+        timers.running = None;
+        if entry.period != 0 && !timers.running_cancelled {
+            /* Periods missed while interrupts were off are dropped, not made up for */
+            entry.deadline = entry.deadline.saturating_add(entry.period);
+            if entry.deadline <= now {
+                entry.deadline = now + entry.period - (now - entry.deadline) % entry.period;
+            }
+            timers.queue.push(Reverse((entry.deadline, id)));
+            timers.entries.insert(id, entry);
         }
     }
 }
 
-pub fn do_nothing() {
-    return;
+static SLEEPERS: WaitQueue = WaitQueue::new();
+
+/// Blocks the calling task, or halts the CPU if it doesn't run tasks, for `millis`
+/// milliseconds
+pub fn sleep(millis: u64) {
+    if task::running() && sync::interrupts_enabled() {
+        task::sleep(millis);
+    } else {
+        SLEEPERS.wait_until_timeout(millis, || false);
+    }
 }
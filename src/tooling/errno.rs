@@ -1,5 +1,70 @@
+use core::fmt;
+
+/* Error numbers of the system call ABI. A failed system call returns the negated number
+ * in rax, the values are the Linux ones so ported C code keeps working. */
+
+#[repr(i64)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Errno {
-    ENOSYS(i8),
-    EACCES(i8),
-    EFAULT(i8),
+    /// Operation not permitted
+    EPERM = 1,
+    /// No such file or directory
+    ENOENT = 2,
+    /// No such process
+    ESRCH = 3,
+    /// Interrupted system call
+    EINTR = 4,
+    /// I/O error
+    EIO = 5,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Permission denied
+    EACCES = 13,
+    /// Bad address
+    EFAULT = 14,
+    /// File exists
+    EEXIST = 17,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Is a directory
+    EISDIR = 21,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Too many open files
+    EMFILE = 24,
+    /// No space left on device
+    ENOSPC = 28,
+    /// File name too long
+    ENAMETOOLONG = 36,
+    /// Function not implemented
+    ENOSYS = 38,
+}
+
+impl Errno {
+    /// What a system call returns in rax for this error
+    pub fn to_return(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({})", self, *self as i64)
+    }
+}
+
+#[cfg(test)]
+mod errno_tests {
+    use super::*;
+
+    #[test]
+    fn test_return_value_is_the_negated_number() {
+        assert!(Errno::EPERM.to_return() as i64 == -1);
+        assert!(Errno::EFAULT.to_return() as i64 == -14);
+        assert!(Errno::ENOSYS.to_return() as i64 == -38);
+        // user code sees anything above -4096 as an error
+        assert!(Errno::ENOSYS.to_return() > (-4096i64) as u64);
+    }
 }
@@ -1,3 +1,4 @@
+pub mod errno;
 pub mod panic_handler;
 pub mod qemu_io;
 pub mod serial;
@@ -106,6 +106,7 @@ pub fn stack_trace() {
 fn dump_current_frame() {}
 
 /// This function is called on panic.
+#[cfg(not(test))]
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
     qemu_println("\n\n=========================================================");
@@ -51,7 +51,12 @@ pub fn qemu_print_num(mut n: u64) {
 
 /// Prints a string without newline to QEMU serial stdout
 pub fn qemu_print(string: &str) {
-    for b in string.bytes() {
+    qemu_print_bytes(string.as_bytes());
+}
+
+/// Raw bytes, for output that isn't necessarily UTF-8
+pub fn qemu_print_bytes(bytes: &[u8]) {
+    for &b in bytes {
         outb(0x3F8, b);
     }
 }
@@ -1,4 +1,9 @@
+use crate::mem::memory::phys_to_virt;
 use core::fmt::Write;
+
+// physical address of the text mode buffer
+const VGA_TEXT_BASE: u64 = 0xb8000;
+
 /// A custom writer to store a string in a buffer.
 pub struct VGAWriter {
     pub buffer: &'static mut [u8],
@@ -30,7 +35,7 @@ impl Color {
 impl VGAWriter {
     pub fn new() -> Self {
         unsafe {
-            let vga_offset = 0xb8000 as *mut u8;
+            let vga_offset = phys_to_virt(VGA_TEXT_BASE) as *mut u8;
             let vga_buffer_slice = core::slice::from_raw_parts_mut(vga_offset, 4000); // (rows * cols) * (chars + color)
             VGAWriter {
                 buffer: vga_buffer_slice,
@@ -46,7 +51,7 @@ impl VGAWriter {
         self.idx % 160
     }
     pub fn copy_to_vga(&self) {
-        let vga_buffer = 0xb8000 as *mut u8;
+        let vga_buffer = phys_to_virt(VGA_TEXT_BASE) as *mut u8;
         for i in 0..self.idx {}
     }
     pub fn newline(&mut self) {
@@ -115,7 +120,7 @@ pub fn write_str(s: &str, writer: Option<VGAWriter>, color: u8) -> VGAWriter {
     return w;
 }
 pub fn write_str_at(s: &str, row: usize, col: usize, color: u8) {
-    let vga_buffer = 0xb8000 as *mut u8;
+    let vga_buffer = phys_to_virt(VGA_TEXT_BASE) as *mut u8;
     let start_pos = (row * 80 + col) * 2;
 
     for (i, byte) in s.bytes().enumerate() {
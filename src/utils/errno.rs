@@ -1,5 +0,0 @@
-pub enum Errno {
-    ENOSYS(i8),
-    EACCES(i8),
-    EFAULT(i8),
-}
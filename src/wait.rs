@@ -0,0 +1,224 @@
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::apic::MAX_CPUS;
+use crate::percpu;
+use crate::smp;
+use crate::sync::{self, IrqSpinlock};
+use crate::task::{self, TaskId};
+
+/* Waiting without burning the CPU. A waiting task blocks and lets other tasks run. A CPU
+ * that doesn't run tasks halts until the next interrupt and then checks its condition
+ * again. The check happens with interrupts off and `sti; hlt` only lets interrupts in
+ * once the CPU is halted, so a wakeup between the check and the halt can't get lost.
+ *
+ * Interrupts on the CPU that waits wake it up by themselves. A `WaitQueue` also wakes
+ * waiters on other CPUs, which only get their own interrupts (the LAPIC timer, IPIs),
+ * with the wakeup IPI.
+ *
+ * `Mutex` is a lock whose waiters sleep on a `WaitQueue`, for data that stays locked
+ * across waits such as disk I/O. A spinning waiter of a higher priority would never let
+ * the holder run again. */
+
+/// Halts until the next interrupt. With interrupts off this would never return, so it
+/// only pauses then
+pub fn halt() {
+    if sync::interrupts_enabled() {
+        unsafe {
+            asm!("hlt", options(nomem, nostack));
+        }
+    } else {
+        core::hint::spin_loop();
+    }
+}
+
+/// Halts until `done` returns true, `done` is checked with interrupts off
+pub fn halt_until(mut done: impl FnMut() -> bool) {
+    if !sync::interrupts_enabled() {
+        /* Nothing could wake us up, called from an interrupt handler or too early */
+        while !done() {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    loop {
+        unsafe {
+            asm!("cli", options(nomem, nostack));
+        }
+        if done() {
+            break;
+        }
+        unsafe {
+            asm!("sti", "hlt", options(nomem, nostack));
+        }
+    }
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+}
+
+/// Lets tasks (or CPUs, before they run tasks) block until something, usually an
+/// interrupt handler, calls `wake_all`
+pub struct WaitQueue {
+    /* Bit n: CPU n is waiting */
+    waiters: AtomicU64,
+    tasks: IrqSpinlock<Vec<TaskId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: AtomicU64::new(0),
+            tasks: IrqSpinlock::new(Vec::new()),
+        }
+    }
+
+    /// Blocks until `done` returns true. `done` is checked again whenever the queue is
+    /// woken, a CPU that doesn't run tasks also checks it after every interrupt
+    pub fn wait_until(&self, mut done: impl FnMut() -> bool) {
+        if !task::running() || !sync::interrupts_enabled() {
+            let bit = 1 << percpu::current().index;
+            self.waiters.fetch_or(bit, Ordering::AcqRel);
+            halt_until(done);
+            self.waiters.fetch_and(!bit, Ordering::AcqRel);
+            return;
+        }
+
+        /* Interrupts stay off from the check until the task is blocked, `wake_all` from
+         * another CPU in between leaves a pending wakeup that keeps `block` from blocking */
+        let id = task::current_id();
+        loop {
+            unsafe {
+                asm!("cli", options(nomem, nostack));
+            }
+            if done() {
+                break;
+            }
+            {
+                let mut tasks = self.tasks.lock();
+                if !tasks.contains(&id) {
+                    tasks.push(id);
+                }
+            }
+            task::block();
+        }
+        unsafe {
+            asm!("sti", options(nomem, nostack));
+        }
+    }
+
+    /// Like `wait_until`, gives up after `ms` milliseconds. Returns whether `done`
+    /// returned true
+    pub fn wait_until_timeout(&self, ms: u64, mut done: impl FnMut() -> bool) -> bool {
+        let deadline = crate::time::get_millis().saturating_add(ms);
+        /* Only the boot CPU gets the PIT tick, others need a timer to wake them */
+        let timer = if task::running() {
+            let id = task::current_id();
+            crate::time::after(ms, move || task::wake(id))
+        } else {
+            let cpu = percpu::current().index;
+            crate::time::after(ms, move || smp::wake(cpu))
+        };
+
+        let mut result = false;
+        self.wait_until(|| {
+            result = done();
+            result || crate::time::get_millis() >= deadline
+        });
+        timer.cancel();
+        result
+    }
+
+    /// Wakes every task and CPU waiting on this queue
+    pub fn wake_all(&self) {
+        let tasks = core::mem::take(&mut *self.tasks.lock());
+        for id in tasks {
+            task::wake(id);
+        }
+
+        let waiters = self.waiters.load(Ordering::Acquire);
+        if waiters == 0 {
+            return;
+        }
+        for cpu in 0..MAX_CPUS {
+            /* `wake` skips the calling CPU, it is running and checks its condition once
+             * it returns from the interrupt */
+            if waiters & (1 << cpu) != 0 {
+                smp::wake(cpu);
+            }
+        }
+    }
+
+    pub fn has_waiters(&self) -> bool {
+        self.waiters.load(Ordering::Acquire) != 0 || !self.tasks.lock().is_empty()
+    }
+}
+
+/// A lock that blocks the tasks waiting for it instead of spinning. Can't be taken in
+/// interrupt handlers
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let mut guard = None;
+        self.waiters.wait_until(|| {
+            guard = self.try_lock();
+            guard.is_some()
+        });
+        guard.unwrap()
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_all();
+    }
+}